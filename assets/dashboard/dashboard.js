@@ -0,0 +1,267 @@
+// Live dashboard fed by /api/v1/stream (SSE) and the JSON REST API.
+"use strict";
+
+const TOP_TALKERS = 20;
+const TCP_ROWS = 10;
+const HISTORY_SECONDS = 60;
+const POLL_INTERVAL_MS = 3000;
+
+// ip -> array of the last HISTORY_SECONDS {tx, rx} bps samples
+const history = new Map();
+let selectedIp = null;
+
+function formatBps(bps) {
+  if (bps >= 1e9) return (bps / 1e9).toFixed(1) + "G";
+  if (bps >= 1e6) return (bps / 1e6).toFixed(1) + "M";
+  if (bps >= 1e3) return (bps / 1e3).toFixed(1) + "K";
+  return bps.toFixed(0);
+}
+
+function formatBytes(bytes) {
+  if (bytes >= 1073741824) return (bytes / 1073741824).toFixed(1) + "G";
+  if (bytes >= 1048576) return (bytes / 1048576).toFixed(1) + "M";
+  if (bytes >= 1024) return (bytes / 1024).toFixed(1) + "K";
+  return String(bytes);
+}
+
+function cell(text, className) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  if (className) td.className = className;
+  return td;
+}
+
+function setStatus(connected) {
+  const status = document.getElementById("status");
+  status.textContent = connected ? "live" : "disconnected – retrying…";
+  status.className = "status " + (connected ? "connected" : "disconnected");
+}
+
+function recordHistory(hosts) {
+  const seen = new Set();
+  for (const host of hosts) {
+    seen.add(host.ip);
+    let samples = history.get(host.ip);
+    if (!samples) {
+      samples = [];
+      history.set(host.ip, samples);
+    }
+    samples.push({ tx: host.tx.bps, rx: host.rx.bps });
+    if (samples.length > HISTORY_SECONDS) samples.shift();
+  }
+  // Forget hosts the server has evicted
+  for (const ip of history.keys()) {
+    if (!seen.has(ip)) history.delete(ip);
+  }
+}
+
+function drawSparkline(canvas, samples) {
+  const ratio = window.devicePixelRatio || 1;
+  const width = 160;
+  const height = 24;
+  canvas.width = width * ratio;
+  canvas.height = height * ratio;
+  canvas.style.width = width + "px";
+  canvas.style.height = height + "px";
+  const ctx = canvas.getContext("2d");
+  ctx.scale(ratio, ratio);
+
+  const max = Math.max(1, ...samples.map((s) => Math.max(s.tx, s.rx)));
+  const step = width / (HISTORY_SECONDS - 1);
+  const offset = HISTORY_SECONDS - samples.length;
+  const styles = getComputedStyle(document.documentElement);
+  for (const [key, color] of [["rx", "--rx"], ["tx", "--tx"]]) {
+    ctx.beginPath();
+    samples.forEach((sample, i) => {
+      const x = (offset + i) * step;
+      const y = height - 1 - (sample[key] / max) * (height - 2);
+      if (i === 0) ctx.moveTo(x, y);
+      else ctx.lineTo(x, y);
+    });
+    ctx.strokeStyle = styles.getPropertyValue(color).trim();
+    ctx.lineWidth = 1.2;
+    ctx.stroke();
+  }
+}
+
+function renderTalkers(hosts) {
+  const top = hosts
+    .filter((host) => host.lan)
+    .sort((a, b) => b.tx.bps + b.rx.bps - (a.tx.bps + a.rx.bps) || a.ip.localeCompare(b.ip))
+    .slice(0, TOP_TALKERS);
+
+  const tbody = document.querySelector("#talkers tbody");
+  tbody.replaceChildren();
+  for (const host of top) {
+    const row = document.createElement("tr");
+    if (host.ip === selectedIp) row.className = "selected";
+    row.addEventListener("click", () => selectHost(host.ip));
+
+    row.append(cell(host.ip));
+    const name = cell(host.hostname || "");
+    if (host.nat_suspected) {
+      const badge = document.createElement("span");
+      badge.className = "nat";
+      badge.textContent = "NAT?";
+      name.append(badge);
+    }
+    row.append(name);
+    row.append(cell(formatBps(host.tx.bps), "num"));
+    row.append(cell(formatBps(host.rx.bps), "num"));
+    row.append(cell(formatBytes(host.tx.bytes_per_sec), "num"));
+    row.append(cell(formatBytes(host.rx.bytes_per_sec), "num"));
+    row.append(cell(String(host.average_packet_size), "num"));
+
+    const spark = document.createElement("td");
+    const canvas = document.createElement("canvas");
+    canvas.className = "spark";
+    spark.append(canvas);
+    row.append(spark);
+    tbody.append(row);
+    drawSparkline(canvas, history.get(host.ip) || []);
+  }
+
+  if (selectedIp === null && top.length > 0) selectHost(top[0].ip);
+}
+
+function renderTcpHealth(hosts) {
+  const rows = hosts
+    .filter((host) => host.lan && (host.tcp.retransmissions > 0 || host.tcp.duplicate_acks > 0))
+    .sort(
+      (a, b) =>
+        b.tcp.retransmissions_per_sec + b.tcp.duplicate_acks_per_sec -
+          (a.tcp.retransmissions_per_sec + a.tcp.duplicate_acks_per_sec) ||
+        b.tcp.retransmissions - a.tcp.retransmissions
+    )
+    .slice(0, TCP_ROWS);
+
+  const tbody = document.querySelector("#tcp tbody");
+  tbody.replaceChildren();
+  for (const host of rows) {
+    const row = document.createElement("tr");
+    row.append(cell(host.hostname ? `${host.ip} (${host.hostname})` : host.ip));
+    row.append(cell(String(host.tcp.retransmissions_per_sec), host.tcp.retransmissions_per_sec > 0 ? "num bad" : "num"));
+    row.append(cell(String(host.tcp.duplicate_acks_per_sec), host.tcp.duplicate_acks_per_sec > 0 ? "num bad" : "num"));
+    row.append(cell(String(host.tcp.window_size_changes_per_sec), "num"));
+    row.append(cell(String(host.tcp.retransmissions), "num"));
+    tbody.append(row);
+  }
+  if (rows.length === 0) {
+    const row = document.createElement("tr");
+    const empty = cell("No retransmissions or duplicate ACKs seen.", "muted");
+    empty.colSpan = 5;
+    row.append(empty);
+    tbody.append(row);
+  }
+}
+
+function renderProtocols(detail) {
+  document.getElementById("protocol-host").textContent = detail.hostname ? `${detail.ip} (${detail.hostname})` : detail.ip;
+  const names = new Set([...Object.keys(detail.protocols.tx), ...Object.keys(detail.protocols.rx)]);
+  const rows = [...names]
+    .map((name) => {
+      const tx = (detail.protocols.tx[name] || { bytes: 0 }).bytes;
+      const rx = (detail.protocols.rx[name] || { bytes: 0 }).bytes;
+      return { name, tx, rx };
+    })
+    .sort((a, b) => b.tx + b.rx - (a.tx + a.rx));
+  const max = Math.max(1, ...rows.map((r) => r.tx + r.rx));
+
+  const container = document.getElementById("protocols");
+  container.replaceChildren();
+  for (const { name, tx, rx } of rows) {
+    const row = document.createElement("div");
+    row.className = "row";
+    const label = document.createElement("span");
+    label.textContent = name;
+    const track = document.createElement("div");
+    track.className = "track";
+    const txBar = document.createElement("div");
+    txBar.className = "tx";
+    txBar.style.width = (tx / max) * 100 + "%";
+    txBar.title = `TX ${formatBytes(tx)}`;
+    const rxBar = document.createElement("div");
+    rxBar.className = "rx";
+    rxBar.style.width = (rx / max) * 100 + "%";
+    rxBar.title = `RX ${formatBytes(rx)}`;
+    track.append(txBar, rxBar);
+    const amount = document.createElement("span");
+    amount.className = "amount";
+    amount.textContent = `${formatBytes(tx)} / ${formatBytes(rx)}`;
+    row.append(label, track, amount);
+    container.append(row);
+  }
+  if (rows.length === 0) {
+    container.innerHTML = '<p class="muted">No traffic recorded for this host yet.</p>';
+  }
+}
+
+function renderTotals(totals) {
+  const scope = totals.scope;
+  document.getElementById("wan-up").textContent = formatBps(scope.lan_wan.bps) + "bps";
+  document.getElementById("wan-down").textContent = formatBps(scope.wan_lan.bps) + "bps";
+  document.getElementById("lan-internal").textContent = formatBps(scope.lan_lan.bps) + "bps";
+  document.getElementById("active-hosts").textContent = `${totals.active_hosts} / ${totals.hosts}`;
+  document.getElementById("broadcast").textContent = totals.broadcast_packets_per_sec + " pkt/s";
+  document.getElementById("multicast").textContent = totals.multicast_packets_per_sec + " pkt/s";
+
+  const tbody = document.querySelector("#external tbody");
+  tbody.replaceChildren();
+  for (const talker of totals.external.top) {
+    const row = document.createElement("tr");
+    row.append(cell(talker.ip));
+    row.append(cell(formatBytes(talker.bytes), "num"));
+    row.append(cell("±" + formatBytes(talker.error_bytes), "num"));
+    row.append(cell(talker.hostname || ""));
+    tbody.append(row);
+  }
+}
+
+async function fetchJson(url) {
+  const response = await fetch(url);
+  if (!response.ok) throw new Error(`${url}: ${response.status}`);
+  return response.json();
+}
+
+async function refreshTotals() {
+  try {
+    renderTotals(await fetchJson("/api/v1/totals"));
+  } catch (e) {
+    console.warn(e);
+  }
+}
+
+async function refreshProtocols() {
+  if (selectedIp === null) return;
+  try {
+    renderProtocols(await fetchJson("/api/v1/hosts/" + encodeURIComponent(selectedIp)));
+  } catch (e) {
+    console.warn(e);
+  }
+}
+
+function selectHost(ip) {
+  selectedIp = ip;
+  for (const row of document.querySelectorAll("#talkers tbody tr")) {
+    row.classList.toggle("selected", row.firstChild.textContent === ip);
+  }
+  refreshProtocols();
+}
+
+function connect() {
+  const source = new EventSource("/api/v1/stream");
+  source.onopen = () => setStatus(true);
+  source.onmessage = (event) => {
+    const snapshot = JSON.parse(event.data);
+    recordHistory(snapshot.hosts);
+    renderTalkers(snapshot.hosts);
+    renderTcpHealth(snapshot.hosts);
+  };
+  // EventSource reconnects by itself; just reflect the state
+  source.onerror = () => setStatus(false);
+}
+
+connect();
+refreshTotals();
+setInterval(refreshTotals, POLL_INTERVAL_MS);
+setInterval(refreshProtocols, POLL_INTERVAL_MS);
@@ -77,7 +77,18 @@ Wants=network.target
 Type=simple
 User=root
 Group=root
-ExecStart=$BINARY_PATH eth2
+# メトリクスの待ち受け先（他のホストからスクレイプする場合は 0.0.0.0:9090,[::]:9090 など）
+Environment=LPD_LISTEN=127.0.0.1:9090
+# ログのレベル（error / warn / info / debug）と要約を記録する間隔（秒）
+Environment=LPD_LOG=info
+Environment=LPD_SUMMARY_INTERVAL_SECS=60
+# 再起動をまたいで累積値を引き継ぐ状態ファイル
+Environment=LPD_STATE_FILE=/var/lib/localpacketdump/state.json
+# 時系列の履歴（/api/v1/history）を引き継ぐファイル
+Environment=LPD_HISTORY_FILE=/var/lib/localpacketdump/history.bin
+ExecStart=$BINARY_PATH --daemon eth2
+# 設定ファイル（/etc/localpacketdump/config.toml）の再読み込み
+ExecReload=/bin/kill -HUP \$MAINPID
 WorkingDirectory=$CURRENT_DIR
 Restart=always
 RestartSec=10
@@ -90,6 +101,7 @@ NoNewPrivileges=true
 ProtectSystem=strict
 # ProtectHome=true
 ReadWritePaths=/tmp
+StateDirectory=localpacketdump
 CapabilityBoundingSet=CAP_NET_RAW CAP_NET_ADMIN
 AmbientCapabilities=CAP_NET_RAW CAP_NET_ADMIN
 
@@ -0,0 +1,577 @@
+// 現在の IP 別統計を JSON で返す REST API（/api/v1/hosts, /api/v1/hosts/{ip}, /api/v1/totals, /api/v1/top）
+//
+// /metrics と同じくリクエスト時に統計のスナップショットを取り、hosts → totals → hostnames の順にロックする。
+// 絞り込みは host（IP、カンマ区切りで複数可）、subnet（CIDR、カンマ区切りで複数可）、
+// active（直近1秒に送受信があるか）、active_within（最後の送受信からの秒数）で行う。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use hyper::{Body, Response};
+use pnet::ipnetwork::IpNetwork;
+use serde::Serialize;
+
+use crate::cast::CastType;
+use crate::scope::Scope;
+use crate::{dns, geoip, json_response, persist, quic, IpStats, SharedStats, Traffic};
+
+const DEFAULT_TOP_N: usize = 10;
+const MAX_TOP_N: usize = 1000;
+// /api/v1/totals に含める外部IPの上位件数
+const EXTERNAL_TOP_N: usize = 10;
+// /api/v1/hosts/{ip} に含めるフローの件数（1秒間の通信量の多い順）
+const DETAIL_FLOWS: usize = 20;
+
+/// クエリパラメータによるホストの絞り込み
+pub struct HostFilter {
+    hosts: Vec<IpAddr>,
+    subnets: Vec<IpNetwork>,
+    active: Option<bool>,
+    active_within: Option<u64>,
+}
+
+impl HostFilter {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let hosts = match params.get("host") {
+            Some(value) => value
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<IpAddr>().map_err(|_| format!("invalid host '{}'", s)))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        let subnets = match params.get("subnet") {
+            Some(value) => value
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<IpNetwork>().map_err(|_| format!("invalid subnet '{}'", s)))
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        let active = match params.get("active").map(String::as_str) {
+            Some("true") | Some("1") => Some(true),
+            Some("false") | Some("0") => Some(false),
+            Some(other) => return Err(format!("invalid active '{}' (expected true or false)", other)),
+            None => None,
+        };
+        let active_within = match params.get("active_within") {
+            Some(value) => Some(value.parse().map_err(|_| format!("invalid active_within '{}' (expected seconds)", value))?),
+            None => None,
+        };
+        Ok(Self { hosts, subnets, active, active_within })
+    }
+
+    pub fn matches(&self, host: &HostSummary) -> bool {
+        if !self.hosts.is_empty() && !self.hosts.contains(&host.ip) {
+            return false;
+        }
+        if !self.subnets.is_empty() && !self.subnets.iter().any(|net| net.contains(host.ip)) {
+            return false;
+        }
+        if self.active.is_some_and(|active| host.active != active) {
+            return false;
+        }
+        if self.active_within.is_some_and(|secs| host.idle_secs > secs) {
+            return false;
+        }
+        true
+    }
+}
+
+fn is_active(stat: &IpStats) -> bool {
+    stat.tx_packets_per_sec > 0 || stat.rx_packets_per_sec > 0
+}
+
+fn idle_secs(stat: &IpStats, now: Instant) -> u64 {
+    now.duration_since(stat.last_active).as_secs()
+}
+
+#[derive(Serialize, Default)]
+struct DirectionStats {
+    bytes: u64,
+    packets: u64,
+    bytes_per_sec: u64,
+    packets_per_sec: u64,
+    bps: f64,
+}
+
+impl DirectionStats {
+    fn add(&mut self, other: &DirectionStats) {
+        self.bytes += other.bytes;
+        self.packets += other.packets;
+        self.bytes_per_sec += other.bytes_per_sec;
+        self.packets_per_sec += other.packets_per_sec;
+        self.bps += other.bps;
+    }
+}
+
+#[derive(Serialize, Default)]
+struct TcpHealth {
+    retransmissions: u64,
+    duplicate_acks: u64,
+    window_size_changes: u64,
+    retransmissions_per_sec: u64,
+    duplicate_acks_per_sec: u64,
+    window_size_changes_per_sec: u64,
+}
+
+impl TcpHealth {
+    fn add(&mut self, other: &TcpHealth) {
+        self.retransmissions += other.retransmissions;
+        self.duplicate_acks += other.duplicate_acks;
+        self.window_size_changes += other.window_size_changes;
+        self.retransmissions_per_sec += other.retransmissions_per_sec;
+        self.duplicate_acks_per_sec += other.duplicate_acks_per_sec;
+        self.window_size_changes_per_sec += other.window_size_changes_per_sec;
+    }
+}
+
+#[derive(Serialize)]
+pub struct HostSummary {
+    pub ip: IpAddr,
+    hostname: Option<String>,
+    lan: bool,
+    active: bool,
+    idle_secs: u64,
+    first_seen: u64, // UNIX時刻（秒）
+    last_seen: u64,  // UNIX時刻（秒）
+    tx: DirectionStats,
+    rx: DirectionStats,
+    tcp: TcpHealth,
+    average_packet_size: u64,
+    nat_suspected: bool,
+}
+
+pub fn summarize(ip: IpAddr, stat: &IpStats, lan: bool, hostnames: &dns::HostnameCache, now: Instant) -> HostSummary {
+    HostSummary {
+        ip,
+        hostname: hostnames.lookup(&ip).map(str::to_string),
+        lan,
+        active: is_active(stat),
+        idle_secs: idle_secs(stat, now),
+        first_seen: persist::unix_secs(stat.first_seen),
+        last_seen: persist::unix_secs(stat.last_seen),
+        tx: DirectionStats {
+            bytes: stat.tx_byte_count,
+            packets: stat.tx_packet_count,
+            bytes_per_sec: stat.tx_bytes_per_sec,
+            packets_per_sec: stat.tx_packets_per_sec,
+            bps: stat.tx_current_bps,
+        },
+        rx: DirectionStats {
+            bytes: stat.rx_byte_count,
+            packets: stat.rx_packet_count,
+            bytes_per_sec: stat.rx_bytes_per_sec,
+            packets_per_sec: stat.rx_packets_per_sec,
+            bps: stat.rx_current_bps,
+        },
+        tcp: TcpHealth {
+            retransmissions: stat.retransmissions,
+            duplicate_acks: stat.duplicate_acks,
+            window_size_changes: stat.window_size_changes,
+            retransmissions_per_sec: stat.retransmissions_per_sec,
+            duplicate_acks_per_sec: stat.duplicate_acks_per_sec,
+            window_size_changes_per_sec: stat.window_size_changes_per_sec,
+        },
+        average_packet_size: stat.average_packet_size(),
+        nat_suspected: stat.nat_signals.nat_suspected(),
+    }
+}
+
+#[derive(Serialize)]
+struct PerDirection<T> {
+    tx: T,
+    rx: T,
+}
+
+#[derive(Serialize)]
+struct AsnTraffic {
+    asn: String,
+    as_org: String,
+    packets: u64,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct IcmpMessages {
+    r#type: &'static str,
+    code: u8,
+    messages: u64,
+}
+
+#[derive(Serialize)]
+struct CastTraffic {
+    layer: &'static str,
+    class: &'static str,
+    packets: u64,
+    bytes: u64,
+}
+
+#[derive(Serialize)]
+struct DnsClientStats {
+    queries: u64,
+    timeouts: u64,
+    responses: HashMap<&'static str, u64>,
+    resolvers: HashMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct NatStats {
+    suspected: bool,
+    ttl_baselines: usize,
+    tcp_clock_sources: usize,
+}
+
+/// フローの相手側（ホスト名はDNS応答から学習したもの）
+#[derive(Serialize)]
+struct Endpoint {
+    ip: IpAddr,
+    port: u16,
+    hostname: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FlowEntry {
+    protocol: &'static str,
+    local_port: u16,
+    remote: Endpoint,
+    tx: Traffic,
+    rx: Traffic,
+    tx_bytes_per_sec: u64,
+    rx_bytes_per_sec: u64,
+    rtt_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct HostDetail {
+    #[serde(flatten)]
+    summary: HostSummary,
+    flows: Vec<FlowEntry>,
+    protocols: PerDirection<HashMap<&'static str, Traffic>>,
+    domains: HashMap<String, Traffic>,
+    quic_versions: HashMap<String, u64>,
+    dscp: PerDirection<HashMap<&'static str, Traffic>>,
+    scope: PerDirection<HashMap<&'static str, Traffic>>,
+    asns: PerDirection<Vec<AsnTraffic>>,
+    countries: PerDirection<HashMap<String, Traffic>>,
+    icmp: PerDirection<Vec<IcmpMessages>>,
+    cast: Vec<CastTraffic>,
+    broadcast_packets_per_sec: u64,
+    multicast_packets_per_sec: u64,
+    dns: DnsClientStats,
+    nat: NatStats,
+}
+
+fn detail(summary: HostSummary, stat: &IpStats, hostnames: &dns::HostnameCache) -> HostDetail {
+    let scope = |counters: &crate::scope::ScopeCounters| {
+        Scope::ALL
+            .into_iter()
+            .filter(|&scope| counters.packets(scope) > 0)
+            .map(|scope| (scope.label(), Traffic { packets: counters.packets(scope), bytes: counters.bytes(scope) }))
+            .collect()
+    };
+    let asns = |asns: &HashMap<(u32, std::sync::Arc<str>), Traffic>| {
+        let mut entries: Vec<AsnTraffic> = asns
+            .iter()
+            .map(|((asn, as_org), traffic)| AsnTraffic {
+                asn: geoip::asn_label(*asn),
+                as_org: as_org.to_string(),
+                packets: traffic.packets,
+                bytes: traffic.bytes,
+            })
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.bytes));
+        entries
+    };
+    let countries = |countries: &HashMap<std::sync::Arc<str>, Traffic>| {
+        countries.iter().map(|(country, traffic)| (country.to_string(), *traffic)).collect()
+    };
+    let icmp = |messages: &HashMap<(&'static str, u8), u64>| {
+        messages
+            .iter()
+            .map(|(&(kind, code), &count)| IcmpMessages { r#type: kind, code, messages: count })
+            .collect()
+    };
+
+    HostDetail {
+        summary,
+        flows: stat
+            .flows
+            .top(DETAIL_FLOWS)
+            .into_iter()
+            .map(|(key, flow)| FlowEntry {
+                protocol: key.protocol,
+                local_port: key.local_port,
+                remote: Endpoint {
+                    ip: key.remote,
+                    port: key.remote_port,
+                    hostname: hostnames.lookup(&key.remote).map(str::to_string),
+                },
+                tx: flow.tx,
+                rx: flow.rx,
+                tx_bytes_per_sec: flow.tx_bytes_per_sec,
+                rx_bytes_per_sec: flow.rx_bytes_per_sec,
+                rtt_ms: flow.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            })
+            .collect(),
+        protocols: PerDirection { tx: stat.tx_protocols.clone(), rx: stat.rx_protocols.clone() },
+        domains: stat.domains.clone(),
+        quic_versions: stat
+            .quic_versions
+            .iter()
+            .map(|(version, packets)| (quic::version_name(*version), *packets))
+            .collect(),
+        dscp: PerDirection { tx: stat.tx_dscp.clone(), rx: stat.rx_dscp.clone() },
+        scope: PerDirection { tx: scope(&stat.tx_scope), rx: scope(&stat.rx_scope) },
+        asns: PerDirection { tx: asns(&stat.tx_asns), rx: asns(&stat.rx_asns) },
+        countries: PerDirection { tx: countries(&stat.tx_countries), rx: countries(&stat.rx_countries) },
+        icmp: PerDirection { tx: icmp(&stat.icmp_tx_messages), rx: icmp(&stat.icmp_rx_messages) },
+        cast: stat
+            .tx_cast
+            .iter()
+            .map(|(&(layer, class), traffic)| CastTraffic { layer, class, packets: traffic.packets, bytes: traffic.bytes })
+            .collect(),
+        broadcast_packets_per_sec: stat.broadcast_packets_per_sec,
+        multicast_packets_per_sec: stat.multicast_packets_per_sec,
+        dns: DnsClientStats {
+            queries: stat.dns_queries,
+            timeouts: stat.dns_timeouts,
+            responses: stat.dns_responses.clone(),
+            resolvers: stat
+                .dns_resolver_queries
+                .iter()
+                .map(|(resolver, queries)| (resolver.to_string(), *queries))
+                .collect(),
+        },
+        nat: NatStats {
+            suspected: stat.nat_signals.nat_suspected(),
+            ttl_baselines: stat.nat_signals.ttl_baselines(),
+            tcp_clock_sources: stat.nat_signals.clock_sources(),
+        },
+    }
+}
+
+pub fn error_response(status: u16, message: &str) -> Response<Body> {
+    let mut response = json_response(&serde_json::json!({ "error": message }));
+    *response.status_mut() = hyper::StatusCode::from_u16(status).unwrap();
+    response
+}
+
+/// 絞り込んだホストの一覧（IP順）
+pub fn hosts_response(stats: &SharedStats, dns_state: &dns::DnsState, params: &HashMap<String, String>) -> Response<Body> {
+    let filter = match HostFilter::from_params(params) {
+        Ok(filter) => filter,
+        Err(e) => return error_response(400, &e),
+    };
+    let now = Instant::now();
+    let hosts = stats.hosts.lock().unwrap();
+    let hostnames = dns_state.hostnames.lock().unwrap();
+    let mut entries: Vec<HostSummary> = hosts
+        .iter()
+        .map(|(ip, stat)| summarize(*ip, stat, stats.target_ips.contains(ip), &hostnames, now))
+        .filter(|host| filter.matches(host))
+        .collect();
+    entries.sort_by_key(|e| e.ip);
+    json_response(&entries)
+}
+
+/// 1ホストの詳細（プロトコル・ドメイン・スコープ・ASN・DNSなどの内訳を含む）
+pub fn host_response(stats: &SharedStats, dns_state: &dns::DnsState, ip: &str) -> Response<Body> {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return error_response(400, &format!("invalid IP address '{}'", ip));
+    };
+    let now = Instant::now();
+    let hosts = stats.hosts.lock().unwrap();
+    let Some(stat) = hosts.get(&ip) else {
+        return error_response(404, &format!("no statistics for {}", ip));
+    };
+    let hostnames = dns_state.hostnames.lock().unwrap();
+    let summary = summarize(ip, stat, stats.target_ips.contains(&ip), &hostnames, now);
+    json_response(&detail(summary, stat, &hostnames))
+}
+
+#[derive(Serialize)]
+struct ScopeTotals {
+    packets: u64,
+    bytes: u64,
+    bytes_per_sec: u64,
+    bps: f64,
+}
+
+#[derive(Serialize)]
+struct ExternalTalker {
+    ip: IpAddr,
+    hostname: Option<String>,
+    bytes: u64,
+    error_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct ExternalTotals {
+    packets: u64,
+    bytes: u64,
+    tracked_ips: usize,
+    error_bound_bytes: u64,
+    top: Vec<ExternalTalker>,
+}
+
+#[derive(Serialize)]
+struct Totals {
+    hosts: usize,
+    active_hosts: usize,
+    tx: DirectionStats,
+    rx: DirectionStats,
+    tcp: TcpHealth,
+    // 以下はネットワーク全体の値（絞り込みの対象外）
+    scope: HashMap<&'static str, ScopeTotals>,
+    broadcast_packets_per_sec: u64,
+    multicast_packets_per_sec: u64,
+    external: ExternalTotals,
+}
+
+/// 監視対象のホストの合計（絞り込み可）とネットワーク全体の集計
+pub fn totals_response(stats: &SharedStats, dns_state: &dns::DnsState, params: &HashMap<String, String>) -> Response<Body> {
+    let filter = match HostFilter::from_params(params) {
+        Ok(filter) => filter,
+        Err(e) => return error_response(400, &e),
+    };
+    let now = Instant::now();
+    let hosts = stats.hosts.lock().unwrap();
+    let totals = stats.totals.lock().unwrap();
+    let hostnames = dns_state.hostnames.lock().unwrap();
+
+    let mut result = Totals {
+        hosts: 0,
+        active_hosts: 0,
+        tx: DirectionStats::default(),
+        rx: DirectionStats::default(),
+        tcp: TcpHealth::default(),
+        scope: Scope::ALL
+            .into_iter()
+            .map(|scope| {
+                (
+                    scope.label(),
+                    ScopeTotals {
+                        packets: totals.scope.packets(scope),
+                        bytes: totals.scope.bytes(scope),
+                        bytes_per_sec: totals.scope.bytes_per_sec(scope),
+                        bps: totals.scope.bps(scope),
+                    },
+                )
+            })
+            .collect(),
+        broadcast_packets_per_sec: totals.storm.packets_per_sec(CastType::Broadcast),
+        multicast_packets_per_sec: totals.storm.packets_per_sec(CastType::Multicast),
+        external: ExternalTotals {
+            packets: totals.external_packets,
+            bytes: totals.external_talkers.total(),
+            tracked_ips: totals.external_talkers.tracked(),
+            error_bound_bytes: totals.external_talkers.error_bound(),
+            top: totals
+                .external_talkers
+                .top(EXTERNAL_TOP_N)
+                .into_iter()
+                .map(|talker| ExternalTalker {
+                    ip: talker.key,
+                    hostname: hostnames.lookup(&talker.key).map(str::to_string),
+                    bytes: talker.count,
+                    error_bytes: talker.error,
+                })
+                .collect(),
+        },
+    };
+    for (ip, stat) in hosts.iter() {
+        // メトリクスの合計と同じく監視対象のIPだけを足す
+        if !stats.target_ips.contains(ip) {
+            continue;
+        }
+        let summary = summarize(*ip, stat, true, &hostnames, now);
+        if !filter.matches(&summary) {
+            continue;
+        }
+        result.hosts += 1;
+        if summary.active {
+            result.active_hosts += 1;
+        }
+        result.tx.add(&summary.tx);
+        result.rx.add(&summary.rx);
+        result.tcp.add(&summary.tcp);
+    }
+    json_response(&result)
+}
+
+#[derive(Serialize)]
+struct TopEntry {
+    value: f64,
+    #[serde(flatten)]
+    host: HostSummary,
+}
+
+// /api/v1/top の by に指定できる項目
+const TOP_KEYS: [&str; 13] = [
+    "bytes",
+    "tx_bytes",
+    "rx_bytes",
+    "packets",
+    "tx_packets",
+    "rx_packets",
+    "bps",
+    "tx_bps",
+    "rx_bps",
+    "packets_per_sec",
+    "retransmissions",
+    "retransmissions_per_sec",
+    "duplicate_acks",
+];
+
+fn top_value(host: &HostSummary, by: &str) -> f64 {
+    match by {
+        "bytes" => (host.tx.bytes + host.rx.bytes) as f64,
+        "tx_bytes" => host.tx.bytes as f64,
+        "rx_bytes" => host.rx.bytes as f64,
+        "packets" => (host.tx.packets + host.rx.packets) as f64,
+        "tx_packets" => host.tx.packets as f64,
+        "rx_packets" => host.rx.packets as f64,
+        "bps" => host.tx.bps + host.rx.bps,
+        "tx_bps" => host.tx.bps,
+        "rx_bps" => host.rx.bps,
+        "packets_per_sec" => (host.tx.packets_per_sec + host.rx.packets_per_sec) as f64,
+        "retransmissions" => host.tcp.retransmissions as f64,
+        "retransmissions_per_sec" => host.tcp.retransmissions_per_sec as f64,
+        "duplicate_acks" => host.tcp.duplicate_acks as f64,
+        _ => 0.0,
+    }
+}
+
+/// by で指定した項目の上位 n ホスト（既定は bps の上位10件）
+pub fn top_response(stats: &SharedStats, dns_state: &dns::DnsState, params: &HashMap<String, String>) -> Response<Body> {
+    let filter = match HostFilter::from_params(params) {
+        Ok(filter) => filter,
+        Err(e) => return error_response(400, &e),
+    };
+    let by = params.get("by").map(String::as_str).unwrap_or("bps");
+    if !TOP_KEYS.contains(&by) {
+        return error_response(400, &format!("invalid by '{}' (expected one of {})", by, TOP_KEYS.join(", ")));
+    }
+    let n = match params.get("n") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(n) => n.min(MAX_TOP_N),
+            Err(_) => return error_response(400, &format!("invalid n '{}'", value)),
+        },
+        None => DEFAULT_TOP_N,
+    };
+
+    let now = Instant::now();
+    let hosts = stats.hosts.lock().unwrap();
+    let hostnames = dns_state.hostnames.lock().unwrap();
+    let mut entries: Vec<TopEntry> = hosts
+        .iter()
+        .map(|(ip, stat)| summarize(*ip, stat, stats.target_ips.contains(ip), &hostnames, now))
+        .filter(|host| filter.matches(host))
+        .map(|host| TopEntry { value: top_value(&host, by), host })
+        .collect();
+    entries.sort_by(|a, b| b.value.total_cmp(&a.value).then(a.host.ip.cmp(&b.host.ip)));
+    entries.truncate(n);
+    json_response(&entries)
+}
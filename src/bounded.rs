@@ -0,0 +1,21 @@
+// 種類数に上限のある集計（ASN・国・TLS のドメインなど、値を外部から決められるキー）
+//
+// 上限に達した後に現れた新しいキーは、呼び出し元が決めた "other" のようなキーにまとめる。
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// 種類数の上限付きでエントリを取り出す。上限に達した後の新しいキーは overflow() にまとめる
+pub fn entry<K: Eq + Hash, V: Default>(
+    map: &mut HashMap<K, V>,
+    key: K,
+    overflow: impl FnOnce() -> K,
+    limit: usize,
+) -> &mut V {
+    let key = if map.contains_key(&key) || map.len() < limit {
+        key
+    } else {
+        overflow()
+    };
+    map.entry(key).or_default()
+}
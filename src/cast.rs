@@ -0,0 +1,174 @@
+// 宛先の種別（ユニキャスト / ブロードキャスト / マルチキャスト）の判定
+//
+// L2 は宛先 MAC、L3 は宛先 IP で判定する。サブネットのブロードキャストアドレス
+// （例: 192.168.1.255）は監視対象の IP 一覧に含まれないので別に渡す。
+// L2 の判定は EtherType によらずすべてのフレームで行い（ARP のブロードキャストストームなど）、
+// 監視対象外や外部の送信元も送信元 MAC 別の上位として数える。
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+
+use crate::heavy_hitters::{HeavyHitter, SpaceSaving};
+
+// マルチキャストグループ別の集計数の上限
+const MAX_GROUPS: usize = 256;
+// ブロードキャスト・マルチキャストの送信元 MAC を数えるカウンタ数（種別ごと）
+const SOURCE_CAPACITY: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CastType {
+    Unicast,
+    Broadcast,
+    Multicast,
+}
+
+impl CastType {
+    pub fn label(self) -> &'static str {
+        match self {
+            CastType::Unicast => "unicast",
+            CastType::Broadcast => "broadcast",
+            CastType::Multicast => "multicast",
+        }
+    }
+}
+
+pub fn l2_cast(dst: MacAddr) -> CastType {
+    if dst.is_broadcast() {
+        CastType::Broadcast
+    } else if dst.is_multicast() {
+        CastType::Multicast
+    } else {
+        CastType::Unicast
+    }
+}
+
+pub fn l3_cast(dst: IpAddr, subnet_broadcast: Ipv4Addr) -> CastType {
+    match dst {
+        IpAddr::V4(v4) if v4.is_broadcast() || v4 == subnet_broadcast => CastType::Broadcast,
+        IpAddr::V4(v4) if v4.is_multicast() => CastType::Multicast,
+        IpAddr::V6(v6) if v6.is_multicast() => CastType::Multicast,
+        _ => CastType::Unicast,
+    }
+}
+
+#[derive(Default)]
+pub struct GroupStats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// マルチキャストグループ別の合計（上限を超えたグループは "other" にまとめる）
+pub struct GroupCounters {
+    groups: HashMap<IpAddr, GroupStats>,
+    overflow: GroupStats,
+}
+
+impl GroupCounters {
+    pub fn new() -> Self {
+        Self {
+            groups: HashMap::new(),
+            overflow: GroupStats::default(),
+        }
+    }
+
+    pub fn add(&mut self, group: IpAddr, bytes: u64) {
+        let stats = if self.groups.contains_key(&group) || self.groups.len() < MAX_GROUPS {
+            self.groups.entry(group).or_default()
+        } else {
+            &mut self.overflow
+        };
+        stats.packets += 1;
+        stats.bytes += bytes;
+    }
+
+    /// (グループのラベル, 集計) の一覧
+    pub fn entries(&self) -> impl Iterator<Item = (String, &GroupStats)> {
+        self.groups
+            .iter()
+            .map(|(group, stats)| (group.to_string(), stats))
+            .chain((self.overflow.packets > 0).then(|| ("other".to_string(), &self.overflow)))
+    }
+}
+
+/// フレームの種類（ethertype ラベルの値）
+pub fn ethertype_label(ethertype: EtherType) -> &'static str {
+    match ethertype {
+        EtherTypes::Arp => "arp",
+        EtherTypes::Ipv4 => "ipv4",
+        EtherTypes::Ipv6 => "ipv6",
+        _ => "other",
+    }
+}
+
+/// 宛先 MAC がブロードキャスト・マルチキャストのフレームの LAN 全体の集計
+pub struct StormCounters {
+    frames: HashMap<(CastType, &'static str), GroupStats>, // (種別, ethertype) 別
+    packets: [u64; 2],                                     // [broadcast, multicast]
+    last_packets: [u64; 2],
+    packets_per_sec: [u64; 2],
+    last_time: Instant,
+    sources: [SpaceSaving<MacAddr>; 2],
+}
+
+impl StormCounters {
+    pub fn new() -> Self {
+        Self {
+            frames: HashMap::new(),
+            packets: [0; 2],
+            last_packets: [0; 2],
+            packets_per_sec: [0; 2],
+            last_time: Instant::now(),
+            sources: [SpaceSaving::new(SOURCE_CAPACITY), SpaceSaving::new(SOURCE_CAPACITY)],
+        }
+    }
+
+    /// ユニキャストのフレームは数えない
+    pub fn add(&mut self, class: CastType, source: MacAddr, ethertype: EtherType, bytes: u64) {
+        let Some(index) = Self::index(class) else {
+            return;
+        };
+        let stats = self.frames.entry((class, ethertype_label(ethertype))).or_default();
+        stats.packets += 1;
+        stats.bytes += bytes;
+        self.packets[index] += 1;
+        self.sources[index].add(source, 1);
+    }
+
+    /// 1秒以上経過していれば直近1秒のフレーム数を更新する
+    pub fn roll(&mut self, now: Instant) {
+        if now.duration_since(self.last_time).as_secs_f64() < 1.0 {
+            return;
+        }
+        for i in 0..2 {
+            self.packets_per_sec[i] = self.packets[i] - self.last_packets[i];
+            self.last_packets[i] = self.packets[i];
+        }
+        self.last_time = now;
+    }
+
+    /// (種別, ethertype, 集計) の一覧
+    pub fn entries(&self) -> impl Iterator<Item = (CastType, &'static str, &GroupStats)> {
+        self.frames.iter().map(|(&(class, ethertype), stats)| (class, ethertype, stats))
+    }
+
+    pub fn packets_per_sec(&self, class: CastType) -> u64 {
+        Self::index(class).map_or(0, |i| self.packets_per_sec[i])
+    }
+
+    /// 送信元 MAC 別のフレーム数の上位 k 件
+    pub fn top_sources(&self, class: CastType, k: usize) -> Vec<HeavyHitter<MacAddr>> {
+        Self::index(class).map_or_else(Vec::new, |i| self.sources[i].top(k))
+    }
+
+    fn index(class: CastType) -> Option<usize> {
+        match class {
+            CastType::Unicast => None,
+            CastType::Broadcast => Some(0),
+            CastType::Multicast => Some(1),
+        }
+    }
+}
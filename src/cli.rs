@@ -0,0 +1,134 @@
+// コマンドライン引数
+//
+// サブコマンドを省略した場合は run として扱う（`localpacketDump eth0` は `localpacketDump run eth0` と同じ）。
+// 引数で指定した値は設定ファイル・環境変数より優先し、SIGHUP での再読み込み後も同じ値を適用する。
+
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::{Config, CONFIG_ENV};
+
+#[derive(Parser)]
+#[command(
+    name = "localpacketDump",
+    version,
+    about = "Per-host LAN traffic monitor with a Prometheus exporter, REST API and live dashboard",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Capture traffic and export statistics (the default)
+    Run(RunArgs),
+    /// Validate the configuration and print the effective settings as TOML
+    CheckConfig(RunArgs),
+    /// List capture devices and their addresses
+    Interfaces,
+}
+
+#[derive(Args, Clone)]
+pub struct RunArgs {
+    /// Interfaces to capture on (default: the interfaces in the config file, or the system default)
+    #[arg(value_name = "INTERFACE")]
+    pub interfaces: Vec<String>,
+    /// Configuration file (default: /etc/localpacketdump/config.toml if it exists)
+    #[arg(short, long, value_name = "FILE", env = CONFIG_ENV)]
+    pub config: Option<PathBuf>,
+    /// Networks to monitor, comma-separated CIDRs (default: the interface subnets)
+    #[arg(short, long = "target", value_name = "CIDR", value_delimiter = ',')]
+    pub targets: Vec<String>,
+    /// Log periodic summaries instead of drawing statistics on the terminal
+    #[arg(long)]
+    pub daemon: bool,
+    /// Addresses to serve metrics, the API and the dashboard on (ip:port, [ipv6]:port or unix:/path)
+    #[arg(short, long, value_name = "ADDR", value_delimiter = ',')]
+    pub listen: Vec<String>,
+    /// Print the plain table even on a terminal
+    #[arg(long)]
+    pub no_tui: bool,
+    /// Number of hosts in the printed table
+    #[arg(long, value_name = "N")]
+    pub top: Option<usize>,
+    /// Display refresh interval in milliseconds
+    #[arg(long, value_name = "MS")]
+    pub refresh_ms: Option<u64>,
+    /// Capture snapshot length in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub snaplen: Option<i32>,
+    /// Log level or filter (e.g. debug, info,hyper=warn)
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Log format: text, json or journald
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<String>,
+    /// File to keep cumulative counters in across restarts
+    #[arg(long, value_name = "FILE")]
+    pub state_file: Option<PathBuf>,
+    /// File to keep the traffic history in across restarts
+    #[arg(long, value_name = "FILE")]
+    pub history_file: Option<PathBuf>,
+}
+
+impl Cli {
+    /// サブコマンドを省略した場合は run
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Run(self.run))
+    }
+}
+
+impl RunArgs {
+    /// 引数で指定した値を設定に上書きする
+    pub fn apply(&self, config: &mut Config) {
+        if !self.interfaces.is_empty() {
+            config.interfaces = self.interfaces.clone();
+        }
+        if !self.targets.is_empty() {
+            config.target_networks = self.targets.clone();
+        }
+        if self.daemon {
+            config.daemon = true;
+        }
+        if !self.listen.is_empty() {
+            config.exporters.listen = self.listen.clone();
+        }
+        if self.no_tui {
+            config.display.tui = false;
+        }
+        if let Some(top) = self.top {
+            config.display.top_hosts = top;
+        }
+        if let Some(refresh_ms) = self.refresh_ms {
+            config.display.refresh_interval_ms = refresh_ms;
+        }
+        if let Some(snaplen) = self.snaplen {
+            config.capture.snaplen = snaplen;
+        }
+        if let Some(level) = &self.log_level {
+            config.log.level = level.clone();
+        }
+        if let Some(format) = &self.log_format {
+            config.log.format = Some(format.clone());
+        }
+        if let Some(path) = &self.state_file {
+            config.state.path = Some(path.clone());
+        }
+        if let Some(path) = &self.history_file {
+            config.history.path = Some(path.clone());
+        }
+    }
+
+    /// 設定ファイル・環境変数・引数から設定を作る（起動時と SIGHUP での再読み込みで使う）
+    pub fn load_config(&self) -> Result<Config, String> {
+        let mut config = crate::config::load(self.config.as_deref())?;
+        self.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,433 @@
+// スクレイプ時に IpStats のスナップショットからメトリクスを組み立てる Collector
+//
+// 累積値を Counter に差分で足し込む方式では f64 の丸め誤差が積み重なり、状態も二重に持つことになる。
+// ここではロックを取った時点の u64 の値をそのまま1回だけ変換して出力するため、
+// 1回のスクレイプ内の値（ホスト別と合計など）は互いに矛盾しない。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{self, MetricFamily, MetricType};
+
+use crate::cast::CastType;
+use crate::scope::{Scope, TargetSet};
+use crate::{geoip, quic, IpStats, SharedStats, TrafficTotals};
+
+// メトリクスとして出力する外部通信相手の件数
+const EXTERNAL_TOP_K: usize = 20;
+// メトリクスとして出力するブロードキャスト・マルチキャストの送信元MACの件数（種別ごと）
+const STORM_TOP_K: usize = 10;
+
+struct Spec {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricType,
+    labels: &'static [&'static str],
+}
+
+const fn counter(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Spec {
+    Spec { name, help, kind: MetricType::COUNTER, labels }
+}
+
+const fn gauge(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Spec {
+    Spec { name, help, kind: MetricType::GAUGE, labels }
+}
+
+const IP: &[&str] = &["ip_address"];
+const DIRECTION: &[&str] = &["direction"];
+const IP_DIRECTION: &[&str] = &["ip_address", "direction"];
+const DIRECTION_SCOPE: &[&str] = &["direction", "scope"];
+const IP_DIRECTION_SCOPE: &[&str] = &["ip_address", "direction", "scope"];
+
+const SPECS: &[Spec] = &[
+    // 全体のメトリクス（サブネット内のIPの合計）
+    counter("network_bytes_total", "Total bytes per direction (tx, rx) and scope (lan_lan, lan_wan, wan_lan)", DIRECTION_SCOPE),
+    counter("network_packets_total", "Total packets per direction (tx, rx) and scope (lan_lan, lan_wan, wan_lan)", DIRECTION_SCOPE),
+    gauge("network_bytes_per_sec", "Bytes per second per direction (tx, rx)", DIRECTION),
+    gauge("network_packets_per_sec", "Packets per second per direction (tx, rx)", DIRECTION),
+    gauge("network_bps", "Bits per second per direction (tx, rx)", DIRECTION),
+    counter("network_retransmissions_total", "Total retransmissions", &[]),
+    counter("network_duplicate_acks_total", "Total duplicate ACKs", &[]),
+    counter("network_window_size_changes_total", "Total window size changes", &[]),
+    gauge("network_retransmissions_per_sec", "Retransmissions per second", &[]),
+    gauge("network_duplicate_acks_per_sec", "Duplicate ACKs per second", &[]),
+    gauge("network_window_size_changes_per_sec", "Window size changes per second", &[]),
+    // IPごとのメトリクス
+    counter("network_ip_bytes_total", "Total bytes per IP, direction (tx, rx) and scope (lan_lan, lan_wan, wan_lan)", IP_DIRECTION_SCOPE),
+    counter("network_ip_packets_total", "Total packets per IP, direction (tx, rx) and scope (lan_lan, lan_wan, wan_lan)", IP_DIRECTION_SCOPE),
+    gauge("network_ip_bytes_per_sec", "Bytes per second per IP and direction (tx, rx)", IP_DIRECTION),
+    gauge("network_ip_packets_per_sec", "Packets per second per IP and direction (tx, rx)", IP_DIRECTION),
+    gauge("network_ip_bps", "Bits per second per IP and direction (tx, rx)", IP_DIRECTION),
+    counter("network_ip_retransmissions_total", "Total retransmissions per IP", IP),
+    counter("network_ip_duplicate_acks_total", "Total duplicate ACKs per IP", IP),
+    counter("network_ip_window_size_changes_total", "Total window size changes per IP", IP),
+    gauge("network_ip_retransmissions_per_sec", "Retransmissions per second per IP", IP),
+    gauge("network_ip_duplicate_acks_per_sec", "Duplicate ACKs per second per IP", IP),
+    gauge("network_ip_window_size_changes_per_sec", "Window size changes per second per IP", IP),
+    // プロトコル別・ドメイン別
+    counter("network_ip_protocol_bytes_total", "Total bytes per IP, direction and protocol", &["ip_address", "direction", "protocol"]),
+    counter("network_ip_protocol_packets_total", "Total packets per IP, direction and protocol", &["ip_address", "direction", "protocol"]),
+    counter("network_ip_quic_packets_total", "Total QUIC packets per IP and QUIC version", &["ip_address", "version"]),
+    counter("network_ip_domain_bytes_total", "Total bytes per IP and server name (QUIC / TLS SNI, at most 64 per IP, the rest as other)", &["ip_address", "domain"]),
+    counter("network_ip_domain_packets_total", "Total packets per IP and server name (QUIC / TLS SNI, at most 64 per IP, the rest as other)", &["ip_address", "domain"]),
+    // DNS
+    counter("network_ip_dns_queries_total", "Total DNS queries sent per client IP", IP),
+    counter("network_ip_dns_resolver_queries_total", "Total DNS queries per client IP and resolver", &["ip_address", "resolver"]),
+    counter("network_ip_dns_responses_total", "Total DNS responses per client IP and rcode", &["ip_address", "rcode"]),
+    counter("network_ip_dns_timeouts_total", "Total DNS queries without a response per client IP", IP),
+    // ICMP
+    counter("network_ip_icmp_messages_total", "Total ICMP/ICMPv6 messages per IP, direction, type and code", &["ip_address", "direction", "type", "code"]),
+    gauge("network_ip_icmp_reported_mtu", "Last MTU reported by fragmentation-needed / packet-too-big per IP", IP),
+    // DSCP / ECN
+    counter("network_ip_dscp_bytes_total", "Total bytes per IP, direction and DSCP class", &["ip_address", "direction", "dscp"]),
+    counter("network_ip_dscp_packets_total", "Total packets per IP, direction and DSCP class", &["ip_address", "direction", "dscp"]),
+    counter("network_ip_ecn_packets_total", "Total ECN-capable (ect0/ect1) and CE-marked packets per IP and direction", &["ip_address", "direction", "codepoint"]),
+    counter("network_ip_tcp_ecn_flags_total", "Total TCP segments with ECE/CWR set per IP and direction", &["ip_address", "direction", "flag"]),
+    // TTL・IP ID・TCPタイムスタンプと隠れNATの判定
+    counter("network_ip_ttl_packets_total", "Total outgoing unicast packets per source IP and TTL / hop limit", &["ip_address", "ttl"]),
+    counter("network_ip_ipid_packets_total", "Total outgoing IPv4 packets per source IP and IP ID behaviour", &["ip_address", "behavior"]),
+    gauge("network_ip_ttl_baselines", "Number of distinct initial TTL baselines (OS families) seen per source IP", IP),
+    gauge("network_ip_tcp_clock_sources", "Number of distinct TCP timestamp clocks shared by several connections per source IP", IP),
+    gauge("network_ip_nat_suspected", "1 if the IP likely NATs several devices (multiple TTLs or TCP clocks)", IP),
+    // LAN内・インターネット向けの区別
+    gauge("network_scope_bytes_per_sec", "Bytes per second per scope, each packet counted once", &["scope"]),
+    gauge("network_scope_bps", "Bits per second per scope, each packet counted once", &["scope"]),
+    // 外部IPのASN・国別の集約
+    counter("network_ip_asn_bytes_total", "Total bytes exchanged with remote peers per internal IP, direction and remote ASN", &["ip_address", "direction", "asn", "as_org"]),
+    counter("network_ip_asn_packets_total", "Total packets exchanged with remote peers per internal IP, direction and remote ASN", &["ip_address", "direction", "asn", "as_org"]),
+    counter("network_ip_country_bytes_total", "Total bytes exchanged with remote peers per internal IP, direction and remote country", &["ip_address", "direction", "country"]),
+    counter("network_ip_country_packets_total", "Total packets exchanged with remote peers per internal IP, direction and remote country", &["ip_address", "direction", "country"]),
+    // ブロードキャスト・マルチキャスト
+    counter("network_ip_tx_cast_packets_total", "Total transmitted packets per source IP, layer (l2, l3) and destination class (unicast, broadcast, multicast)", &["ip_address", "layer", "class"]),
+    counter("network_ip_tx_cast_bytes_total", "Total transmitted bytes per source IP, layer (l2, l3) and destination class (unicast, broadcast, multicast)", &["ip_address", "layer", "class"]),
+    gauge("network_ip_storm_packets_per_sec", "Broadcast / multicast frames per second sent by each source IP", &["ip_address", "class"]),
+    gauge("network_storm_packets_per_sec", "Total broadcast / multicast frames per second on the LAN from any source and EtherType", &["class"]),
+    counter("network_l2_cast_packets_total", "Total broadcast / multicast frames on the LAN per destination class and EtherType (arp, ipv4, ipv6, other)", &["class", "ethertype"]),
+    counter("network_l2_cast_bytes_total", "Total broadcast / multicast bytes on the LAN per destination class and EtherType (arp, ipv4, ipv6, other)", &["class", "ethertype"]),
+    gauge("network_storm_top_source_packets", "Estimated broadcast / multicast frames sent by each of the top source MAC addresses (upper bound)", &["mac_address", "class"]),
+    gauge("network_storm_top_source_error_packets", "Maximum overestimation of network_storm_top_source_packets", &["mac_address", "class"]),
+    counter("network_multicast_group_packets_total", "Total packets per multicast group (at most 256 groups, the rest as other)", &["group"]),
+    counter("network_multicast_group_bytes_total", "Total bytes per multicast group (at most 256 groups, the rest as other)", &["group"]),
+    // 外部IPの上位通信相手
+    gauge("network_external_top_talker_bytes", "Estimated bytes exchanged with each of the top external IPs (upper bound)", IP),
+    gauge("network_external_top_talker_error_bytes", "Maximum overestimation of network_external_top_talker_bytes per external IP", IP),
+    counter("network_external_bytes_total", "Total bytes exchanged with external IPs", &[]),
+    counter("network_external_packets_total", "Total packets exchanged with external IPs", &[]),
+    gauge("network_external_error_bound_bytes", "Worst-case error of any external IP estimate (total / counters)", &[]),
+    gauge("network_external_tracked_ips", "Number of external IPs currently held by the top talker tracker", &[]),
+];
+
+pub struct SnapshotCollector {
+    stats: SharedStats,
+    descs: Vec<Desc>,
+}
+
+impl SnapshotCollector {
+    pub fn new(stats: SharedStats) -> Self {
+        let descs = SPECS
+            .iter()
+            .map(|spec| {
+                let labels = spec.labels.iter().map(|l| l.to_string()).collect();
+                Desc::new(spec.name.to_string(), spec.help.to_string(), labels, HashMap::new()).unwrap()
+            })
+            .collect();
+        Self { stats, descs }
+    }
+}
+
+impl Collector for SnapshotCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // hosts → totals の順にロックを取る（キャプチャ側と同じ順序）
+        let hosts = self.stats.hosts.lock().unwrap();
+        let totals = self.stats.totals.lock().unwrap();
+        let mut families = Families::new();
+        build(&mut families, &hosts, &totals, &self.stats.target_ips);
+        families.finish()
+    }
+}
+
+/// SPECS の順に並んだ MetricFamily の組み立て途中の状態
+struct Families {
+    families: Vec<MetricFamily>,
+    index: HashMap<&'static str, usize>,
+}
+
+impl Families {
+    fn new() -> Self {
+        let families = SPECS
+            .iter()
+            .map(|spec| {
+                let mut family = MetricFamily::default();
+                family.set_name(spec.name.to_string());
+                family.set_help(spec.help.to_string());
+                family.set_field_type(spec.kind);
+                family
+            })
+            .collect();
+        let index = SPECS.iter().enumerate().map(|(i, spec)| (spec.name, i)).collect();
+        Self { families, index }
+    }
+
+    /// 累積値（u64 をそのまま1回だけ変換する）
+    fn counter(&mut self, name: &str, label_values: &[&str], value: u64) {
+        let mut counter = proto::Counter::default();
+        counter.set_value(value as f64);
+        let mut metric = self.metric(name, label_values);
+        metric.set_counter(counter);
+        self.push(name, metric);
+    }
+
+    fn gauge(&mut self, name: &str, label_values: &[&str], value: f64) {
+        let mut gauge = proto::Gauge::default();
+        gauge.set_value(value);
+        let mut metric = self.metric(name, label_values);
+        metric.set_gauge(gauge);
+        self.push(name, metric);
+    }
+
+    fn metric(&self, name: &str, label_values: &[&str]) -> proto::Metric {
+        let spec = &SPECS[self.index[name]];
+        debug_assert_eq!(spec.labels.len(), label_values.len(), "{}", name);
+        let labels: Vec<proto::LabelPair> = spec
+            .labels
+            .iter()
+            .zip(label_values)
+            .map(|(label, value)| {
+                let mut pair = proto::LabelPair::default();
+                pair.set_name(label.to_string());
+                pair.set_value(value.to_string());
+                pair
+            })
+            .collect();
+        let mut metric = proto::Metric::default();
+        metric.set_label(labels.into());
+        metric
+    }
+
+    fn push(&mut self, name: &str, metric: proto::Metric) {
+        self.families[self.index[name]].mut_metric().push(metric);
+    }
+
+    fn finish(self) -> Vec<MetricFamily> {
+        self.families
+    }
+}
+
+
+fn build(families: &mut Families, stats: &HashMap<IpAddr, IpStats>, totals: &TrafficTotals, target_ips: &TargetSet) {
+    // サブネット内のIPの合計（[tx, rx]）。累積値は削除したホストの分も含む totals.monitored を使う
+    let mut total_bytes_per_sec = [0u64; 2];
+    let mut total_packets_per_sec = [0u64; 2];
+    let mut total_bps = [0f64; 2];
+    let mut total_retransmissions_per_sec = 0u64;
+    let mut total_duplicate_acks_per_sec = 0u64;
+    let mut total_window_size_changes_per_sec = 0u64;
+
+    for (ip, stat) in stats {
+        let ip_str = ip.to_string();
+        let ip_label = [ip_str.as_str()];
+
+        // 累積値はスコープ別（LAN内の通信を lan_lan として分け、WANの利用量を lan_wan / wan_lan で求められる）
+        for (direction, counters) in [("tx", &stat.tx_scope), ("rx", &stat.rx_scope)] {
+            for scope in Scope::ALL {
+                if counters.packets(scope) > 0 {
+                    let labels = [ip_str.as_str(), direction, scope.label()];
+                    families.counter("network_ip_bytes_total", &labels, counters.bytes(scope));
+                    families.counter("network_ip_packets_total", &labels, counters.packets(scope));
+                }
+            }
+        }
+        let directions = [
+            ("tx", stat.tx_bytes_per_sec, stat.tx_packets_per_sec, stat.tx_current_bps),
+            ("rx", stat.rx_bytes_per_sec, stat.rx_packets_per_sec, stat.rx_current_bps),
+        ];
+        for (direction, bytes_per_sec, packets_per_sec, bps) in directions {
+            let labels = [ip_str.as_str(), direction];
+            families.gauge("network_ip_bytes_per_sec", &labels, bytes_per_sec as f64);
+            families.gauge("network_ip_packets_per_sec", &labels, packets_per_sec as f64);
+            families.gauge("network_ip_bps", &labels, bps);
+        }
+
+        // パケットロス関連
+        families.counter("network_ip_retransmissions_total", &ip_label, stat.retransmissions);
+        families.counter("network_ip_duplicate_acks_total", &ip_label, stat.duplicate_acks);
+        families.counter("network_ip_window_size_changes_total", &ip_label, stat.window_size_changes);
+        families.gauge("network_ip_retransmissions_per_sec", &ip_label, stat.retransmissions_per_sec as f64);
+        families.gauge("network_ip_duplicate_acks_per_sec", &ip_label, stat.duplicate_acks_per_sec as f64);
+        families.gauge("network_ip_window_size_changes_per_sec", &ip_label, stat.window_size_changes_per_sec as f64);
+
+        // プロトコル別・ドメイン別の累積値
+        for (direction, protocols) in [("tx", &stat.tx_protocols), ("rx", &stat.rx_protocols)] {
+            for (protocol, traffic) in protocols {
+                let labels = [ip_str.as_str(), direction, protocol];
+                families.counter("network_ip_protocol_bytes_total", &labels, traffic.bytes);
+                families.counter("network_ip_protocol_packets_total", &labels, traffic.packets);
+            }
+        }
+        for (version, packets) in &stat.quic_versions {
+            families.counter("network_ip_quic_packets_total", &[&ip_str, &quic::version_name(*version)], *packets);
+        }
+        for (domain, traffic) in &stat.domains {
+            families.counter("network_ip_domain_bytes_total", &[&ip_str, domain], traffic.bytes);
+            families.counter("network_ip_domain_packets_total", &[&ip_str, domain], traffic.packets);
+        }
+
+        // DNSの累積値（クエリを送ったことのあるクライアントのみ）
+        if stat.dns_queries > 0 || !stat.dns_responses.is_empty() {
+            families.counter("network_ip_dns_queries_total", &ip_label, stat.dns_queries);
+            families.counter("network_ip_dns_timeouts_total", &ip_label, stat.dns_timeouts);
+        }
+        for (resolver, queries) in &stat.dns_resolver_queries {
+            families.counter("network_ip_dns_resolver_queries_total", &[&ip_str, &resolver.to_string()], *queries);
+        }
+        for (rcode, responses) in &stat.dns_responses {
+            families.counter("network_ip_dns_responses_total", &[&ip_str, rcode], *responses);
+        }
+
+        // ICMPの種別・コード別の累積値
+        for (direction, messages) in [("tx", &stat.icmp_tx_messages), ("rx", &stat.icmp_rx_messages)] {
+            for ((kind, code), count) in messages {
+                families.counter("network_ip_icmp_messages_total", &[&ip_str, direction, kind, &code.to_string()], *count);
+            }
+        }
+        if stat.icmp_reported_mtu > 0 {
+            families.gauge("network_ip_icmp_reported_mtu", &ip_label, stat.icmp_reported_mtu as f64);
+        }
+
+        // DSCP / ECN の累積値
+        for (direction, dscp) in [("tx", &stat.tx_dscp), ("rx", &stat.rx_dscp)] {
+            for (class, traffic) in dscp {
+                let labels = [ip_str.as_str(), direction, class];
+                families.counter("network_ip_dscp_bytes_total", &labels, traffic.bytes);
+                families.counter("network_ip_dscp_packets_total", &labels, traffic.packets);
+            }
+        }
+        let marking_counters = [
+            ("network_ip_ecn_packets_total", "tx", &stat.tx_ecn_packets),
+            ("network_ip_ecn_packets_total", "rx", &stat.rx_ecn_packets),
+            ("network_ip_tcp_ecn_flags_total", "tx", &stat.tx_tcp_ecn_flags),
+            ("network_ip_tcp_ecn_flags_total", "rx", &stat.rx_tcp_ecn_flags),
+        ];
+        for (name, direction, values) in marking_counters {
+            for (label, value) in values {
+                families.counter(name, &[&ip_str, direction, label], *value);
+            }
+        }
+
+        // 外部IPのASN・国別の累積値
+        for (direction, asns) in [("tx", &stat.tx_asns), ("rx", &stat.rx_asns)] {
+            for ((asn, as_org), traffic) in asns {
+                let labels = [ip_str.as_str(), direction, &geoip::asn_label(*asn), as_org];
+                families.counter("network_ip_asn_bytes_total", &labels, traffic.bytes);
+                families.counter("network_ip_asn_packets_total", &labels, traffic.packets);
+            }
+        }
+        for (direction, countries) in [("tx", &stat.tx_countries), ("rx", &stat.rx_countries)] {
+            for (country, traffic) in countries {
+                let labels = [ip_str.as_str(), direction, country];
+                families.counter("network_ip_country_bytes_total", &labels, traffic.bytes);
+                families.counter("network_ip_country_packets_total", &labels, traffic.packets);
+            }
+        }
+
+        // 宛先種別（L2/L3のユニキャスト・ブロードキャスト・マルチキャスト）別の送信数
+        for ((layer, class), traffic) in &stat.tx_cast {
+            families.counter("network_ip_tx_cast_packets_total", &[&ip_str, layer, class], traffic.packets);
+            families.counter("network_ip_tx_cast_bytes_total", &[&ip_str, layer, class], traffic.bytes);
+        }
+        if stat.broadcast_packets() > 0 || stat.multicast_packets() > 0 {
+            families.gauge("network_ip_storm_packets_per_sec", &[&ip_str, "broadcast"], stat.broadcast_packets_per_sec as f64);
+            families.gauge("network_ip_storm_packets_per_sec", &[&ip_str, "multicast"], stat.multicast_packets_per_sec as f64);
+        }
+
+        // TTL・IP IDの分布と隠れNATの判定（送信パケットのあるホストのみ）
+        let signals = &stat.nat_signals;
+        if !signals.ttl_packets.is_empty() {
+            for (ttl, packets) in &signals.ttl_packets {
+                families.counter("network_ip_ttl_packets_total", &[&ip_str, &ttl.to_string()], *packets);
+            }
+            for (behavior, packets) in &signals.ipid_packets {
+                families.counter("network_ip_ipid_packets_total", &[&ip_str, behavior.label()], *packets);
+            }
+            families.gauge("network_ip_ttl_baselines", &ip_label, signals.ttl_baselines() as f64);
+            families.gauge("network_ip_tcp_clock_sources", &ip_label, signals.clock_sources() as f64);
+            families.gauge("network_ip_nat_suspected", &ip_label, if signals.nat_suspected() { 1.0 } else { 0.0 });
+        }
+
+        // target_ipsに含まれる場合のみ全体統計に含める
+        if target_ips.contains(ip) {
+            for (i, (_, bytes_per_sec, packets_per_sec, bps)) in directions.into_iter().enumerate() {
+                total_bytes_per_sec[i] += bytes_per_sec;
+                total_packets_per_sec[i] += packets_per_sec;
+                total_bps[i] += bps;
+            }
+            total_retransmissions_per_sec += stat.retransmissions_per_sec;
+            total_duplicate_acks_per_sec += stat.duplicate_acks_per_sec;
+            total_window_size_changes_per_sec += stat.window_size_changes_per_sec;
+        }
+    }
+
+    // 全体のメトリクス
+    let monitored = &totals.monitored;
+    for (i, direction) in ["tx", "rx"].into_iter().enumerate() {
+        for scope in Scope::ALL {
+            // 監視対象のホストの送信は lan_lan / lan_wan、受信は lan_lan / wan_lan にしかならない
+            if monitored.packets[i][scope.index()] > 0 {
+                let labels = [direction, scope.label()];
+                families.counter("network_bytes_total", &labels, monitored.bytes[i][scope.index()]);
+                families.counter("network_packets_total", &labels, monitored.packets[i][scope.index()]);
+            }
+        }
+        families.gauge("network_bytes_per_sec", &[direction], total_bytes_per_sec[i] as f64);
+        families.gauge("network_packets_per_sec", &[direction], total_packets_per_sec[i] as f64);
+        families.gauge("network_bps", &[direction], total_bps[i]);
+    }
+    families.counter("network_retransmissions_total", &[], monitored.retransmissions);
+    families.counter("network_duplicate_acks_total", &[], monitored.duplicate_acks);
+    families.counter("network_window_size_changes_total", &[], monitored.window_size_changes);
+    families.gauge("network_retransmissions_per_sec", &[], total_retransmissions_per_sec as f64);
+    families.gauge("network_duplicate_acks_per_sec", &[], total_duplicate_acks_per_sec as f64);
+    families.gauge("network_window_size_changes_per_sec", &[], total_window_size_changes_per_sec as f64);
+
+    // スコープ別の1秒間の値（LAN内のパケットも1回だけ数える）
+    for scope in Scope::ALL {
+        families.gauge("network_scope_bytes_per_sec", &[scope.label()], totals.scope.bytes_per_sec(scope) as f64);
+        families.gauge("network_scope_bps", &[scope.label()], totals.scope.bps(scope));
+    }
+
+    // マルチキャストグループ別の合計とブロードキャスト/マルチキャストのストーム率
+    for (group, group_stats) in totals.multicast_groups.entries() {
+        families.counter("network_multicast_group_packets_total", &[&group], group_stats.packets);
+        families.counter("network_multicast_group_bytes_total", &[&group], group_stats.bytes);
+    }
+    // ストーム率と送信元MAC別の上位は、監視対象外の送信元やARPなどIP以外のフレームも含む
+    for (class, ethertype, frame_stats) in totals.storm.entries() {
+        families.counter("network_l2_cast_packets_total", &[class.label(), ethertype], frame_stats.packets);
+        families.counter("network_l2_cast_bytes_total", &[class.label(), ethertype], frame_stats.bytes);
+    }
+    for class in [CastType::Broadcast, CastType::Multicast] {
+        families.gauge("network_storm_packets_per_sec", &[class.label()], totals.storm.packets_per_sec(class) as f64);
+        for source in totals.storm.top_sources(class, STORM_TOP_K) {
+            let mac = source.key.to_string();
+            families.gauge("network_storm_top_source_packets", &[&mac, class.label()], source.count as f64);
+            families.gauge("network_storm_top_source_error_packets", &[&mac, class.label()], source.error as f64);
+        }
+    }
+
+    // 上位の外部通信相手
+    let talkers = &totals.external_talkers;
+    for talker in talkers.top(EXTERNAL_TOP_K) {
+        let ip_str = talker.key.to_string();
+        families.gauge("network_external_top_talker_bytes", &[&ip_str], talker.count as f64);
+        families.gauge("network_external_top_talker_error_bytes", &[&ip_str], talker.error as f64);
+    }
+    families.counter("network_external_bytes_total", &[], talkers.total());
+    families.counter("network_external_packets_total", &[], totals.external_packets);
+    families.gauge("network_external_error_bound_bytes", &[], talkers.error_bound() as f64);
+    families.gauge("network_external_tracked_ips", &[], talkers.tracked() as f64);
+}
@@ -0,0 +1,441 @@
+// 設定（TOMLの設定ファイル → 環境変数 → コマンドライン引数の順に上書きする）
+//
+// SIGHUP で設定ファイルを読み直したときは、監視対象のネットワーク・しきい値・表示・ラベルだけを反映し、
+// 統計はそのまま引き継ぐ。インターフェース・キャプチャ・待ち受け先・GeoIP・ログの設定は再起動まで変わらない。
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use pnet::ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+
+use crate::{geoip, listen, logging, plain_http, tui};
+
+/// 設定ファイルのパスを指定する環境変数（`--config` と同じ）
+pub const CONFIG_ENV: &str = "LPD_CONFIG";
+/// `--config` も LPD_CONFIG もない場合に、存在すれば読む設定ファイル
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/localpacketdump/config.toml";
+
+/// フレームサイズのヒストグラムのバケットを指定する環境変数（カンマ区切りのバイト数）
+const PACKET_SIZE_BUCKETS_ENV: &str = "LPD_PACKET_SIZE_BUCKETS";
+const DEFAULT_PACKET_SIZE_BUCKETS: [f64; 8] = [64.0, 128.0, 256.0, 512.0, 1024.0, 1280.0, 1514.0, 9018.0];
+/// 無通信のホストを削除するまでの秒数を指定する環境変数
+const IDLE_TIMEOUT_ENV: &str = "LPD_IDLE_TIMEOUT_SECS";
+/// 出力するシリーズ数の上限を指定する環境変数
+const MAX_SERIES_ENV: &str = "LPD_MAX_SERIES";
+/// デーモンモードで要約をログに記録する間隔（秒）を指定する環境変数
+const SUMMARY_INTERVAL_ENV: &str = "LPD_SUMMARY_INTERVAL_SECS";
+/// デーモンモードにする環境変数（`--daemon` と同じ）
+const DAEMON_ENV: &str = "LPD_DAEMON";
+/// 累積値を保存する状態ファイルを指定する環境変数（`--state-file` と同じ）
+const STATE_FILE_ENV: &str = "LPD_STATE_FILE";
+/// 時系列の履歴を保存するファイルを指定する環境変数（`--history-file` と同じ）
+const HISTORY_FILE_ENV: &str = "LPD_HISTORY_FILE";
+
+// 表示の更新間隔の下限（統計は1秒ごとに更新されるため、これより短くしても変わらない）
+const MIN_REFRESH_INTERVAL_MS: u64 = 1000;
+
+/// 実行中に SIGHUP で差し替える設定
+pub type Settings = Arc<RwLock<Config>>;
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// キャプチャするインターフェース（空なら既定のインターフェース）
+    pub interfaces: Vec<String>,
+    /// 監視対象のネットワーク（CIDR、空ならインターフェースのサブネットとIPv6プレフィックス）
+    pub target_networks: Vec<String>,
+    /// 画面に出力せず、要約をログに記録する
+    pub daemon: bool,
+    pub capture: CaptureConfig,
+    pub exporters: ExportersConfig,
+    pub thresholds: Thresholds,
+    pub display: DisplayConfig,
+    pub geoip: GeoIpConfig,
+    pub log: LogConfig,
+    pub state: StateConfig,
+    pub history: HistoryConfig,
+    /// すべてのメトリクスに付けるラベル（例: site = "home"）
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    pub snaplen: i32,
+    pub timeout_ms: i32,
+    pub promisc: bool,
+    /// 平文HTTPとして解析するTCPポート
+    pub http_ports: Vec<u16>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            snaplen: 65535,
+            timeout_ms: 100,
+            promisc: true,
+            http_ports: vec![plain_http::DEFAULT_PORT],
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportersConfig {
+    /// メトリクス・API・ダッシュボードの待ち受け先（ip:port / [ipv6]:port / unix:/path）
+    pub listen: Vec<String>,
+    /// フレームサイズのヒストグラムのバケット（バイト）
+    pub packet_size_buckets: Vec<f64>,
+}
+
+impl Default for ExportersConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![listen::DEFAULT_LISTEN.to_string()],
+            packet_size_buckets: DEFAULT_PACKET_SIZE_BUCKETS.to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// この秒数通信のないホストを削除する
+    pub idle_timeout_secs: u64,
+    /// ホストが持つシリーズ数（ip_address ラベル付き）の上限（超えた分は最後の通信が古いホストから削除する）
+    pub max_series: usize,
+    /// デーモンモードで要約をログに記録する間隔（秒）
+    pub summary_interval_secs: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 600,
+            max_series: 100_000,
+            summary_interval_secs: 60,
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn summary_interval(&self) -> Duration {
+        Duration::from_secs(self.summary_interval_secs)
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    /// 端末では全画面表示を使う
+    pub tui: bool,
+    /// 表の出力に含めるホスト数
+    pub top_hosts: usize,
+    /// 表示を更新する間隔（ミリ秒）
+    pub refresh_interval_ms: u64,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            tui: true,
+            top_hosts: 20,
+            refresh_interval_ms: 1000,
+        }
+    }
+}
+
+impl DisplayConfig {
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_millis(self.refresh_interval_ms)
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    pub asn_db: Option<PathBuf>,
+    pub country_db: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// error / warn / info / debug / trace（`info,hyper=warn` のようにモジュール別にも指定できる）
+    pub level: String,
+    /// text / json / journald（未指定なら journal につながっていれば journald）
+    pub format: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// 再起動をまたいで累積値を引き継ぐ状態ファイル（未指定なら保存しない）
+    pub path: Option<PathBuf>,
+    /// 状態ファイルに書き込む間隔（秒、終了時にも書き込む）
+    pub checkpoint_interval_secs: u64,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            checkpoint_interval_secs: 60,
+        }
+    }
+}
+
+impl StateConfig {
+    pub fn checkpoint_interval(&self) -> Duration {
+        Duration::from_secs(self.checkpoint_interval_secs)
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// ホスト別・合計の時系列を記録する
+    pub enabled: bool,
+    /// 個別に記録するホスト数の上限（超えた分は合計にだけ含める。1ホストあたり約700KBのメモリと履歴ファイルを使う）
+    pub max_hosts: usize,
+    /// 再起動をまたいで履歴を引き継ぐファイル（未指定ならメモリ上だけに保持する）
+    pub path: Option<PathBuf>,
+    /// 履歴ファイルに書き込む間隔（秒、終了時にも書き込む）
+    pub checkpoint_interval_secs: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_hosts: 64,
+            path: None,
+            checkpoint_interval_secs: 600,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn checkpoint_interval(&self) -> Duration {
+        Duration::from_secs(self.checkpoint_interval_secs)
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: None,
+        }
+    }
+}
+
+/// 設定ファイル（指定がなければ DEFAULT_CONFIG_PATH があればそれ）を読み、環境変数で上書きする
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let default_path = Path::new(DEFAULT_CONFIG_PATH);
+    let path = path.or_else(|| default_path.exists().then_some(default_path));
+    let mut config = match path {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            toml::from_str(&text).map_err(|e| format!("Invalid configuration in {}: {}", path.display(), e))?
+        }
+        None => Config::default(),
+    };
+    config.apply_env();
+    Ok(config)
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    env_value(name).and_then(|v| v.parse().ok())
+}
+
+fn env_list<T: FromStr>(name: &str) -> Vec<T> {
+    env_value(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .filter_map(|v| v.parse().ok())
+        .collect()
+}
+
+fn env_flag(name: &str) -> Option<bool> {
+    env_value(name).map(|v| matches!(v.as_str(), "1" | "on" | "true"))
+}
+
+impl Config {
+    // 従来の環境変数は設定ファイルより優先する（読めない値は無視する）
+    fn apply_env(&mut self) {
+        let listen: Vec<String> = env_list(listen::LISTEN_ENV);
+        if !listen.is_empty() {
+            self.exporters.listen = listen;
+        }
+        let buckets: Vec<f64> = env_list(PACKET_SIZE_BUCKETS_ENV);
+        let buckets: Vec<f64> = buckets.into_iter().filter(|b| b.is_finite() && *b > 0.0).collect();
+        if !buckets.is_empty() {
+            self.exporters.packet_size_buckets = buckets;
+        }
+        let http_ports: Vec<u16> = env_list(plain_http::PORTS_ENV);
+        if !http_ports.is_empty() {
+            self.capture.http_ports = http_ports;
+        }
+        if let Some(path) = env_value(geoip::ASN_DB_ENV) {
+            self.geoip.asn_db = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env_value(geoip::COUNTRY_DB_ENV) {
+            self.geoip.country_db = Some(PathBuf::from(path));
+        }
+        if let Some(secs) = env_parse(IDLE_TIMEOUT_ENV) {
+            self.thresholds.idle_timeout_secs = secs;
+        }
+        if let Some(max_series) = env_parse(MAX_SERIES_ENV) {
+            self.thresholds.max_series = max_series;
+        }
+        if let Some(secs) = env_parse::<u64>(SUMMARY_INTERVAL_ENV).filter(|&secs| secs > 0) {
+            self.thresholds.summary_interval_secs = secs;
+        }
+        if let Some(daemon) = env_flag(DAEMON_ENV) {
+            self.daemon = daemon;
+        }
+        if let Some(disabled) = env_value(tui::TUI_ENV).map(|v| matches!(v.as_str(), "0" | "off" | "false")) {
+            self.display.tui = !disabled;
+        }
+        if let Some(level) = env_value(logging::LOG_ENV) {
+            self.log.level = level;
+        }
+        if let Some(format) = env_value(logging::LOG_FORMAT_ENV) {
+            self.log.format = Some(format);
+        }
+        if let Some(path) = env_value(STATE_FILE_ENV) {
+            self.state.path = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env_value(HISTORY_FILE_ENV) {
+            self.history.path = Some(PathBuf::from(path));
+        }
+    }
+
+    /// 値の検査と正規化（バケットの並べ替えなど）
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.parsed_target_networks()?;
+        if self.exporters.listen.is_empty() {
+            return Err("exporters.listen must contain at least one address".to_string());
+        }
+        if self.capture.snaplen <= 0 {
+            return Err(format!("capture.snaplen must be positive (got {})", self.capture.snaplen));
+        }
+        if self.capture.timeout_ms <= 0 {
+            return Err(format!("capture.timeout_ms must be positive (got {})", self.capture.timeout_ms));
+        }
+        if self.capture.http_ports.is_empty() {
+            self.capture.http_ports = vec![plain_http::DEFAULT_PORT];
+        }
+
+        let buckets = &mut self.exporters.packet_size_buckets;
+        if let Some(bucket) = buckets.iter().find(|b| !b.is_finite() || **b <= 0.0) {
+            return Err(format!("exporters.packet_size_buckets must be positive (got {})", bucket));
+        }
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        if buckets.is_empty() {
+            *buckets = DEFAULT_PACKET_SIZE_BUCKETS.to_vec();
+        }
+
+        if self.state.checkpoint_interval_secs == 0 {
+            return Err("state.checkpoint_interval_secs must be positive".to_string());
+        }
+        if self.history.checkpoint_interval_secs == 0 {
+            return Err("history.checkpoint_interval_secs must be positive".to_string());
+        }
+        if self.thresholds.summary_interval_secs == 0 {
+            return Err("thresholds.summary_interval_secs must be positive".to_string());
+        }
+        if self.display.refresh_interval_ms < MIN_REFRESH_INTERVAL_MS {
+            return Err(format!("display.refresh_interval_ms must be at least {}", MIN_REFRESH_INTERVAL_MS));
+        }
+        for name in self.labels.keys() {
+            if !is_valid_label_name(name) {
+                return Err(format!("Invalid label name '{}' (use letters, digits and underscores, not starting with a digit or __)", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// target_networks を解析したネットワーク
+    pub fn parsed_target_networks(&self) -> Result<Vec<IpNetwork>, String> {
+        self.target_networks
+            .iter()
+            .map(|network| {
+                network
+                    .trim()
+                    .parse::<IpNetwork>()
+                    .map_err(|e| format!("Invalid target network '{}': {}", network, e))
+            })
+            .collect()
+    }
+
+    /// 再読み込みでは反映されない設定の名前（変更されていれば警告する）
+    pub fn restart_required_changes(&self, other: &Config) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        if self.interfaces != other.interfaces {
+            changes.push("interfaces");
+        }
+        if self.daemon != other.daemon {
+            changes.push("daemon");
+        }
+        if self.capture != other.capture {
+            changes.push("capture");
+        }
+        if self.exporters != other.exporters {
+            changes.push("exporters");
+        }
+        if self.geoip != other.geoip {
+            changes.push("geoip");
+        }
+        if self.log != other.log {
+            changes.push("log");
+        }
+        if self.display.tui != other.display.tui {
+            changes.push("display.tui");
+        }
+        if self.state.path != other.state.path {
+            changes.push("state.path");
+        }
+        if self.history.path != other.history.path {
+            changes.push("history.path");
+        }
+        changes
+    }
+
+    /// 再読み込みでは反映されない設定を実行中の値に戻す
+    pub fn keep_restart_settings(&mut self, running: &Config) {
+        self.interfaces = running.interfaces.clone();
+        self.daemon = running.daemon;
+        self.capture = running.capture.clone();
+        self.exporters = running.exporters.clone();
+        self.geoip = running.geoip.clone();
+        self.log = running.log.clone();
+        self.display.tui = running.display.tui;
+        self.state.path = running.state.path.clone();
+        self.history.path = running.history.path.clone();
+    }
+}
+
+// Prometheus のラベル名として使えるか
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_first = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    valid_first && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') && !name.starts_with("__")
+}
@@ -0,0 +1,26 @@
+// メトリクスサーバーから配信する組み込みのWebダッシュボード（/）
+//
+// 静的ファイルはバイナリに埋め込み、表示内容は /api/v1/stream（SSE）と
+// /api/v1/totals・/api/v1/hosts/{ip} のポーリングでブラウザ側が組み立てる。
+
+use hyper::{Body, Response};
+
+const INDEX_HTML: &str = include_str!("../assets/dashboard/index.html");
+const DASHBOARD_JS: &str = include_str!("../assets/dashboard/dashboard.js");
+const DASHBOARD_CSS: &str = include_str!("../assets/dashboard/dashboard.css");
+
+/// ダッシュボードのファイルであれば返す
+pub fn response(path: &str) -> Option<Response<Body>> {
+    let (content_type, content) = match path {
+        "/" | "/index.html" => ("text/html; charset=utf-8", INDEX_HTML),
+        "/dashboard.js" => ("text/javascript; charset=utf-8", DASHBOARD_JS),
+        "/dashboard.css" => ("text/css; charset=utf-8", DASHBOARD_CSS),
+        _ => return None,
+    };
+    let response = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(content))
+        .unwrap();
+    Some(response)
+}
@@ -0,0 +1,641 @@
+// DNS (UDP/TCP 53) のクエリ・レスポンス解析
+//
+// クエリとレスポンスをトランザクションID・ポートで対応付け、
+// クライアントごとの問い合わせ先やリゾルバごとの応答時間を求める。
+// レスポンスの A / AAAA / CNAME レコードからは IP → ホスト名の対応表を作り、
+// 能動的な逆引きをせずにリモートのアドレスへ名前を付ける。
+// 応答時間のヒストグラムはリゾルバごとに十数シリーズになるため、resolver ラベルにするのは
+// /etc/resolv.conf のリゾルバとクエリの多いリゾルバだけにして、残りは "other" にまとめる。
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::heavy_hitters::SpaceSaving;
+
+pub const DNS_PORT: u16 = 53;
+
+const MAX_PENDING: usize = 8192;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+const LOG_CAPACITY: usize = 1000;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// resolver ラベルにする IP の上限と、ラベルを付けるまでに必要なクエリ数
+const MAX_RESOLVER_LABELS: usize = 16;
+const RESOLVER_LABEL_MIN_QUERIES: u64 = 20;
+// クエリ数を数えるリゾルバの数（固定メモリの近似値）
+const RESOLVER_CANDIDATES: usize = 256;
+pub const OTHER_RESOLVER: &str = "other";
+
+const MAX_HOSTNAMES: usize = 65536;
+// TTLが極端に短いCDNなどでは接続の方が長く続くため、下限を設ける
+const MIN_HOSTNAME_TTL: u32 = 60;
+const MAX_HOSTNAME_TTL: u32 = 86400;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+
+pub struct DnsMessage {
+    pub id: u16,
+    pub is_response: bool,
+    pub rcode: u8,
+    pub questions: Vec<(String, u16)>,
+    pub answers: Vec<DnsAnswer>,
+}
+
+pub enum AnswerData {
+    Address(IpAddr),
+    Cname(String),
+}
+
+pub struct DnsAnswer {
+    pub name: String,
+    pub ttl: u32,
+    pub data: AnswerData,
+}
+
+/// レスポンスから判明したアドレスと、クライアントが問い合わせた名前
+#[derive(Clone)]
+pub struct ResolvedAddress {
+    pub address: IpAddr,
+    pub name: String,
+    pub ttl: u32,
+}
+
+/// 1件のクエリとその結果
+pub struct DnsTransaction {
+    pub client: IpAddr,
+    pub resolver: IpAddr,
+    pub query: String,
+    pub qtype: u16,
+    // 応答がなかった場合は None
+    pub rcode: Option<u8>,
+    pub latency: Option<Duration>,
+    pub addresses: Vec<ResolvedAddress>,
+}
+
+pub enum DnsEvent {
+    Query { client: IpAddr, resolver: IpAddr },
+    Response(DnsTransaction),
+}
+
+// クライアント IP/ポート, リゾルバ IP, トランザクションID
+type PendingKey = (IpAddr, u16, IpAddr, u16);
+
+struct PendingQuery {
+    sent_at: Instant,
+    query: String,
+    qtype: u16,
+}
+
+pub struct DnsTracker {
+    pending: HashMap<PendingKey, PendingQuery>,
+    last_expire: Instant,
+}
+
+impl DnsTracker {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            last_expire: Instant::now(),
+        }
+    }
+
+    /// 1つの DNS メッセージを処理し、クエリ送信またはレスポンス受信のイベントを返す
+    pub fn observe(
+        &mut self,
+        message: &DnsMessage,
+        src: (IpAddr, u16),
+        dst: (IpAddr, u16),
+    ) -> Option<DnsEvent> {
+        let now = Instant::now();
+
+        if !message.is_response {
+            if dst.1 != DNS_PORT {
+                return None;
+            }
+            let (query, qtype) = message.questions.first().cloned().unwrap_or_default();
+            if self.pending.len() < MAX_PENDING {
+                self.pending.insert(
+                    (src.0, src.1, dst.0, message.id),
+                    PendingQuery {
+                        sent_at: now,
+                        query,
+                        qtype,
+                    },
+                );
+            }
+            return Some(DnsEvent::Query {
+                client: src.0,
+                resolver: dst.0,
+            });
+        }
+
+        if src.1 != DNS_PORT {
+            return None;
+        }
+        let pending = self.pending.remove(&(dst.0, dst.1, src.0, message.id));
+        let (query, qtype, latency) = match pending {
+            Some(p) => (p.query, p.qtype, Some(now.duration_since(p.sent_at))),
+            None => {
+                let (query, qtype) = message.questions.first().cloned().unwrap_or_default();
+                (query, qtype, None)
+            }
+        };
+        Some(DnsEvent::Response(DnsTransaction {
+            client: dst.0,
+            resolver: src.0,
+            query,
+            qtype,
+            rcode: Some(message.rcode),
+            latency,
+            addresses: resolved_addresses(message),
+        }))
+    }
+
+    /// 応答のないまま期限切れになったクエリを取り出す（1秒に1回だけ走査する）
+    pub fn expire(&mut self) -> Vec<DnsTransaction> {
+        let now = Instant::now();
+        if now.duration_since(self.last_expire) < EXPIRE_INTERVAL {
+            return Vec::new();
+        }
+        self.last_expire = now;
+
+        let expired: Vec<PendingKey> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| now.duration_since(p.sent_at) >= QUERY_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|key| {
+                let p = self.pending.remove(&key)?;
+                Some(DnsTransaction {
+                    client: key.0,
+                    resolver: key.2,
+                    query: p.query,
+                    qtype: p.qtype,
+                    rcode: None,
+                    latency: None,
+                    addresses: Vec::new(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// HTTP で返すクエリログの1行
+#[derive(Clone, Serialize)]
+pub struct DnsLogEntry {
+    pub timestamp: f64,
+    pub client: IpAddr,
+    pub resolver: IpAddr,
+    pub query: String,
+    pub qtype: String,
+    pub rcode: String,
+    pub latency_ms: Option<f64>,
+    pub answers: Vec<IpAddr>,
+}
+
+struct HostnameEntry {
+    name: String,
+    expires_at: Instant,
+}
+
+/// 観測した DNS 応答から作る IP → ホスト名の対応表（TTL で失効）
+pub struct HostnameCache {
+    entries: HashMap<IpAddr, HostnameEntry>,
+}
+
+impl HostnameCache {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, resolved: &ResolvedAddress) {
+        let now = Instant::now();
+        if self.entries.len() >= MAX_HOSTNAMES && !self.entries.contains_key(&resolved.address) {
+            self.entries.retain(|_, e| e.expires_at > now);
+            if self.entries.len() >= MAX_HOSTNAMES {
+                return;
+            }
+        }
+        let ttl = resolved.ttl.clamp(MIN_HOSTNAME_TTL, MAX_HOSTNAME_TTL);
+        self.entries.insert(
+            resolved.address,
+            HostnameEntry {
+                name: resolved.name.clone(),
+                expires_at: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    pub fn lookup(&self, address: &IpAddr) -> Option<&str> {
+        self.entries
+            .get(address)
+            .filter(|e| e.expires_at > Instant::now())
+            .map(|e| e.name.as_str())
+    }
+
+    /// 有効なエントリを (アドレス, 名前, 残りTTL秒) で返す
+    pub fn entries(&self) -> Vec<(IpAddr, String, u64)> {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(|(_, e)| e.expires_at > now)
+            .map(|(addr, e)| (*addr, e.name.clone(), e.expires_at.duration_since(now).as_secs()))
+            .collect()
+    }
+}
+
+/// network_dns_response_seconds の resolver ラベル
+pub struct ResolverLabels {
+    labeled: HashSet<IpAddr>,
+    queries: SpaceSaving,
+}
+
+impl ResolverLabels {
+    fn new() -> Self {
+        Self {
+            labeled: configured_resolvers(),
+            queries: SpaceSaving::new(RESOLVER_CANDIDATES),
+        }
+    }
+
+    pub fn count_query(&mut self, resolver: IpAddr) {
+        self.queries.add(resolver, 1);
+    }
+
+    /// 設定済みか、RESOLVER_LABEL_MIN_QUERIES 回以上問い合わせのあったリゾルバは IP、それ以外は "other"
+    pub fn label(&mut self, resolver: IpAddr) -> String {
+        if !self.labeled.contains(&resolver)
+            && self.labeled.len() < MAX_RESOLVER_LABELS
+            && self.queries.guaranteed(&resolver) >= RESOLVER_LABEL_MIN_QUERIES
+        {
+            self.labeled.insert(resolver);
+        }
+        if self.labeled.contains(&resolver) {
+            resolver.to_string()
+        } else {
+            OTHER_RESOLVER.to_string()
+        }
+    }
+}
+
+// /etc/resolv.conf の nameserver（読めなければ空）
+fn configured_resolvers() -> HashSet<IpAddr> {
+    let Ok(content) = std::fs::read_to_string(RESOLV_CONF) else {
+        return HashSet::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|value| {
+            // IPv6 のゾーンID（fe80::1%eth0）は除く
+            value.trim().split('%').next()?.parse().ok()
+        })
+        .take(MAX_RESOLVER_LABELS)
+        .collect()
+}
+
+/// キャプチャスレッドと HTTP サーバー・統計表示で共有する DNS の状態
+pub struct DnsState {
+    pub log: Mutex<VecDeque<DnsLogEntry>>,
+    pub hostnames: Mutex<HostnameCache>,
+    pub resolvers: Mutex<ResolverLabels>,
+}
+
+pub fn new_state() -> Arc<DnsState> {
+    Arc::new(DnsState {
+        log: Mutex::new(VecDeque::with_capacity(LOG_CAPACITY)),
+        hostnames: Mutex::new(HostnameCache::new()),
+        resolvers: Mutex::new(ResolverLabels::new()),
+    })
+}
+
+/// 完了したトランザクションをクエリログと IP → ホスト名の対応表に反映する
+pub fn record_transaction(state: &DnsState, transaction: &DnsTransaction) {
+    if !transaction.addresses.is_empty() {
+        let mut hostnames = state.hostnames.lock().unwrap();
+        for resolved in &transaction.addresses {
+            hostnames.insert(resolved);
+        }
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    let entry = DnsLogEntry {
+        timestamp,
+        client: transaction.client,
+        resolver: transaction.resolver,
+        query: transaction.query.clone(),
+        qtype: qtype_name(transaction.qtype),
+        rcode: rcode_label(transaction.rcode).to_string(),
+        latency_ms: transaction.latency.map(|d| d.as_secs_f64() * 1000.0),
+        answers: transaction.addresses.iter().map(|r| r.address).collect(),
+    };
+
+    let mut log = state.log.lock().unwrap();
+    if log.len() >= LOG_CAPACITY {
+        log.pop_front();
+    }
+    log.push_back(entry);
+}
+
+/// A / AAAA レコードのアドレスを、CNAME を遡った問い合わせ名と対応付ける
+fn resolved_addresses(message: &DnsMessage) -> Vec<ResolvedAddress> {
+    let cname_sources: HashMap<&str, &str> = message
+        .answers
+        .iter()
+        .filter_map(|a| match &a.data {
+            AnswerData::Cname(target) => Some((target.as_str(), a.name.as_str())),
+            AnswerData::Address(_) => None,
+        })
+        .collect();
+
+    message
+        .answers
+        .iter()
+        .filter_map(|a| {
+            let AnswerData::Address(address) = a.data else {
+                return None;
+            };
+            let mut name = a.name.as_str();
+            for _ in 0..8 {
+                match cname_sources.get(name) {
+                    Some(source) => name = source,
+                    None => break,
+                }
+            }
+            Some(ResolvedAddress {
+                address,
+                name: name.to_string(),
+                ttl: a.ttl,
+            })
+        })
+        .collect()
+}
+
+pub fn rcode_label(rcode: Option<u8>) -> &'static str {
+    match rcode {
+        None => "TIMEOUT",
+        Some(0) => "NOERROR",
+        Some(1) => "FORMERR",
+        Some(2) => "SERVFAIL",
+        Some(3) => "NXDOMAIN",
+        Some(4) => "NOTIMP",
+        Some(5) => "REFUSED",
+        Some(_) => "OTHER",
+    }
+}
+
+pub fn qtype_name(qtype: u16) -> String {
+    match qtype {
+        1 => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        12 => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        28 => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        64 => "SVCB".to_string(),
+        65 => "HTTPS".to_string(),
+        255 => "ANY".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+/// TCP ペイロード（2バイトの長さプレフィクス付き）の先頭にある DNS メッセージを解析する。
+/// セグメントをまたぐメッセージは再構築しない。
+pub fn parse_tcp(payload: &[u8]) -> Option<DnsMessage> {
+    let len = u16::from_be_bytes([*payload.first()?, *payload.get(1)?]) as usize;
+    parse_message(payload.get(2..2 + len)?)
+}
+
+/// UDP ペイロードの DNS メッセージを解析する
+pub fn parse_message(msg: &[u8]) -> Option<DnsMessage> {
+    let header = msg.get(..12)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let qdcount = u16::from_be_bytes([header[4], header[5]]);
+    let ancount = u16::from_be_bytes([header[6], header[7]]);
+
+    // 標準クエリ (opcode 0) 以外は対象外
+    if (flags >> 11) & 0x0f != 0 {
+        return None;
+    }
+
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..qdcount.min(4) {
+        let (name, next) = read_name(msg, pos)?;
+        let qtype = u16::from_be_bytes([*msg.get(next)?, *msg.get(next + 1)?]);
+        msg.get(next + 2..next + 4)?; // qclass
+        questions.push((name, qtype));
+        pos = next + 4;
+    }
+
+    // 解析できない回答があっても、それまでに読めた分は使う
+    let mut answers = Vec::new();
+    for _ in 0..ancount.min(64) {
+        let Some((answer, next)) = read_answer(msg, pos) else {
+            break;
+        };
+        if let Some(answer) = answer {
+            answers.push(answer);
+        }
+        pos = next;
+    }
+
+    Some(DnsMessage {
+        id,
+        is_response: flags & 0x8000 != 0,
+        rcode: (flags & 0x000f) as u8,
+        questions,
+        answers,
+    })
+}
+
+/// リソースレコードを1つ読み、A / AAAA / CNAME なら DnsAnswer として返す
+fn read_answer(msg: &[u8], start: usize) -> Option<(Option<DnsAnswer>, usize)> {
+    let (name, pos) = read_name(msg, start)?;
+    let fixed = msg.get(pos..pos + 10)?;
+    let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+    let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+    let rdata_start = pos + 10;
+    let rdata = msg.get(rdata_start..rdata_start + rdlength)?;
+    let next = rdata_start + rdlength;
+
+    let data = match (rtype, rdlength) {
+        (TYPE_A, 4) => AnswerData::Address(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+        (TYPE_AAAA, 16) => {
+            let octets: [u8; 16] = rdata.try_into().ok()?;
+            AnswerData::Address(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        (TYPE_CNAME, _) => AnswerData::Cname(read_name(msg, rdata_start)?.0),
+        _ => return Some((None, next)),
+    };
+    Some((Some(DnsAnswer { name, ttl, data }), next))
+}
+
+/// 圧縮ポインタを考慮してドメイン名を読み取り、(名前, 名前の直後の位置) を返す
+fn read_name(msg: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = start;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len & 0xc0 {
+            0x00 => {
+                if len == 0 {
+                    pos += 1;
+                    break;
+                }
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + len;
+            }
+            0xc0 => {
+                let pointer = ((len & 0x3f) << 8) | *msg.get(pos + 1)? as usize;
+                if end.is_none() {
+                    end = Some(pos + 2);
+                }
+                jumps += 1;
+                if jumps > 16 {
+                    return None;
+                }
+                pos = pointer;
+            }
+            _ => return None,
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // www.example.com の A の問い合わせに対する、CNAME（cdn.example.com）と A を返す応答。
+    // 回答の名前と CNAME の中身は圧縮ポインタで前の名前を指す
+    fn compressed_response(flags: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34];
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&[0, 1, 0, 2, 0, 0, 0, 0]);
+        // 12: www.example.com A IN
+        msg.extend_from_slice(b"\x03www\x07example\x03com\x00");
+        msg.extend_from_slice(&[0, 1, 0, 1]);
+        // 33: www.example.com（→12） CNAME TTL 300 cdn + example.com（→16）
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0x01, 0x2c, 0, 6]);
+        msg.extend_from_slice(b"\x03cdn\xc0\x10");
+        // 51: cdn.example.com（→45） A TTL 30 93.184.216.34
+        msg.extend_from_slice(&[0xc0, 45, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 34]);
+        msg
+    }
+
+    #[test]
+    fn parses_compressed_answer() {
+        let msg = parse_message(&compressed_response(0x8180)).unwrap();
+        assert_eq!(msg.id, 0x1234);
+        assert!(msg.is_response);
+        assert_eq!(msg.questions, vec![("www.example.com".to_string(), TYPE_A)]);
+        assert_eq!(msg.answers.len(), 2);
+        assert_eq!(msg.answers[0].name, "www.example.com");
+        assert!(matches!(&msg.answers[0].data, AnswerData::Cname(target) if target == "cdn.example.com"));
+        assert_eq!(msg.answers[1].name, "cdn.example.com");
+        assert_eq!(msg.answers[1].ttl, 30);
+
+        // アドレスは CNAME を遡った問い合わせ名に対応付ける
+        let resolved = resolved_addresses(&msg);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].address, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(resolved[0].name, "www.example.com");
+    }
+
+    #[test]
+    fn reads_rcode() {
+        let msg = parse_message(&compressed_response(0x8183)).unwrap();
+        assert_eq!(rcode_label(Some(msg.rcode)), "NXDOMAIN");
+        assert_eq!(rcode_label(None), "TIMEOUT");
+    }
+
+    #[test]
+    fn rejects_looping_and_truncated_pointers() {
+        let header = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+        // 自分自身を指すポインタ
+        let mut looping = header.to_vec();
+        looping.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert!(parse_message(&looping).is_none());
+
+        // 2つの名前が互いを指すポインタ
+        let mut mutual = header.to_vec();
+        mutual.extend_from_slice(&[0xc0, 14, 0xc0, 12]);
+        assert!(read_name(&mutual, 12).is_none());
+
+        // ポインタの2バイト目がない・ラベルがメッセージの外にはみ出す
+        let mut truncated = header.to_vec();
+        truncated.push(0xc0);
+        assert!(parse_message(&truncated).is_none());
+        let mut overrun = header.to_vec();
+        overrun.extend_from_slice(b"\x3fwww");
+        assert!(parse_message(&overrun).is_none());
+
+        // メッセージの外を指すポインタ
+        let mut outside = header.to_vec();
+        outside.extend_from_slice(&[0xc0, 0xff, 0, 1, 0, 1]);
+        assert!(parse_message(&outside).is_none());
+    }
+
+    #[test]
+    fn keeps_answers_before_truncation() {
+        let mut msg = compressed_response(0x8180);
+        msg.truncate(msg.len() - 2);
+        let msg = parse_message(&msg).unwrap();
+        assert_eq!(msg.answers.len(), 1);
+    }
+
+    #[test]
+    fn parses_tcp_framed_response() {
+        let msg = compressed_response(0x8180);
+        let mut framed = (msg.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&msg);
+        let parsed = parse_tcp(&framed).unwrap();
+        assert_eq!(parsed.id, 0x1234);
+        assert_eq!(parsed.answers.len(), 2);
+
+        // 長さプレフィクスより短いセグメントは再構築しない
+        assert!(parse_tcp(&framed[..framed.len() - 1]).is_none());
+        assert!(parse_tcp(&framed[..1]).is_none());
+    }
+
+    #[test]
+    fn clamps_hostname_ttl() {
+        let mut cache = HostnameCache::new();
+        let short = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let long = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        cache.insert(&ResolvedAddress { address: short, name: "short.example".to_string(), ttl: 0 });
+        cache.insert(&ResolvedAddress { address: long, name: "long.example".to_string(), ttl: u32::MAX });
+        assert_eq!(cache.lookup(&short), Some("short.example"));
+
+        let ttls: HashMap<IpAddr, u64> = cache.entries().into_iter().map(|(address, _, ttl)| (address, ttl)).collect();
+        assert!(ttls[&short] >= (MIN_HOSTNAME_TTL - 1) as u64 && ttls[&short] <= MIN_HOSTNAME_TTL as u64);
+        assert!(ttls[&long] <= MAX_HOSTNAME_TTL as u64);
+    }
+}
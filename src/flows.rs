@@ -0,0 +1,183 @@
+// ホストごとの通信フロー（プロトコル・相手のIP・ポート）と RTT
+//
+// RTT はキャプチャ地点から相手までの往復時間で、TCP のハンドシェイク
+// （SYN → SYN/ACK、またはホストが返した SYN/ACK → 相手の ACK）と ICMP エコーから求める。
+// フロー数はホストごとに上限を設け、上限に達した後の新しいフローは数えずに dropped に加える。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use pnet::packet::tcp::TcpFlags;
+
+use crate::Traffic;
+
+// ホストごとに保持するフロー数の上限
+pub const MAX_FLOWS_PER_HOST: usize = 256;
+// この時間パケットのないフローは削除する
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// ハンドシェイクの応答をこれ以上待たない
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ホストから見たフロー（ポートのないプロトコルはポートを0とする）
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: &'static str,
+    pub remote: IpAddr,
+    pub local_port: u16,
+    pub remote_port: u16,
+}
+
+#[derive(Clone, Copy)]
+enum Handshake {
+    SynSent(Instant),    // ホストが SYN を送った（相手の SYN/ACK を待つ）
+    SynAckSent(Instant), // ホストが SYN/ACK を返した（相手の ACK を待つ）
+}
+
+pub struct Flow {
+    pub tx: Traffic,
+    pub rx: Traffic,
+    last_tx_bytes: u64,
+    last_rx_bytes: u64,
+    pub tx_bytes_per_sec: u64,
+    pub rx_bytes_per_sec: u64,
+    pub last_seen: Instant,
+    handshake: Option<Handshake>,
+    pub rtt: Option<Duration>, // 直近の RTT
+}
+
+impl Flow {
+    fn new(now: Instant) -> Self {
+        Self {
+            tx: Traffic::default(),
+            rx: Traffic::default(),
+            last_tx_bytes: 0,
+            last_rx_bytes: 0,
+            tx_bytes_per_sec: 0,
+            rx_bytes_per_sec: 0,
+            last_seen: now,
+            handshake: None,
+            rtt: None,
+        }
+    }
+}
+
+/// ホスト全体の RTT の集計
+#[derive(Clone, Copy, Default)]
+pub struct RttSummary {
+    pub samples: u64,
+    pub last: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    sum: Duration,
+}
+
+impl RttSummary {
+    fn observe(&mut self, rtt: Duration) {
+        if self.samples == 0 || rtt < self.min {
+            self.min = rtt;
+        }
+        self.max = self.max.max(rtt);
+        self.last = rtt;
+        self.sum = self.sum.saturating_add(rtt);
+        self.samples += 1;
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        // サンプル数は u32 に収まらないことがあるので、ナノ秒で割る
+        (self.samples > 0).then(|| Duration::from_nanos((self.sum.as_nanos() / self.samples as u128) as u64))
+    }
+}
+
+pub struct FlowTable {
+    flows: HashMap<FlowKey, Flow>,
+    pub dropped: u64, // 上限のため数えなかったパケット数
+    pub rtt: RttSummary,
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+            dropped: 0,
+            rtt: RttSummary::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// パケットを記録する。TCP の場合はフラグからハンドシェイクの RTT を求める
+    pub fn record(&mut self, key: FlowKey, is_tx: bool, bytes: u64, tcp_flags: Option<u8>, now: Instant) {
+        if !self.flows.contains_key(&key) && self.flows.len() >= MAX_FLOWS_PER_HOST {
+            self.dropped += 1;
+            return;
+        }
+        let flow = self.flows.entry(key).or_insert_with(|| Flow::new(now));
+        if is_tx {
+            flow.tx.add(bytes);
+        } else {
+            flow.rx.add(bytes);
+        }
+        flow.last_seen = now;
+
+        let Some(flags) = tcp_flags else {
+            return;
+        };
+        let syn = flags & TcpFlags::SYN != 0;
+        let ack = flags & TcpFlags::ACK != 0;
+        let sample = match (is_tx, syn, ack, flow.handshake) {
+            (true, true, false, _) => {
+                flow.handshake = Some(Handshake::SynSent(now));
+                None
+            }
+            (true, true, true, _) => {
+                flow.handshake = Some(Handshake::SynAckSent(now));
+                None
+            }
+            (false, true, true, Some(Handshake::SynSent(sent))) => Some(now.duration_since(sent)),
+            (false, false, true, Some(Handshake::SynAckSent(sent))) => Some(now.duration_since(sent)),
+            _ => None,
+        };
+        if let Some(rtt) = sample {
+            flow.handshake = None;
+            flow.rtt = Some(rtt);
+            self.rtt.observe(rtt);
+        }
+    }
+
+    /// ICMP エコーなど、別の方法で測った RTT を記録する
+    pub fn observe_rtt(&mut self, key: FlowKey, rtt: Duration) {
+        if let Some(flow) = self.flows.get_mut(&key) {
+            flow.rtt = Some(rtt);
+        }
+        self.rtt.observe(rtt);
+    }
+
+    /// 1秒ごとに呼び、フローごとの1秒間のバイト数を求めて無通信のフローを削除する
+    pub fn roll(&mut self, now: Instant) {
+        self.flows.retain(|_, flow| now.duration_since(flow.last_seen) < FLOW_IDLE_TIMEOUT);
+        for flow in self.flows.values_mut() {
+            flow.tx_bytes_per_sec = flow.tx.bytes - flow.last_tx_bytes;
+            flow.rx_bytes_per_sec = flow.rx.bytes - flow.last_rx_bytes;
+            flow.last_tx_bytes = flow.tx.bytes;
+            flow.last_rx_bytes = flow.rx.bytes;
+            if let Some(Handshake::SynSent(sent) | Handshake::SynAckSent(sent)) = flow.handshake {
+                if now.duration_since(sent) >= HANDSHAKE_TIMEOUT {
+                    flow.handshake = None;
+                }
+            }
+        }
+    }
+
+    /// 直近1秒のバイト数、次に累積バイト数の多い順に n 件
+    pub fn top(&self, n: usize) -> Vec<(&FlowKey, &Flow)> {
+        let mut flows: Vec<_> = self.flows.iter().collect();
+        flows.sort_by_key(|(_, flow)| {
+            std::cmp::Reverse((flow.tx_bytes_per_sec + flow.rx_bytes_per_sec, flow.tx.bytes + flow.rx.bytes))
+        });
+        flows.truncate(n);
+        flows
+    }
+}
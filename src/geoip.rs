@@ -0,0 +1,119 @@
+// ローカルの MaxMind 形式データベース（GeoLite2-ASN / GeoLite2-Country など）による外部 IP の ASN・国の判定
+//
+// 外部 IP ごとの統計はラベルが際限なく増えるため、内部ホストごとに ASN・国単位へ集約する。
+// 1ホストあたりの ASN の種類数には上限を設け、超えた分は "other" にまとめる。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use maxminddb::{geoip2, Reader};
+
+/// ASN データベースのパスを指定する環境変数
+pub const ASN_DB_ENV: &str = "LPD_ASN_DB";
+/// 国データベースのパスを指定する環境変数
+pub const COUNTRY_DB_ENV: &str = "LPD_COUNTRY_DB";
+
+/// データベースに見つからなかった ASN
+pub const UNKNOWN_ASN: u32 = 0;
+/// ホストごとの上限を超えた ASN をまとめる値
+pub const OTHER_ASN: u32 = u32::MAX;
+pub const MAX_ASNS_PER_HOST: usize = 50;
+pub const MAX_COUNTRIES_PER_HOST: usize = 64;
+
+const MAX_CACHED_PEERS: usize = 65536;
+
+/// 外部 IP の所属（ASN・組織名・国コード）
+#[derive(Clone)]
+pub struct PeerInfo {
+    pub asn: u32,
+    pub as_org: Arc<str>,
+    pub country: Arc<str>,
+}
+
+/// キャプチャスレッドごとにクローンして使う（データベースは共有し、キャッシュはスレッドごと）
+#[derive(Clone)]
+pub struct GeoIp {
+    asn_reader: Option<Arc<Reader<Vec<u8>>>>,
+    country_reader: Option<Arc<Reader<Vec<u8>>>>,
+    cache: HashMap<IpAddr, PeerInfo>,
+    unknown: Arc<str>,
+}
+
+impl GeoIp {
+    /// 設定（geoip.asn_db / geoip.country_db、環境変数 LPD_ASN_DB / LPD_COUNTRY_DB）のデータベースを読み込む。
+    /// 未設定なら無効、読み込めなければ警告を出して無効のまま続行する
+    pub fn open(asn_db: Option<&Path>, country_db: Option<&Path>) -> Self {
+        Self {
+            asn_reader: asn_db.and_then(open_database),
+            country_reader: country_db.and_then(open_database),
+            cache: HashMap::new(),
+            unknown: Arc::from("unknown"),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.asn_reader.is_some() || self.country_reader.is_some()
+    }
+
+    /// 外部 IP の所属を調べる（データベースが1つもなければ None）
+    pub fn lookup(&mut self, ip: IpAddr) -> Option<PeerInfo> {
+        if !self.is_enabled() {
+            return None;
+        }
+        if let Some(peer) = self.cache.get(&ip) {
+            return Some(peer.clone());
+        }
+
+        let (asn, as_org) = self
+            .asn_reader
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::Asn>(ip).ok())
+            .map(|asn| {
+                (
+                    asn.autonomous_system_number.unwrap_or(UNKNOWN_ASN),
+                    asn.autonomous_system_organization.map(Arc::from),
+                )
+            })
+            .unwrap_or((UNKNOWN_ASN, None));
+        let country = self
+            .country_reader
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::Country>(ip).ok())
+            .and_then(|country| country.country.and_then(|c| c.iso_code).map(Arc::from));
+
+        let peer = PeerInfo {
+            asn,
+            as_org: as_org.unwrap_or_else(|| self.unknown.clone()),
+            country: country.unwrap_or_else(|| self.unknown.clone()),
+        };
+        if self.cache.len() >= MAX_CACHED_PEERS {
+            self.cache.clear();
+        }
+        self.cache.insert(ip, peer.clone());
+        Some(peer)
+    }
+}
+
+fn open_database(path: &Path) -> Option<Arc<Reader<Vec<u8>>>> {
+    match Reader::open_readfile(path) {
+        Ok(reader) => {
+            tracing::info!(path = %path.display(), database_type = %reader.metadata.database_type, "Loaded GeoIP database");
+            Some(Arc::new(reader))
+        }
+        Err(e) => {
+            tracing::error!(path = %path.display(), error = %e, "Failed to open GeoIP database");
+            None
+        }
+    }
+}
+
+/// ASN をメトリクスのラベルにする
+pub fn asn_label(asn: u32) -> String {
+    match asn {
+        UNKNOWN_ASN => "unknown".to_string(),
+        OTHER_ASN => "other".to_string(),
+        _ => asn.to_string(),
+    }
+}
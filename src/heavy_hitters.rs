@@ -0,0 +1,108 @@
+// 外部 IP の上位通信相手（とブロードキャスト・マルチキャストの上位送信元 MAC）を固定メモリで求める
+// Space-Saving アルゴリズム
+//
+// カウンタ数を超えた新しいキーは最小のカウンタを置き換え、その最小値を誤差として引き継ぐ。
+// 各エントリの真の値は count - error 以上 count 以下で、
+// 合計の 1/capacity を超える通信相手は必ず残る。
+
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy)]
+struct Counter {
+    count: u64,
+    error: u64,
+}
+
+pub struct HeavyHitter<K = IpAddr> {
+    pub key: K,
+    /// 推定値（真の値以上）
+    pub count: u64,
+    /// 過大評価の最大値（真の値は count - error 以上）
+    pub error: u64,
+}
+
+pub struct SpaceSaving<K = IpAddr> {
+    capacity: usize,
+    counters: HashMap<K, Counter>,
+    // 最小のカウンタを素早く見つけるための (count, key) の順序付き集合
+    order: BTreeSet<(u64, K)>,
+    total: u64,
+}
+
+impl<K: Copy + Ord + Hash> SpaceSaving<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            counters: HashMap::with_capacity(capacity),
+            order: BTreeSet::new(),
+            total: 0,
+        }
+    }
+
+    pub fn add(&mut self, key: K, weight: u64) {
+        self.total += weight;
+
+        if let Some(counter) = self.counters.get_mut(&key) {
+            self.order.remove(&(counter.count, key));
+            counter.count += weight;
+            self.order.insert((counter.count, key));
+            return;
+        }
+
+        let counter = if self.counters.len() < self.capacity {
+            Counter {
+                count: weight,
+                error: 0,
+            }
+        } else {
+            // 最小のカウンタを置き換える
+            let Some((min_count, min_key)) = self.order.pop_first() else {
+                return;
+            };
+            self.counters.remove(&min_key);
+            Counter {
+                count: min_count + weight,
+                error: min_count,
+            }
+        };
+        self.counters.insert(key, counter);
+        self.order.insert((counter.count, key));
+    }
+
+    /// 推定値の大きい順に上位 k 件
+    pub fn top(&self, k: usize) -> Vec<HeavyHitter<K>> {
+        self.order
+            .iter()
+            .rev()
+            .take(k)
+            .map(|&(count, key)| HeavyHitter {
+                key,
+                count,
+                error: self.counters[&key].error,
+            })
+            .collect()
+    }
+
+    /// キーの真の値の下限（count - error、数えていないキーは 0）
+    pub fn guaranteed(&self, key: &K) -> u64 {
+        self.counters.get(key).map_or(0, |counter| counter.count - counter.error)
+    }
+
+    /// これまでに加算した合計
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// どのエントリにも共通する誤差の上限（合計 / カウンタ数）
+    pub fn error_bound(&self) -> u64 {
+        self.total / self.capacity as u64
+    }
+
+    pub fn tracked(&self) -> usize {
+        self.counters.len()
+    }
+}
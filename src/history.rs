@@ -0,0 +1,781 @@
+// ホスト別・合計の時系列の履歴（固定長のリングバッファ）と範囲を指定した取得（/api/v1/history）
+//
+// 1秒ごとの値をホスト別・合計のそれぞれで 1秒 × 1時間・1分 × 1週間・1時間 × 1年 の3段階で保持する。
+// 各段階は区間番号（時刻 / 区間の長さ）で位置の決まる固定長のリングバッファで、同じ区間の値は足し合わせ
+// （1分・1時間への集約）、保持期間を過ぎた区間は次の周回で上書きする。1区間は32バイトなので、
+// 系列1つで (3600 + 10080 + 8760) × 32 バイト ≒ 700KB を使い、全体では max_hosts（既定64）と合計の分の
+// 約46MBが上限になる。リングバッファはホストが最初に通信したときに確保し、1年間通信のなかったホストは捨てる。
+//
+// history.path を指定した場合は起動時に読み込み、一定間隔（history.checkpoint_interval_secs）と終了時に書き込む。
+// ファイルはメモリ上と同じ固定長の配置（ヘッダ・合計・ホストの領域）で、書き込むのは前回から更新された区間と
+// ホストの入れ替わった領域だけにする。ファイルがない・ホストの領域が足りない・前回の書き込みに失敗した場合だけ、
+// persist と同じく一時ファイルから全体を置き換える。
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use hyper::{Body, Response};
+use serde::Serialize;
+
+use crate::api::error_response;
+use crate::{json_response, persist, scope, IpStats};
+
+/// 区間の長さと保持期間（秒）
+struct Tier {
+    step: u64,
+    retention: u64,
+    label: &'static str,
+}
+
+impl Tier {
+    // リングバッファの区間数
+    const fn slots(&self) -> usize {
+        (self.retention / self.step) as usize
+    }
+}
+
+const TIERS: [Tier; 3] = [
+    Tier { step: 1, retention: 3600, label: "1s" },
+    Tier { step: 60, retention: 7 * 86400, label: "1m" },
+    Tier { step: 3600, retention: 365 * 86400, label: "1h" },
+];
+
+// 1回の取得で返す区間数の上限（1分 × 1週間が収まる）
+const MAX_POINTS: u64 = 20_000;
+// 範囲を省略したときに返す期間（秒）
+const DEFAULT_RANGE_SECS: u64 = 3600;
+
+// 履歴ファイルの先頭と形式の版（形式が変わったら上げる）
+const FILE_MAGIC: &[u8; 8] = b"LPDHIST\0";
+const FILE_VERSION: u32 = 3;
+// ヘッダ（先頭・版・記録を始めた時刻・ホストの領域数）と1区間の長さ
+const HEADER_LEN: u64 = 8 + 4 + 8 + 8;
+const SLOT_LEN: usize = 32;
+// 領域の先頭（0: 空き / 4: IPv4 / 6: IPv6 と16バイトのアドレス）
+const REGION_HEADER_LEN: usize = 17;
+
+/// 1区間の値（送受信のバイト数・パケット数と再送の数）
+#[derive(Clone, Copy, Default, Serialize)]
+struct Sample {
+    tx_bytes: u64,
+    rx_bytes: u64,
+    tx_packets: u64,
+    rx_packets: u64,
+    retransmissions: u64,
+}
+
+impl Sample {
+    fn from_stats(stat: &IpStats) -> Self {
+        Self {
+            tx_bytes: stat.tx_bytes_per_sec,
+            rx_bytes: stat.rx_bytes_per_sec,
+            tx_packets: stat.tx_packets_per_sec,
+            rx_packets: stat.rx_packets_per_sec,
+            retransmissions: stat.retransmissions_per_sec,
+        }
+    }
+
+    fn add(&mut self, other: &Sample) {
+        self.tx_bytes += other.tx_bytes;
+        self.rx_bytes += other.rx_bytes;
+        self.tx_packets += other.tx_packets;
+        self.rx_packets += other.rx_packets;
+        self.retransmissions += other.retransmissions;
+    }
+
+    fn is_zero(&self) -> bool {
+        self.tx_packets == 0 && self.rx_packets == 0 && self.retransmissions == 0
+    }
+}
+
+/// リングバッファの1区間（区間番号が0なら空き）
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    // 区間の開始時刻 / 区間の長さ（u32 で2106年まで表せる）
+    period: u32,
+    tx_packets: u32,
+    rx_packets: u32,
+    retransmissions: u32,
+    tx_bytes: u64,
+    rx_bytes: u64,
+}
+
+impl Slot {
+    fn add(&mut self, sample: &Sample) {
+        self.tx_bytes = self.tx_bytes.saturating_add(sample.tx_bytes);
+        self.rx_bytes = self.rx_bytes.saturating_add(sample.rx_bytes);
+        add_count(&mut self.tx_packets, sample.tx_packets);
+        add_count(&mut self.rx_packets, sample.rx_packets);
+        add_count(&mut self.retransmissions, sample.retransmissions);
+    }
+
+    fn sample(&self) -> Sample {
+        Sample {
+            tx_bytes: self.tx_bytes,
+            rx_bytes: self.rx_bytes,
+            tx_packets: self.tx_packets as u64,
+            rx_packets: self.rx_packets as u64,
+            retransmissions: self.retransmissions as u64,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for value in [self.period, self.tx_packets, self.rx_packets, self.retransmissions] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [self.tx_bytes, self.rx_bytes] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn read(data: &[u8]) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+        Self {
+            period: u32_at(0),
+            tx_packets: u32_at(4),
+            rx_packets: u32_at(8),
+            retransmissions: u32_at(12),
+            tx_bytes: u64_at(16),
+            rx_bytes: u64_at(24),
+        }
+    }
+}
+
+// パケット数は u32 に収まらない分を切り捨てる（1時間の区間でも約119万pps まで数えられる）
+fn add_count(count: &mut u32, value: u64) {
+    *count = count.saturating_add(u32::try_from(value).unwrap_or(u32::MAX));
+}
+
+/// 1段階の固定長のリングバッファ（区間番号を区間数で割った余りの位置に置く）
+#[derive(Default)]
+struct Ring {
+    // 最初に記録したときに確保する
+    slots: Vec<Slot>,
+    // 最後に記録した区間番号（0 は未記録）
+    last: u32,
+}
+
+impl Ring {
+    fn add(&mut self, tier: &Tier, time: u64, sample: &Sample) {
+        if self.slots.is_empty() {
+            self.slots = vec![Slot::default(); tier.slots()];
+        }
+        // 時計が戻った場合も最後の区間に足す（区間番号を戻さない）
+        let period = ((time / tier.step) as u32).max(self.last);
+        let index = self.index(period);
+        let slot = &mut self.slots[index];
+        if slot.period != period {
+            *slot = Slot { period, ..Slot::default() };
+        }
+        slot.add(sample);
+        self.last = period;
+    }
+
+    fn index(&self, period: u32) -> usize {
+        period as usize % self.slots.len()
+    }
+
+    fn get(&self, period: u32) -> Option<&Slot> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let slot = &self.slots[self.index(period)];
+        (slot.period == period).then_some(slot)
+    }
+
+    // 最後の区間も保持期間を過ぎたか
+    fn expired(&self, tier: &Tier, now: u64) -> bool {
+        self.last as u64 * tier.step + tier.retention <= now
+    }
+
+    // 前回書き込んだ区間から最後の区間までを、ファイル上の位置（base が先頭の区間）への書き込みにする。
+    // 周回の境目をまたぐ場合は2つに分ける
+    fn dirty_writes(&self, saved: u32, base: u64, writes: &mut Vec<(u64, Vec<u8>)>) {
+        if self.slots.is_empty() {
+            return;
+        }
+        let len = self.slots.len();
+        let count = (self.last.saturating_sub(saved) as usize).saturating_add(1).min(len);
+        let first = (self.index(self.last) + len + 1 - count) % len;
+        let mut write = |start: usize, end: usize| {
+            let mut out = Vec::with_capacity((end - start) * SLOT_LEN);
+            for slot in &self.slots[start..end] {
+                slot.write(&mut out);
+            }
+            writes.push((base + (start * SLOT_LEN) as u64, out));
+        };
+        if first + count <= len {
+            write(first, first + count);
+        } else {
+            write(first, len);
+            write(0, first + count - len);
+        }
+    }
+}
+
+/// 1つの系列（ホストまたは合計）の段階ごとのリングバッファと、履歴ファイルへの書き込みの状態
+#[derive(Default)]
+struct Series {
+    rings: [Ring; 3],
+    // 履歴ファイル上のホストの領域（合計と、まだ割り当てていないホストは None）
+    region: Option<usize>,
+    // 段階ごとにファイルへ書き込んだ最後の区間番号
+    saved: [u32; 3],
+}
+
+impl Series {
+    fn add(&mut self, time: u64, sample: &Sample) {
+        for (tier, ring) in TIERS.iter().zip(&mut self.rings) {
+            ring.add(tier, time, sample);
+        }
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        TIERS.iter().zip(&self.rings).all(|(tier, ring)| ring.expired(tier, now))
+    }
+
+    // 領域全体（先頭のアドレスとすべての区間）
+    fn write_region(&self, ip: Option<IpAddr>, out: &mut Vec<u8>) {
+        let mut header = [0u8; REGION_HEADER_LEN];
+        match ip {
+            Some(IpAddr::V4(v4)) => {
+                header[0] = 4;
+                header[1..5].copy_from_slice(&v4.octets());
+            }
+            Some(IpAddr::V6(v6)) => {
+                header[0] = 6;
+                header[1..].copy_from_slice(&v6.octets());
+            }
+            None => {}
+        }
+        out.extend_from_slice(&header);
+        for (tier, ring) in TIERS.iter().zip(&self.rings) {
+            if ring.slots.is_empty() {
+                out.resize(out.len() + tier.slots() * SLOT_LEN, 0);
+            } else {
+                for slot in &ring.slots {
+                    slot.write(out);
+                }
+            }
+        }
+    }
+
+    // 前回の書き込みから更新された区間（offset は領域の先頭）
+    fn dirty_writes(&self, offset: u64, writes: &mut Vec<(u64, Vec<u8>)>) {
+        let mut base = offset + REGION_HEADER_LEN as u64;
+        for ((tier, ring), saved) in TIERS.iter().zip(&self.rings).zip(&self.saved) {
+            ring.dirty_writes(*saved, base, writes);
+            base += (tier.slots() * SLOT_LEN) as u64;
+        }
+    }
+
+    fn mark_saved(&mut self) {
+        for (saved, ring) in self.saved.iter_mut().zip(&self.rings) {
+            *saved = ring.last;
+        }
+    }
+}
+
+// 1つの系列の領域の長さ
+fn region_len() -> u64 {
+    (REGION_HEADER_LEN + TIERS.iter().map(|tier| tier.slots() * SLOT_LEN).sum::<usize>()) as u64
+}
+
+// ホストの領域の位置（ヘッダと合計の領域の後に並べる）
+fn host_offset(region: usize) -> u64 {
+    HEADER_LEN + (region as u64 + 1) * region_len()
+}
+
+/// 履歴ファイルへの書き込み（全体の置き換え、または位置ごとの書き込み）
+enum Checkpoint {
+    Full(Vec<u8>),
+    Partial(Vec<(u64, Vec<u8>)>),
+}
+
+pub struct History {
+    // 記録を始めた時刻（これより前の区間は返さない）
+    started_at: u64,
+    total: Series,
+    hosts: HashMap<IpAddr, Series>,
+    // 履歴ファイルの配置がメモリ上の領域の割り当てと一致しているか（false なら次の書き込みで全体を置き換える）
+    in_file: bool,
+    // ファイル上の空いている領域と、捨てたホストの（ファイル上ではまだ空けていない）領域
+    free_regions: Vec<usize>,
+    released: Vec<usize>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            started_at: persist::unix_secs(SystemTime::now()),
+            total: Series::default(),
+            hosts: HashMap::new(),
+            in_file: false,
+            free_regions: Vec::new(),
+            released: Vec::new(),
+        }
+    }
+
+    pub fn host_count(&self) -> usize {
+        self.hosts.len()
+    }
+
+    /// 1秒間の値を記録する。合計は監視対象のホストの和で、max_hosts を超えた新しいホストは合計にだけ含める
+    fn record(&mut self, time: u64, samples: &[(IpAddr, bool, Sample)], max_hosts: usize) {
+        let mut total = Sample::default();
+        for (ip, lan, sample) in samples {
+            if *lan {
+                total.add(sample);
+            }
+            if sample.is_zero() {
+                continue;
+            }
+            if let Some(series) = self.hosts.get_mut(ip) {
+                series.add(time, sample);
+            } else if self.hosts.len() < max_hosts {
+                self.hosts.entry(*ip).or_default().add(time, sample);
+            }
+        }
+        if !total.is_zero() {
+            self.total.add(time, &total);
+        }
+
+        let released = &mut self.released;
+        self.hosts.retain(|_, series| {
+            let expired = series.expired(time);
+            if expired {
+                released.extend(series.region);
+            }
+            !expired
+        });
+    }
+
+    // 前回の書き込みからの差分。ホストに割り当てる領域が足りなければ None
+    fn partial(&mut self) -> Option<Vec<(u64, Vec<u8>)>> {
+        let unassigned = self.hosts.values().filter(|series| series.region.is_none()).count();
+        if !self.in_file || unassigned > self.free_regions.len() + self.released.len() {
+            return None;
+        }
+        let mut writes = Vec::new();
+        for region in self.released.drain(..) {
+            writes.push((host_offset(region), vec![0; REGION_HEADER_LEN]));
+            self.free_regions.push(region);
+        }
+        self.total.dirty_writes(HEADER_LEN, &mut writes);
+        self.total.mark_saved();
+        for (ip, series) in &mut self.hosts {
+            match series.region {
+                Some(region) => series.dirty_writes(host_offset(region), &mut writes),
+                None => {
+                    // 新しく割り当てた領域には前のホストの区間が残っているので全体を書く
+                    let Some(region) = self.free_regions.pop() else {
+                        continue;
+                    };
+                    let mut out = Vec::new();
+                    series.write_region(Some(*ip), &mut out);
+                    writes.push((host_offset(region), out));
+                    series.region = Some(region);
+                }
+            }
+            series.mark_saved();
+        }
+        Some(writes)
+    }
+
+    // ファイル全体（ホストの領域は max_hosts 個と記録しているホスト数の多いほう）
+    fn full(&mut self, max_hosts: usize) -> Vec<u8> {
+        let capacity = max_hosts.max(self.hosts.len());
+        let mut out = Vec::with_capacity((host_offset(0) + capacity as u64 * region_len()) as usize);
+        out.extend_from_slice(FILE_MAGIC);
+        out.extend_from_slice(&FILE_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.started_at.to_le_bytes());
+        out.extend_from_slice(&(capacity as u64).to_le_bytes());
+        self.total.write_region(None, &mut out);
+        self.total.mark_saved();
+        for (region, (ip, series)) in self.hosts.iter_mut().enumerate() {
+            series.write_region(Some(*ip), &mut out);
+            series.region = Some(region);
+            series.mark_saved();
+        }
+        out.resize(out.len() + (capacity - self.hosts.len()) * region_len() as usize, 0);
+        self.free_regions = (self.hosts.len()..capacity).rev().collect();
+        self.released.clear();
+        self.in_file = true;
+        out
+    }
+
+    fn checkpoint(&mut self, max_hosts: usize) -> Checkpoint {
+        match self.partial() {
+            Some(writes) => Checkpoint::Partial(writes),
+            None => Checkpoint::Full(self.full(max_hosts)),
+        }
+    }
+}
+
+/// 直近1秒間の値を履歴に記録する（calculate_bps の後に呼ぶ。hosts と history を同時にはロックしない）
+pub fn record(history: &Mutex<History>, hosts: &Mutex<HashMap<IpAddr, IpStats>>, target_ips: &scope::TargetSet, max_hosts: usize) {
+    let samples: Vec<(IpAddr, bool, Sample)> = hosts
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(ip, stat)| (*ip, target_ips.contains(ip), Sample::from_stats(stat)))
+        .collect();
+    let now = persist::unix_secs(SystemTime::now());
+    history.lock().unwrap().record(now, &samples, max_hosts);
+}
+
+#[derive(Serialize)]
+struct Point {
+    time: u64, // 区間の開始（UNIX時刻、秒）
+    #[serde(flatten)]
+    sample: Sample,
+}
+
+#[derive(Serialize)]
+struct Range {
+    series: String, // IPアドレスまたは "total"
+    resolution: &'static str,
+    step: u64,
+    from: u64,
+    to: u64,
+    sum: Sample,
+    points: Vec<Point>,
+}
+
+// 時刻の指定（UNIX時刻の秒、または "-3600" のような現在からの相対秒数）
+fn parse_time(value: &str, now: u64) -> Option<u64> {
+    match value.strip_prefix('-') {
+        Some(relative) => relative.parse::<u64>().ok().map(|secs| now.saturating_sub(secs)),
+        None if value == "now" => Some(now),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_resolution(value: &str) -> Option<usize> {
+    TIERS
+        .iter()
+        .position(|tier| tier.label == value || tier.step.to_string() == value)
+}
+
+/// host（省略時は監視対象のホストの合計）の from〜to の時系列。
+/// resolution（1s / 1m / 1h）を省略した場合は、範囲が保持期間に収まる最も細かい段階を使う
+pub fn range_response(history: &Mutex<History>, params: &HashMap<String, String>) -> Response<Body> {
+    let now = persist::unix_secs(SystemTime::now());
+    let host = match params.get("host").map(|value| value.parse::<IpAddr>()) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(_)) => return error_response(400, &format!("invalid host '{}'", params["host"])),
+        None => None,
+    };
+    let to = match params.get("to") {
+        Some(value) => match parse_time(value, now) {
+            Some(to) => to.min(now),
+            None => return error_response(400, &format!("invalid to '{}' (expected UNIX seconds or -SECONDS)", value)),
+        },
+        None => now,
+    };
+    let from = match params.get("from") {
+        Some(value) => match parse_time(value, now) {
+            Some(from) => from,
+            None => return error_response(400, &format!("invalid from '{}' (expected UNIX seconds or -SECONDS)", value)),
+        },
+        None => to.saturating_sub(DEFAULT_RANGE_SECS),
+    };
+    if from > to {
+        return error_response(400, "from must not be later than to");
+    }
+    let points_in = |tier: &Tier, from: u64| (to / tier.step).saturating_sub(from / tier.step) + 1;
+    let tier_index = match params.get("resolution") {
+        Some(value) => match parse_resolution(value) {
+            Some(index) => index,
+            None => return error_response(400, &format!("invalid resolution '{}' (expected 1s, 1m or 1h)", value)),
+        },
+        None => TIERS
+            .iter()
+            .position(|tier| from + tier.retention >= now && points_in(tier, from) <= MAX_POINTS)
+            .unwrap_or(TIERS.len() - 1),
+    };
+    let tier = &TIERS[tier_index];
+
+    let history = history.lock().unwrap();
+    let series = match host {
+        Some(ip) => match history.hosts.get(&ip) {
+            Some(series) => series,
+            None => return error_response(404, &format!("no history for {}", ip)),
+        },
+        None => &history.total,
+    };
+
+    // 記録を始める前と保持期間を過ぎた区間は返さない
+    let oldest = history.started_at.max((now + 1).saturating_sub(tier.retention));
+    let start = from.max(oldest);
+    let start = start - start % tier.step;
+    if points_in(tier, start) > MAX_POINTS {
+        return error_response(
+            400,
+            &format!("range too large for resolution {} (at most {} points)", tier.label, MAX_POINTS),
+        );
+    }
+    let mut range = Range {
+        series: host.map_or_else(|| "total".to_string(), |ip| ip.to_string()),
+        resolution: tier.label,
+        step: tier.step,
+        from: start,
+        to,
+        sum: Sample::default(),
+        points: Vec::new(),
+    };
+    let ring = &series.rings[tier_index];
+    let mut time = start;
+    while time <= to {
+        let sample = ring.get((time / tier.step) as u32).map_or_else(Sample::default, Slot::sample);
+        range.sum.add(&sample);
+        range.points.push(Point { time, sample });
+        time += tier.step;
+    }
+    json_response(&range)
+}
+
+/// 履歴をファイルに書き込む（書き込んだホスト数を返す）。ロックしている間は書き込む内容をメモリ上で組み立てるだけにする
+pub fn save(history: &Mutex<History>, path: &Path, max_hosts: usize) -> Result<usize, String> {
+    let (checkpoint, hosts) = {
+        let mut history = history.lock().unwrap();
+        (history.checkpoint(max_hosts), history.hosts.len())
+    };
+    let result = match checkpoint {
+        Checkpoint::Full(data) => persist::write_atomic(path, &data),
+        Checkpoint::Partial(writes) => write_partial(path, &writes),
+    };
+    if let Err(e) = result {
+        // ファイルの中身がわからなくなったので、次は全体を置き換える
+        history.lock().unwrap().in_file = false;
+        return Err(format!("Failed to write history file {}: {}", path.display(), e));
+    }
+    Ok(hosts)
+}
+
+fn write_partial(path: &Path, writes: &[(u64, Vec<u8>)]) -> io::Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    for (offset, data) in writes {
+        file.write_all_at(data, *offset)?;
+    }
+    file.sync_data()
+}
+
+// 履歴ファイルの読み出し（長さが足りなければ None）
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    // 1つの領域（空き領域と合計はアドレスが None）
+    fn region(&mut self) -> Option<(Option<IpAddr>, Series)> {
+        let header = self.take(REGION_HEADER_LEN)?;
+        let ip = match header[0] {
+            0 => None,
+            4 => Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&header[1..5]).unwrap()))),
+            6 => Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&header[1..]).unwrap()))),
+            _ => return None,
+        };
+        let mut series = Series::default();
+        for (tier, ring) in TIERS.iter().zip(&mut series.rings) {
+            let slots: Vec<Slot> = self.take(tier.slots() * SLOT_LEN)?.chunks_exact(SLOT_LEN).map(Slot::read).collect();
+            ring.last = slots.iter().map(|slot| slot.period).max().unwrap_or(0);
+            if ring.last != 0 {
+                ring.slots = slots;
+            }
+        }
+        Some((ip, series))
+    }
+}
+
+/// 履歴ファイルを読み込む（ファイルがなければ空の履歴）。1年間通信のなかったホストは捨てる
+pub fn load(path: &Path) -> Result<History, String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(History::new()),
+        Err(e) => return Err(format!("Failed to read history file {}: {}", path.display(), e)),
+    };
+    let invalid = || format!("Invalid history file {}", path.display());
+    let mut reader = Reader { data: &data };
+    if reader.take(FILE_MAGIC.len()) != Some(FILE_MAGIC.as_slice()) {
+        return Err(invalid());
+    }
+    let version = reader.take(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).ok_or_else(invalid)?;
+    if version != FILE_VERSION {
+        return Err(format!("History file {} has version {} (expected {})", path.display(), version, FILE_VERSION));
+    }
+
+    let started_at = reader.u64().ok_or_else(invalid)?;
+    let capacity = reader.u64().ok_or_else(invalid)?;
+    if data.len() as u64 != host_offset(0) + capacity.saturating_mul(region_len()) {
+        return Err(invalid());
+    }
+    let (_, mut total) = reader.region().ok_or_else(invalid)?;
+    total.mark_saved();
+    let mut history = History {
+        started_at,
+        total,
+        hosts: HashMap::new(),
+        in_file: true,
+        free_regions: Vec::new(),
+        released: Vec::new(),
+    };
+    let now = persist::unix_secs(SystemTime::now());
+    for region in 0..capacity as usize {
+        let (ip, mut series) = reader.region().ok_or_else(invalid)?;
+        match ip {
+            None => history.free_regions.push(region),
+            Some(ip) if series.expired(now) || history.hosts.contains_key(&ip) => history.released.push(region),
+            Some(ip) => {
+                series.region = Some(region);
+                series.mark_saved();
+                history.hosts.insert(ip, series);
+            }
+        }
+    }
+    history.free_regions.reverse();
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(tx_bytes: u64) -> Sample {
+        Sample {
+            tx_bytes,
+            rx_bytes: 1,
+            tx_packets: 1,
+            rx_packets: 1,
+            retransmissions: 0,
+        }
+    }
+
+    // 1秒の段階のリングバッファの先頭の位置に当たる時刻
+    const WRAP: u64 = 1_700_000_000 / 3600 * 3600;
+
+    #[test]
+    fn ring_wraps_around() {
+        let tier = &TIERS[0];
+        let mut ring = Ring::default();
+        for time in WRAP - 3..WRAP + 3 {
+            ring.add(tier, time, &sample(time % 10));
+        }
+        assert_eq!(ring.slots.len(), 3600);
+        assert_eq!(ring.index(ring.last), 2);
+        assert_eq!(ring.get((WRAP - 1) as u32).unwrap().tx_bytes, (WRAP - 1) % 10);
+        assert_eq!(ring.get(WRAP as u32).unwrap().tx_bytes, WRAP % 10);
+
+        // 1周後の区間は同じ位置を上書きし、古い区間は返さない
+        ring.add(tier, WRAP + 3600, &sample(7));
+        assert!(ring.get(WRAP as u32).is_none());
+        assert_eq!(ring.get((WRAP + 3600) as u32).unwrap().tx_bytes, 7);
+        assert!(!ring.expired(tier, WRAP + 3600));
+        assert!(ring.expired(tier, WRAP + 7200));
+    }
+
+    #[test]
+    fn aggregates_and_keeps_order_when_clock_goes_back() {
+        let mut series = Series::default();
+        series.add(WRAP + 60, &sample(5));
+        series.add(WRAP + 119, &sample(6));
+        // 時計が戻った場合は最後の区間に足す
+        series.add(WRAP + 30, &sample(1));
+        assert_eq!(series.rings[0].get((WRAP + 119) as u32).unwrap().tx_bytes, 7);
+        assert_eq!(series.rings[1].get(((WRAP + 60) / 60) as u32).unwrap().tx_bytes, 12);
+        assert_eq!(series.rings[2].get((WRAP / 3600) as u32).unwrap().tx_packets, 3);
+        assert!(series.rings[1].get(((WRAP + 30) / 60) as u32).is_none());
+    }
+
+    #[test]
+    fn dirty_writes_split_at_wrap() {
+        let tier = &TIERS[0];
+        let mut ring = Ring::default();
+        for time in WRAP - 3..WRAP + 2 {
+            ring.add(tier, time, &sample(time % 10));
+        }
+        let saved = (WRAP - 3) as u32;
+        let mut writes = Vec::new();
+        ring.dirty_writes(saved, 1000, &mut writes);
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].0, 1000 + 3597 * SLOT_LEN as u64);
+        assert_eq!(writes[0].1.len(), 3 * SLOT_LEN);
+        assert_eq!(writes[1].0, 1000);
+        assert_eq!(writes[1].1.len(), 2 * SLOT_LEN);
+        let last = Slot::read(&writes[1].1[SLOT_LEN..]);
+        assert_eq!(last.period, (WRAP + 1) as u32);
+        assert_eq!(last.tx_bytes, (WRAP + 1) % 10);
+
+        // 前回から更新のない区間は最後の区間だけを書き直す
+        let mut writes = Vec::new();
+        ring.dirty_writes(ring.last, 1000, &mut writes);
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].0, 1000 + SLOT_LEN as u64);
+        assert_eq!(writes[0].1.len(), SLOT_LEN);
+    }
+
+    #[test]
+    fn saves_in_place_and_loads() {
+        let path = std::env::temp_dir().join(format!("lpd-history-test-{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let now = persist::unix_secs(SystemTime::now());
+        let a: IpAddr = "192.168.1.23".parse().unwrap();
+        let b: IpAddr = "fd00::23".parse().unwrap();
+
+        let mut history = History::new();
+        for time in now - 120..now - 60 {
+            history.record(time, &[(a, true, sample(time % 7)), (b, true, sample(2))], 4);
+        }
+        let history = Mutex::new(history);
+        assert_eq!(save(&history, &path, 4), Ok(2));
+        assert_eq!(fs::metadata(&path).unwrap().len(), host_offset(4));
+
+        // 2回目は更新された区間だけを書く
+        for time in now - 60..now {
+            history.lock().unwrap().record(time, &[(a, true, sample(time % 5))], 4);
+        }
+        assert_eq!(save(&history, &path, 4), Ok(2));
+        let loaded = load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let history = history.lock().unwrap();
+        assert_eq!(loaded.hosts.len(), 2);
+        assert_same(&history.total, &loaded.total);
+        for (ip, series) in &history.hosts {
+            assert_same(series, &loaded.hosts[ip]);
+        }
+    }
+
+    fn assert_same(series: &Series, loaded: &Series) {
+        for (ring, loaded) in series.rings.iter().zip(&loaded.rings) {
+            assert_eq!(ring.last, loaded.last);
+            assert_eq!(ring.slots.len(), loaded.slots.len());
+            for (slot, loaded) in ring.slots.iter().zip(&loaded.slots) {
+                assert_eq!(
+                    (slot.period, slot.tx_bytes, slot.rx_bytes, slot.tx_packets),
+                    (loaded.period, loaded.tx_bytes, loaded.rx_bytes, loaded.tx_packets)
+                );
+            }
+        }
+    }
+}
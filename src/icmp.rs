@@ -0,0 +1,217 @@
+// ICMP / ICMPv6 の種別解析とエコー要求・応答の対応付け
+//
+// 宛先到達不能（フラグメント必要を含む）・時間超過・リダイレクトを種別ごとに数え、
+// エコー要求と応答を ID・シーケンス番号で対応付けてパッシブに RTT を求める。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const MAX_PENDING_ECHOES: usize = 4096;
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+/// 監視対象外のping先をまとめる RTT ヒストグラムのラベル
+pub const EXTERNAL_TARGET: &str = "external";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IcmpKind {
+    EchoRequest,
+    EchoReply,
+    DestUnreachable,
+    // IPv4 の Fragmentation Needed と ICMPv6 の Packet Too Big
+    FragNeeded,
+    TimeExceeded,
+    Redirect,
+    NeighborDiscovery,
+    Other,
+}
+
+impl IcmpKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            IcmpKind::EchoRequest => "echo_request",
+            IcmpKind::EchoReply => "echo_reply",
+            IcmpKind::DestUnreachable => "dest_unreachable",
+            IcmpKind::FragNeeded => "frag_needed",
+            IcmpKind::TimeExceeded => "time_exceeded",
+            IcmpKind::Redirect => "redirect",
+            IcmpKind::NeighborDiscovery => "neighbor_discovery",
+            IcmpKind::Other => "other",
+        }
+    }
+}
+
+pub struct IcmpMessage {
+    pub kind: IcmpKind,
+    pub code: u8,
+    // エコー要求・応答の (ID, シーケンス番号)
+    pub echo: Option<(u16, u16)>,
+    // フラグメント必要 / Packet Too Big が通知した MTU
+    pub mtu: Option<u32>,
+}
+
+pub struct IcmpInfo {
+    pub message: IcmpMessage,
+    // エコー応答が要求と対応付けられた場合の RTT
+    pub echo_rtt: Option<Duration>,
+}
+
+// 要求元, 宛先, ID, シーケンス番号
+type EchoKey = (IpAddr, IpAddr, u16, u16);
+
+pub struct IcmpTracker {
+    pending_echoes: HashMap<EchoKey, Instant>,
+}
+
+impl IcmpTracker {
+    pub fn new() -> Self {
+        Self {
+            pending_echoes: HashMap::new(),
+        }
+    }
+
+    pub fn inspect(&mut self, message: IcmpMessage, src: IpAddr, dst: IpAddr) -> IcmpInfo {
+        let now = Instant::now();
+        let mut echo_rtt = None;
+
+        match (message.kind, message.echo) {
+            (IcmpKind::EchoRequest, Some((id, seq))) => {
+                if self.pending_echoes.len() >= MAX_PENDING_ECHOES {
+                    self.pending_echoes
+                        .retain(|_, sent_at| now.duration_since(*sent_at) < ECHO_TIMEOUT);
+                }
+                if self.pending_echoes.len() < MAX_PENDING_ECHOES {
+                    self.pending_echoes.insert((src, dst, id, seq), now);
+                }
+            }
+            (IcmpKind::EchoReply, Some((id, seq))) => {
+                if let Some(sent_at) = self.pending_echoes.remove(&(dst, src, id, seq)) {
+                    echo_rtt = Some(now.duration_since(sent_at));
+                }
+            }
+            _ => {}
+        }
+
+        IcmpInfo { message, echo_rtt }
+    }
+}
+
+/// ICMP (IPv4) メッセージを解析する
+pub fn parse_v4(payload: &[u8]) -> Option<IcmpMessage> {
+    let header = payload.get(..8)?;
+    let (icmp_type, code) = (header[0], header[1]);
+    let kind = match (icmp_type, code) {
+        (0, _) => IcmpKind::EchoReply,
+        (3, 4) => IcmpKind::FragNeeded,
+        (3, _) => IcmpKind::DestUnreachable,
+        (5, _) => IcmpKind::Redirect,
+        (8, _) => IcmpKind::EchoRequest,
+        (11, _) => IcmpKind::TimeExceeded,
+        _ => IcmpKind::Other,
+    };
+    let mtu = (kind == IcmpKind::FragNeeded)
+        .then(|| u16::from_be_bytes([header[6], header[7]]) as u32)
+        .filter(|&mtu| mtu > 0);
+    Some(IcmpMessage {
+        kind,
+        code,
+        echo: echo_fields(kind, header),
+        mtu,
+    })
+}
+
+/// ICMPv6 メッセージを解析する
+pub fn parse_v6(payload: &[u8]) -> Option<IcmpMessage> {
+    let header = payload.get(..8)?;
+    let (icmp_type, code) = (header[0], header[1]);
+    let kind = match icmp_type {
+        1 => IcmpKind::DestUnreachable,
+        2 => IcmpKind::FragNeeded,
+        3 => IcmpKind::TimeExceeded,
+        128 => IcmpKind::EchoRequest,
+        129 => IcmpKind::EchoReply,
+        137 => IcmpKind::Redirect,
+        133..=136 => IcmpKind::NeighborDiscovery,
+        _ => IcmpKind::Other,
+    };
+    let mtu = (kind == IcmpKind::FragNeeded)
+        .then(|| u32::from_be_bytes([header[4], header[5], header[6], header[7]]));
+    Some(IcmpMessage {
+        kind,
+        code,
+        echo: echo_fields(kind, header),
+        mtu,
+    })
+}
+
+fn echo_fields(kind: IcmpKind, header: &[u8]) -> Option<(u16, u16)> {
+    matches!(kind, IcmpKind::EchoRequest | IcmpKind::EchoReply).then(|| {
+        (
+            u16::from_be_bytes([header[4], header[5]]),
+            u16::from_be_bytes([header[6], header[7]]),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn parses_v4_messages() {
+        let request = parse_v4(&[8, 0, 0xf7, 0xfe, 0x12, 0x34, 0x00, 0x01, 0xde, 0xad]).unwrap();
+        assert!(request.kind == IcmpKind::EchoRequest);
+        assert_eq!(request.echo, Some((0x1234, 1)));
+        assert_eq!(request.mtu, None);
+
+        // フラグメント必要（type 3 code 4）は次ホップの MTU を持つ
+        let frag = parse_v4(&[3, 4, 0, 0, 0, 0, 0x05, 0xdc]).unwrap();
+        assert!(frag.kind == IcmpKind::FragNeeded);
+        assert_eq!(frag.mtu, Some(1500));
+        assert_eq!(frag.echo, None);
+        // MTU を載せない古いルーター
+        assert_eq!(parse_v4(&[3, 4, 0, 0, 0, 0, 0, 0]).unwrap().mtu, None);
+
+        let unreachable = parse_v4(&[3, 3, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(unreachable.kind == IcmpKind::DestUnreachable);
+        assert_eq!(unreachable.code, 3);
+        assert!(parse_v4(&[11, 0, 0, 0, 0, 0, 0, 0]).unwrap().kind == IcmpKind::TimeExceeded);
+        assert!(parse_v4(&[13, 0, 0, 0, 0, 0, 0, 0]).unwrap().kind == IcmpKind::Other);
+        assert!(parse_v4(&[0, 0, 0, 0, 0, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn parses_v6_messages() {
+        let reply = parse_v6(&[129, 0, 0, 0, 0xab, 0xcd, 0x00, 0x07]).unwrap();
+        assert!(reply.kind == IcmpKind::EchoReply);
+        assert_eq!(reply.echo, Some((0xabcd, 7)));
+
+        // Packet Too Big は32ビットの MTU を持つ
+        let too_big = parse_v6(&[2, 0, 0, 0, 0x00, 0x00, 0x05, 0x00]).unwrap();
+        assert!(too_big.kind == IcmpKind::FragNeeded);
+        assert_eq!(too_big.mtu, Some(1280));
+
+        assert!(parse_v6(&[135, 0, 0, 0, 0, 0, 0, 0]).unwrap().kind == IcmpKind::NeighborDiscovery);
+        assert!(parse_v6(&[137, 0, 0, 0, 0, 0, 0, 0]).unwrap().kind == IcmpKind::Redirect);
+        assert!(parse_v6(&[1, 4, 0, 0, 0, 0, 0, 0]).unwrap().kind == IcmpKind::DestUnreachable);
+        assert!(parse_v6(&[128, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn matches_echo_reply_to_request() {
+        let host = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 23));
+        let target = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut tracker = IcmpTracker::new();
+        let request = tracker.inspect(parse_v4(&[8, 0, 0, 0, 0, 1, 0, 9]).unwrap(), host, target);
+        assert!(request.echo_rtt.is_none());
+
+        // 別のシーケンス番号の応答とは対応付けない
+        let other = tracker.inspect(parse_v4(&[0, 0, 0, 0, 0, 1, 0, 8]).unwrap(), target, host);
+        assert!(other.echo_rtt.is_none());
+        let reply = tracker.inspect(parse_v4(&[0, 0, 0, 0, 0, 1, 0, 9]).unwrap(), target, host);
+        assert!(reply.echo_rtt.is_some());
+        // 同じ応答の重複は1回だけ数える
+        let duplicate = tracker.inspect(parse_v4(&[0, 0, 0, 0, 0, 1, 0, 9]).unwrap(), target, host);
+        assert!(duplicate.echo_rtt.is_none());
+    }
+}
@@ -0,0 +1,127 @@
+// メトリクスサーバーの待ち受け先（TCP / Unixソケット / systemd のソケットアクティベーション）
+//
+// 待ち受けは起動時にまとめて行い、ポートが使用中などで失敗した場合はその場で終了する。
+// サーバーのタスクの中で bind すると、失敗してもキャプチャだけが動き続けてしまうため。
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// 待ち受け先を指定する環境変数（カンマ区切り、例: `0.0.0.0:9090,[::]:9090,unix:/run/lpd.sock`）
+pub const LISTEN_ENV: &str = "LPD_LISTEN";
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:9090";
+
+// systemd から渡されるソケットの最初のファイルディスクリプタ（SD_LISTEN_FDS_START）
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{}", addr),
+                Err(_) => write!(f, "tcp socket"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// systemd から渡されたソケットがあればそれを使い、なければ設定（exporters.listen）の待ち受け先を開く
+pub fn bind_all(specs: &[String]) -> Result<Vec<Listener>, String> {
+    if let Some(listeners) = systemd_listeners()? {
+        return Ok(listeners);
+    }
+    specs.iter().map(|spec| spec.trim()).filter(|s| !s.is_empty()).map(bind).collect()
+}
+
+fn bind(spec: &str) -> Result<Listener, String> {
+    if let Some(path) = spec.strip_prefix("unix:") {
+        return bind_unix(PathBuf::from(path))
+            .map_err(|e| format!("Failed to listen on unix:{}: {}", path, e));
+    }
+    let addr: SocketAddr = spec
+        .parse()
+        .map_err(|_| format!("Invalid listen address '{}' (expected ip:port, [ipv6]:port or unix:/path)", spec))?;
+    bind_tcp(addr).map_err(|e| {
+        if e.kind() == io::ErrorKind::AddrInUse {
+            format!("Failed to listen on {}: address already in use (another process holds the port; change it with --listen, {} or exporters.listen)", addr, LISTEN_ENV)
+        } else {
+            format!("Failed to listen on {}: {}", addr, e)
+        }
+    })
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<Listener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // IPv4 と IPv6 を別々に指定できるよう、IPv6 のソケットは IPv6 専用にする
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    let listener: TcpListener = socket.into();
+    listener.set_nonblocking(true)?;
+    Ok(Listener::Tcp(listener))
+}
+
+fn bind_unix(path: PathBuf) -> io::Result<Listener> {
+    // 前回の実行で残ったソケットファイルだけを消す（通常のファイルは消さない）
+    if let Ok(metadata) = fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(&path)?;
+        }
+    }
+    let listener = UnixListener::bind(&path)?;
+    listener.set_nonblocking(true)?;
+    Ok(Listener::Unix(listener, path))
+}
+
+/// systemd のソケットアクティベーション（LISTEN_PID / LISTEN_FDS）で渡されたソケット
+fn systemd_listeners() -> Result<Option<Vec<Listener>>, String> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|v| v.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|v| v.parse::<RawFd>().ok())
+        .filter(|&n| n > 0)
+        .ok_or_else(|| "LISTEN_PID is set but LISTEN_FDS is missing or zero".to_string())?;
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        // 種類は getsockname で判別する（TCP として読めなければ Unix ソケット）
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        let listener = if tcp.local_addr().is_ok() {
+            Listener::Tcp(tcp)
+        } else {
+            let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+            let path = unix
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(PathBuf::from))
+                .ok_or_else(|| format!("Socket passed by systemd (fd {}) is neither TCP nor a Unix socket", fd))?;
+            Listener::Unix(unix, path)
+        };
+        match &listener {
+            Listener::Tcp(l) => l.set_nonblocking(true),
+            Listener::Unix(l, _) => l.set_nonblocking(true),
+        }
+        .map_err(|e| format!("Failed to use socket passed by systemd (fd {}): {}", fd, e))?;
+        listeners.push(listener);
+    }
+    Ok(Some(listeners))
+}
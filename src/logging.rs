@@ -0,0 +1,139 @@
+// ログの出力（テキスト / JSON / journald）
+//
+// レベルは log.level / LPD_LOG / --log-level（例: `debug`、`info,hyper=warn`）、
+// 形式は log.format / LPD_LOG_FORMAT / --log-format（text / json / journald）で指定する。
+// 形式を指定しない場合、systemd から起動されて出力が journal につながっていれば（JOURNAL_STREAM）journald に、
+// それ以外は標準エラーにテキストで出力する。journald にはフィールドを INTERFACE=eth0 のような形で送る。
+// 全画面表示の間は標準エラーに書くと画面が崩れるため、ログを保留して（最新の1行は画面の下に表示する）
+// 画面を元に戻した後にまとめて出力する。
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Mutex, PoisonError};
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use crate::config::LogConfig;
+
+pub const LOG_ENV: &str = "LPD_LOG";
+pub const LOG_FORMAT_ENV: &str = "LPD_LOG_FORMAT";
+const DEFAULT_LEVEL: &str = "info";
+const SYSLOG_IDENTIFIER: &str = "localpacketdump";
+// 全画面表示の間に保留するログの行数
+const MAX_HELD_LINES: usize = 1000;
+
+// 全画面表示の間に保留しているログ（None の間は標準エラーに書く）
+static HELD: Mutex<Option<HeldLog>> = Mutex::new(None);
+
+#[derive(Default)]
+struct HeldLog {
+    lines: VecDeque<String>,
+    dropped: usize,
+}
+
+/// 標準エラーへのログの出力先（保留中はメモリに溜める）
+struct StderrWriter;
+
+impl Write for StderrWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(held) = held.as_mut() else {
+            return io::stderr().write(buf);
+        };
+        if held.lines.len() >= MAX_HELD_LINES {
+            held.lines.pop_front();
+            held.dropped += 1;
+        }
+        held.lines.push_back(String::from_utf8_lossy(buf).into_owned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// 標準エラーへのログを保留する（全画面表示を始める前に呼ぶ）
+pub fn hold_stderr() {
+    HELD.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert_with(HeldLog::default);
+}
+
+/// 保留したログを標準エラーに出力し、以降は直接書く（全画面表示を元に戻した後に呼ぶ）
+pub fn release_stderr() {
+    let Some(held) = HELD.lock().unwrap_or_else(PoisonError::into_inner).take() else {
+        return;
+    };
+    let mut stderr = io::stderr().lock();
+    if held.dropped > 0 {
+        let _ = writeln!(stderr, "({} earlier log lines were dropped while the terminal UI was open)", held.dropped);
+    }
+    for line in held.lines {
+        let _ = stderr.write_all(line.as_bytes());
+    }
+}
+
+/// 保留中の最新のログ（色の制御文字と改行を除く）
+pub fn last_held() -> Option<String> {
+    let held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+    held.as_ref()?.lines.back().map(|line| strip_ansi(line.trim_end()))
+}
+
+// 端末の色指定（ESC [ ... 終端文字）を取り除く
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// systemd が標準出力・標準エラーを journal につないでいるか
+pub fn journal_connected() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some()
+}
+
+pub fn init(config: &LogConfig) {
+    let invalid_level = EnvFilter::try_new(&config.level).err();
+    let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL));
+    let format = config
+        .format
+        .as_deref()
+        .map(str::trim)
+        .unwrap_or(if journal_connected() { "journald" } else { "text" });
+    let registry = tracing_subscriber::registry().with(filter);
+    let text = fmt::layer().with_writer(|| StderrWriter);
+
+    match format {
+        "text" => registry.with(text).init(),
+        "json" => registry.with(fmt::layer().json().with_writer(|| StderrWriter)).init(),
+        "journald" => match tracing_journald::layer() {
+            Ok(journald) => registry
+                .with(journald.with_field_prefix(None).with_syslog_identifier(SYSLOG_IDENTIFIER.to_string()))
+                .init(),
+            Err(e) => {
+                registry.with(text).init();
+                tracing::warn!(error = %e, "journald is not available, logging to stderr");
+            }
+        },
+        other => {
+            registry.with(text).init();
+            tracing::warn!("Unknown log format '{}' (expected text, json or journald), using text", other);
+        }
+    }
+    if let Some(e) = invalid_level {
+        tracing::warn!(error = %e, "Invalid log level '{}', using {}", config.level, DEFAULT_LEVEL);
+    }
+}
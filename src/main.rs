@@ -1,236 +1,230 @@
-use pnet::datalink;
-use pnet::ipnetwork::IpNetwork;
+mod api;
+mod bounded;
+mod cast;
+mod cli;
+mod collector;
+mod config;
+mod dashboard;
+mod dns;
+mod flows;
+mod geoip;
+mod heavy_hitters;
+mod history;
+mod listen;
+mod logging;
+mod icmp;
+mod nat_detect;
+mod persist;
+mod plain_http;
+mod qos;
+mod quic;
+mod scope;
+mod shutdown;
+mod stream;
+mod tls;
+mod tui;
+
+use pnet::datalink::{self, MacAddr};
+use pnet::ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use pnet::packet::Packet;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::tcp::{TcpPacket, TcpFlags};
+use pnet::packet::udp::UdpPacket;
 use pcap::{Capture, Device};
-use std::collections::{HashMap, HashSet};
-use std::env;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::{Duration, Instant};
-use prometheus::{Counter, Gauge, Registry, TextEncoder, Encoder};
+use std::time::{Duration, Instant, SystemTime};
+use prometheus::{Gauge, Registry, TextEncoder, Encoder};
 use hyper::{Body, Response, Server};
 use hyper::service::{make_service_fn, service_fn};
+use clap::Parser;
 use tokio::runtime::Runtime;
-
-static SIGINT_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
 struct PrometheusMetrics {
     registry: Registry,
-    tx_bytes_total: Counter,
-    rx_bytes_total: Counter,
-    tx_bytes_per_sec: Gauge,
-    rx_bytes_per_sec: Gauge,
-    tx_bps: Gauge,
-    rx_bps: Gauge,
-    retransmissions_per_sec: Gauge,
-    duplicate_acks_per_sec: Gauge,
-    window_size_changes_per_sec: Gauge,
-    // 各IPごとのメトリクス
-    ip_tx_bytes_total: prometheus::CounterVec,
-    ip_rx_bytes_total: prometheus::CounterVec,
-    ip_tx_bytes_per_sec: prometheus::GaugeVec,
-    ip_rx_bytes_per_sec: prometheus::GaugeVec,
-    ip_tx_bps: prometheus::GaugeVec,
-    ip_rx_bps: prometheus::GaugeVec,
-    ip_retransmissions_per_sec: prometheus::GaugeVec,
-    ip_duplicate_acks_per_sec: prometheus::GaugeVec,
-    ip_window_size_changes_per_sec: prometheus::GaugeVec,
+    // キャプチャ時に直接記録するメトリクス（累積値・1秒間の値はスクレイプ時に collector::SnapshotCollector が出力する）
+    dns_response_seconds: prometheus::HistogramVec,
+    // 平文HTTPのメトリクス（サーバー単位）
+    http_requests_total: prometheus::CounterVec,
+    http_responses_total: prometheus::CounterVec,
+    http_response_seconds: prometheus::HistogramVec,
+    icmp_echo_rtt_seconds: prometheus::HistogramVec,
+    // フレームサイズ分布
+    ip_packet_size_bytes: prometheus::HistogramVec,
+    // ホストの削除
+    evicted_hosts_total: prometheus::CounterVec,
+    series: Gauge,
+}
+
+/// 統計の表示方法
+#[derive(Clone, Copy, PartialEq)]
+enum OutputMode {
+    Tui,    // 端末での全画面表示
+    Table,  // 表示の更新間隔ごとに表を出力（リダイレクトした場合など）
+    Daemon, // 画面には出力せず、定期的な要約をログに記録する
+}
+
+impl OutputMode {
+    // systemd から起動されて出力が journal につながっている場合もデーモンモードにする
+    fn select(config: &config::Config) -> Self {
+        if config.daemon || logging::journal_connected() {
+            OutputMode::Daemon
+        } else if tui::enabled(config) {
+            OutputMode::Tui
+        } else {
+            OutputMode::Table
+        }
+    }
 }
 
 impl PrometheusMetrics {
-    fn new() -> Self {
+    fn new(packet_size_buckets: Vec<f64>) -> Self {
         let registry = Registry::new();
         
-        // 全体のメトリクス - パケットロスは累積値として扱う
-        let tx_bytes_total = Counter::new("network_tx_bytes_total", "Total transmitted bytes").unwrap();
-        let rx_bytes_total = Counter::new("network_rx_bytes_total", "Total received bytes").unwrap();
-        let tx_bytes_per_sec = Gauge::new("network_tx_bytes_per_sec", "Transmitted bytes per second").unwrap();
-        let rx_bytes_per_sec = Gauge::new("network_rx_bytes_per_sec", "Received bytes per second").unwrap();
-        let tx_bps = Gauge::new("network_tx_bps", "Transmitted bits per second").unwrap();
-        let rx_bps = Gauge::new("network_rx_bps", "Received bits per second").unwrap();
-        let retransmissions_per_sec = Gauge::new("network_retransmissions_per_sec", "Retransmissions per second").unwrap();
-        let duplicate_acks_per_sec = Gauge::new("network_duplicate_acks_per_sec", "Duplicate ACKs per second").unwrap();
-        let window_size_changes_per_sec = Gauge::new("network_window_size_changes_per_sec", "Window size changes per second").unwrap();
-        
-        // IPごとのメトリクス
-        let ip_tx_bytes_total = prometheus::CounterVec::new(
-            prometheus::Opts::new("network_ip_tx_bytes_total", "Total transmitted bytes per IP"),
-            &["ip_address"]
-        ).unwrap();
-        let ip_rx_bytes_total = prometheus::CounterVec::new(
-            prometheus::Opts::new("network_ip_rx_bytes_total", "Total received bytes per IP"),
-            &["ip_address"]
-        ).unwrap();
-        let ip_tx_bytes_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_tx_bytes_per_sec", "Transmitted bytes per second per IP"),
-            &["ip_address"]
-        ).unwrap();
-        let ip_rx_bytes_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_rx_bytes_per_sec", "Received bytes per second per IP"),
-            &["ip_address"]
+        // リゾルバの応答時間はキャプチャ時に直接ヒストグラムへ記録する
+        let dns_response_seconds = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new("network_dns_response_seconds", "DNS query-response latency per resolver (configured or frequently used resolvers, the rest as other)")
+                .buckets(vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            &["resolver"]
         ).unwrap();
-        let ip_tx_bps = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_tx_bps", "Transmitted bits per second per IP"),
-            &["ip_address"]
-        ).unwrap();
-        let ip_rx_bps = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_rx_bps", "Received bits per second per IP"),
-            &["ip_address"]
-        ).unwrap();
-        // パケットロス関連は1秒間の値をGaugeで表示
-        let ip_retransmissions_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_retransmissions_per_sec", "Retransmissions per second per IP"),
-            &["ip_address"]
+        
+        // 平文HTTPのメトリクス（サーバー単位。監視対象外のサーバーは external にまとめる）
+        let http_requests_total = prometheus::CounterVec::new(
+            prometheus::Opts::new("network_http_requests_total", "Total plaintext HTTP requests per monitored server (others as external), host, method and path prefix"),
+            &["server", "host", "method", "path"]
         ).unwrap();
-        let ip_duplicate_acks_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_duplicate_acks_per_sec", "Duplicate ACKs per second per IP"),
-            &["ip_address"]
+        let http_responses_total = prometheus::CounterVec::new(
+            prometheus::Opts::new("network_http_responses_total", "Total plaintext HTTP responses per monitored server (others as external), host and status class"),
+            &["server", "host", "status_class"]
         ).unwrap();
-        let ip_window_size_changes_per_sec = prometheus::GaugeVec::new(
-            prometheus::Opts::new("network_ip_window_size_changes_per_sec", "Window size changes per second per IP"),
-            &["ip_address"]
+        let http_response_seconds = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new("network_http_response_seconds", "Plaintext HTTP request-to-response latency per monitored server (others as external) and host")
+                .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["server", "host"]
         ).unwrap();
         
-        // 累積値のパケットロスメトリクスも追加
-        let ip_retransmissions_total = prometheus::CounterVec::new(
-            prometheus::Opts::new("network_ip_retransmissions_total", "Total retransmissions per IP"),
-            &["ip_address"]
-        ).unwrap();
-        let ip_duplicate_acks_total = prometheus::CounterVec::new(
-            prometheus::Opts::new("network_ip_duplicate_acks_total", "Total duplicate ACKs per IP"),
-            &["ip_address"]
+        // ICMPエコーの往復時間
+        let icmp_echo_rtt_seconds = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new("network_icmp_echo_rtt_seconds", "Passive ICMP echo round-trip time per responding monitored host (other targets as external)")
+                .buckets(vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+            &["target"]
         ).unwrap();
-        let ip_window_size_changes_total = prometheus::CounterVec::new(
-            prometheus::Opts::new("network_ip_window_size_changes_total", "Total window size changes per IP"),
-            &["ip_address"]
+        
+        // フレームサイズ分布（パケットごとにキャプチャ時に記録）
+        let ip_packet_size_bytes = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new("network_ip_packet_size_bytes", "Frame size distribution per IP and direction (tx, rx)")
+                .buckets(packet_size_buckets),
+            &["ip_address", "direction"]
         ).unwrap();
         
-        // メトリクス登録
-        registry.register(Box::new(tx_bytes_total.clone())).unwrap();
-        registry.register(Box::new(rx_bytes_total.clone())).unwrap();
-        registry.register(Box::new(tx_bytes_per_sec.clone())).unwrap();
-        registry.register(Box::new(rx_bytes_per_sec.clone())).unwrap();
-        registry.register(Box::new(tx_bps.clone())).unwrap();
-        registry.register(Box::new(rx_bps.clone())).unwrap();
-        registry.register(Box::new(retransmissions_per_sec.clone())).unwrap();
-        registry.register(Box::new(duplicate_acks_per_sec.clone())).unwrap();
-        registry.register(Box::new(window_size_changes_per_sec.clone())).unwrap();
+        // ホストの削除
+        let evicted_hosts_total = prometheus::CounterVec::new(
+            prometheus::Opts::new("network_evicted_hosts_total", "Total hosts removed together with their series (reason: idle, series_limit)"),
+            &["reason"]
+        ).unwrap();
+        let series = Gauge::new("network_metric_series", "Number of series currently exported by this exporter").unwrap();
         
-        registry.register(Box::new(ip_tx_bytes_total.clone())).unwrap();
-        registry.register(Box::new(ip_rx_bytes_total.clone())).unwrap();
-        registry.register(Box::new(ip_tx_bytes_per_sec.clone())).unwrap();
-        registry.register(Box::new(ip_rx_bytes_per_sec.clone())).unwrap();
-        registry.register(Box::new(ip_tx_bps.clone())).unwrap();
-        registry.register(Box::new(ip_rx_bps.clone())).unwrap();
-        registry.register(Box::new(ip_retransmissions_per_sec.clone())).unwrap();
-        registry.register(Box::new(ip_duplicate_acks_per_sec.clone())).unwrap();
-        registry.register(Box::new(ip_window_size_changes_per_sec.clone())).unwrap();
-        registry.register(Box::new(ip_retransmissions_total.clone())).unwrap();
-        registry.register(Box::new(ip_duplicate_acks_total.clone())).unwrap();
-        registry.register(Box::new(ip_window_size_changes_total.clone())).unwrap();
+        registry.register(Box::new(dns_response_seconds.clone())).unwrap();
+        registry.register(Box::new(http_requests_total.clone())).unwrap();
+        registry.register(Box::new(http_responses_total.clone())).unwrap();
+        registry.register(Box::new(http_response_seconds.clone())).unwrap();
+        registry.register(Box::new(icmp_echo_rtt_seconds.clone())).unwrap();
+        registry.register(Box::new(ip_packet_size_bytes.clone())).unwrap();
+        registry.register(Box::new(evicted_hosts_total.clone())).unwrap();
+        registry.register(Box::new(series.clone())).unwrap();
         
         Self {
             registry,
-            tx_bytes_total,
-            rx_bytes_total,
-            tx_bytes_per_sec,
-            rx_bytes_per_sec,
-            tx_bps,
-            rx_bps,
-            retransmissions_per_sec,
-            duplicate_acks_per_sec,
-            window_size_changes_per_sec,
-            ip_tx_bytes_total,
-            ip_rx_bytes_total,
-            ip_tx_bytes_per_sec,
-            ip_rx_bytes_per_sec,
-            ip_tx_bps,
-            ip_rx_bps,
-            ip_retransmissions_per_sec,
-            ip_duplicate_acks_per_sec,
-            ip_window_size_changes_per_sec,
+            dns_response_seconds,
+            http_requests_total,
+            http_responses_total,
+            http_response_seconds,
+            icmp_echo_rtt_seconds,
+            ip_packet_size_bytes,
+            evicted_hosts_total,
+            series,
         }
     }
     
-    fn update_metrics(&self, stats: &HashMap<IpAddr, IpStats>, target_ips: &HashSet<IpAddr>) {
-        let mut total_tx_bytes = 0u64;
-        let mut total_rx_bytes = 0u64;
-        let mut total_tx_bytes_per_sec = 0u64;
-        let mut total_rx_bytes_per_sec = 0u64;
-        let mut total_tx_bps = 0f64;
-        let mut total_rx_bps = 0f64;
-        let mut total_retransmissions_per_sec = 0u64;
-        let mut total_duplicate_acks_per_sec = 0u64;
-        let mut total_window_size_changes_per_sec = 0u64;
-        
-        for (ip, stat) in stats {
-            let ip_str = ip.to_string();
-            
-            // 累積値は一度だけ設定（reset使わない）
-            let tx_counter = self.ip_tx_bytes_total.with_label_values(&[&ip_str]);
-            let rx_counter = self.ip_rx_bytes_total.with_label_values(&[&ip_str]);
-            
-            // 現在の値を取得して差分を計算
-            let current_tx = tx_counter.get();
-            let current_rx = rx_counter.get();
-            
-            if stat.tx_byte_count as f64 > current_tx {
-                tx_counter.inc_by(stat.tx_byte_count as f64 - current_tx);
-            }
-            if stat.rx_byte_count as f64 > current_rx {
-                rx_counter.inc_by(stat.rx_byte_count as f64 - current_rx);
-            }
-            
-            // 1秒間の値はGaugeで設定
-            self.ip_tx_bytes_per_sec.with_label_values(&[&ip_str]).set(stat.tx_bytes_per_sec as f64);
-            self.ip_rx_bytes_per_sec.with_label_values(&[&ip_str]).set(stat.rx_bytes_per_sec as f64);
-            self.ip_tx_bps.with_label_values(&[&ip_str]).set(stat.tx_current_bps);
-            self.ip_rx_bps.with_label_values(&[&ip_str]).set(stat.rx_current_bps);
-            
-            // パケットロス関連も同じように処理
-            self.ip_retransmissions_per_sec.with_label_values(&[&ip_str]).set(stat.retransmissions_per_sec as f64);
-            self.ip_duplicate_acks_per_sec.with_label_values(&[&ip_str]).set(stat.duplicate_acks_per_sec as f64);
-            self.ip_window_size_changes_per_sec.with_label_values(&[&ip_str]).set(stat.window_size_changes_per_sec as f64);
-            
-            // target_ipsに含まれる場合のみ全体統計に含める
-            if target_ips.contains(ip) {
-                total_tx_bytes += stat.tx_byte_count;
-                total_rx_bytes += stat.rx_byte_count;
-                total_tx_bytes_per_sec += stat.tx_bytes_per_sec;
-                total_rx_bytes_per_sec += stat.rx_bytes_per_sec;
-                total_tx_bps += stat.tx_current_bps;
-                total_rx_bps += stat.rx_current_bps;
-                total_retransmissions_per_sec += stat.retransmissions_per_sec;
-                total_duplicate_acks_per_sec += stat.duplicate_acks_per_sec;
-                total_window_size_changes_per_sec += stat.window_size_changes_per_sec;
+    /// 現在出力しているシリーズの総数と、ip_address（ICMPエコーは target、HTTPは server）ラベルの値ごとのシリーズ数
+    /// （外部IPの上位通信相手も ip_address を持つので、ホストのシリーズ数は IpStats のキーで絞って数える）
+    fn series_by_ip(&self) -> (usize, HashMap<String, usize>) {
+        let mut total = 0;
+        let mut by_ip = HashMap::new();
+        for family in self.registry.gather() {
+            for metric in family.get_metric() {
+                total += 1;
+                if let Some(label) = metric.get_label().iter().find(|l| matches!(l.get_name(), "ip_address" | "target" | "server")) {
+                    *by_ip.entry(label.get_value().to_string()).or_insert(0) += 1;
+                }
             }
         }
-        
-        // 全体のメトリクスを更新（累積値は適切に処理）
-        let current_total_tx = self.tx_bytes_total.get();
-        let current_total_rx = self.rx_bytes_total.get();
-        
-        if total_tx_bytes as f64 > current_total_tx {
-            self.tx_bytes_total.inc_by(total_tx_bytes as f64 - current_total_tx);
+        (total, by_ip)
+    }
+    
+    /// 削除したホストのシリーズを取り除く。
+    /// スナップショットから作るメトリクスは IpStats から消えた時点で出力されなくなるので、
+    /// キャプチャ時に直接記録しているヒストグラムだけを対象にする
+    fn remove_host(&self, ip: &IpAddr, reason: &str) {
+        let ip_str = ip.to_string();
+        remove_label_series(&self.ip_packet_size_bytes, "ip_address", &ip_str);
+        remove_label_series(&self.icmp_echo_rtt_seconds, "target", &ip_str);
+        remove_label_series(&self.http_requests_total, "server", &ip_str);
+        remove_label_series(&self.http_responses_total, "server", &ip_str);
+        remove_label_series(&self.http_response_seconds, "server", &ip_str);
+        self.evicted_hosts_total.with_label_values(&[reason]).inc();
+    }
+    
+    fn observe_packet_size(&self, ip: &IpAddr, is_tx: bool, bytes: u64) {
+        self.ip_packet_size_bytes
+            .with_label_values(&[&ip.to_string(), direction(is_tx)])
+            .observe(bytes as f64);
+    }
+    
+    /// 設定の labels をすべてのシリーズに付けて集める（同じ名前のラベルを既に持つシリーズには付けない）
+    fn gather(&self, labels: &BTreeMap<String, String>) -> Vec<prometheus::proto::MetricFamily> {
+        let mut families = self.registry.gather();
+        if labels.is_empty() {
+            return families;
+        }
+        for family in &mut families {
+            for metric in family.mut_metric().iter_mut() {
+                let mut pairs = metric.take_label().into_vec();
+                for (name, value) in labels {
+                    if pairs.iter().all(|pair| pair.get_name() != name) {
+                        let mut pair = prometheus::proto::LabelPair::new();
+                        pair.set_name(name.clone());
+                        pair.set_value(value.clone());
+                        pairs.push(pair);
+                    }
+                }
+                pairs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                metric.set_label(pairs.into());
+            }
         }
-        if total_rx_bytes as f64 > current_total_rx {
-            self.rx_bytes_total.inc_by(total_rx_bytes as f64 - current_total_rx);
+        families
+    }
+}
+
+// 指定したラベルの値を持つシリーズをMetricVecから取り除く
+fn remove_label_series<T: prometheus::core::MetricVecBuilder>(vec: &prometheus::core::MetricVec<T>, name: &str, value: &str) {
+    use prometheus::core::Collector;
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric.get_label().iter().map(|l| (l.get_name(), l.get_value())).collect();
+            if labels.get(name) == Some(&value) {
+                let _ = vec.remove(&labels);
+            }
         }
-        
-        self.tx_bytes_per_sec.set(total_tx_bytes_per_sec as f64);
-        self.rx_bytes_per_sec.set(total_rx_bytes_per_sec as f64);
-        self.tx_bps.set(total_tx_bps);
-        self.rx_bps.set(total_rx_bps);
-        self.retransmissions_per_sec.set(total_retransmissions_per_sec as f64);
-        self.duplicate_acks_per_sec.set(total_duplicate_acks_per_sec as f64);
-        self.window_size_changes_per_sec.set(total_window_size_changes_per_sec as f64);
     }
 }
 
@@ -246,6 +240,13 @@ struct IpStats {
     rx_current_bps: f64,   // 受信ビット/秒
     tx_bytes_per_sec: u64, // 1秒間の送信バイト数
     rx_bytes_per_sec: u64, // 1秒間の受信バイト数
+    tx_last_packets: u64,
+    rx_last_packets: u64,
+    last_active: Instant,  // 最後にパケットを送受信した時刻（1秒単位）
+    first_seen: SystemTime, // 最初に観測した時刻（状態ファイルから復元した場合は前回の実行での時刻）
+    last_seen: SystemTime,  // 最後にパケットを送受信した時刻（1秒単位、状態ファイルに保存する）
+    tx_packets_per_sec: u64, // 1秒間の送信パケット数
+    rx_packets_per_sec: u64, // 1秒間の受信パケット数
     
     // パケットロス関連
     expected_seq: HashMap<u16, u32>, // ポート別の期待シーケンス番号
@@ -261,6 +262,624 @@ struct IpStats {
     window_size_changes: u64,            // ウィンドウサイズ変更回数
     last_window_size_changes: u64,       // 前回のウィンドウサイズ変更回数
     window_size_changes_per_sec: u64,    // 1秒間のウィンドウサイズ変更回数
+
+    // プロトコル別・ドメイン別の内訳
+    tx_protocols: HashMap<&'static str, Traffic>,  // プロトコル別送信量
+    rx_protocols: HashMap<&'static str, Traffic>,  // プロトコル別受信量
+    domains: HashMap<String, Traffic>,             // SNIで判明したドメイン別の通信量
+    quic_versions: HashMap<u32, u64>,              // QUICバージョン別パケット数
+
+    // DNS関連（このIPがクライアントとして送ったクエリ）
+    dns_queries: u64,                              // クエリ数
+    dns_resolver_queries: HashMap<IpAddr, u64>,    // リゾルバ別クエリ数
+    dns_responses: HashMap<&'static str, u64>,     // rcode別レスポンス数
+    dns_timeouts: u64,                             // 応答のなかったクエリ数
+
+    // ICMP関連（種別・コード別のメッセージ数）
+    icmp_tx_messages: HashMap<(&'static str, u8), u64>, // 送信したICMPメッセージ数
+    icmp_rx_messages: HashMap<(&'static str, u8), u64>, // 受信したICMPメッセージ数
+    icmp_reported_mtu: u32,                             // 直近のフラグメント必要/Packet Too Bigが通知したMTU
+
+    // DSCP / ECN マーキング
+    tx_dscp: HashMap<&'static str, Traffic>,       // DSCPクラス別送信量
+    rx_dscp: HashMap<&'static str, Traffic>,       // DSCPクラス別受信量
+    tx_ecn_packets: HashMap<&'static str, u64>,    // ECNコードポイント別送信パケット数（Not-ECTを除く）
+    rx_ecn_packets: HashMap<&'static str, u64>,    // ECNコードポイント別受信パケット数（Not-ECTを除く）
+    tx_tcp_ecn_flags: HashMap<&'static str, u64>,  // 送信したTCPのECE/CWRフラグ数
+    rx_tcp_ecn_flags: HashMap<&'static str, u64>,  // 受信したTCPのECE/CWRフラグ数
+
+    // TTL・IP ID・TCPタイムスタンプ（隠れNATの検出用、送信パケットのみ）
+    nat_signals: nat_detect::HostSignals,
+
+    // スコープ（LAN↔LAN / LAN→WAN / WAN→LAN）別の通信量
+    tx_scope: scope::ScopeCounters,
+    rx_scope: scope::ScopeCounters,
+
+    // 外部の通信相手のASN（番号, 組織名）・国別の通信量
+    tx_asns: HashMap<(u32, Arc<str>), Traffic>,
+    rx_asns: HashMap<(u32, Arc<str>), Traffic>,
+    tx_countries: HashMap<Arc<str>, Traffic>,
+    rx_countries: HashMap<Arc<str>, Traffic>,
+
+    // 宛先種別（レイヤー, 種別）別の送信数とブロードキャスト/マルチキャストのストーム率（L2基準）
+    tx_cast: HashMap<(&'static str, &'static str), Traffic>,
+    last_broadcast_packets: u64,
+    last_multicast_packets: u64,
+    broadcast_packets_per_sec: u64, // 1秒間のブロードキャスト送信フレーム数
+    multicast_packets_per_sec: u64, // 1秒間のマルチキャスト送信フレーム数
+
+    // 通信相手・ポート別のフローと RTT
+    flows: flows::FlowTable,
+}
+
+impl IpStats {
+    fn new() -> Self {
+        Self {
+            tx_packet_count: 0,
+            rx_packet_count: 0,
+            tx_byte_count: 0,
+            rx_byte_count: 0,
+            tx_last_bytes: 0,
+            rx_last_bytes: 0,
+            last_time: Instant::now(),
+            tx_current_bps: 0.0,
+            rx_current_bps: 0.0,
+            tx_bytes_per_sec: 0,
+            rx_bytes_per_sec: 0,
+            tx_last_packets: 0,
+            rx_last_packets: 0,
+            last_active: Instant::now(),
+            first_seen: SystemTime::now(),
+            last_seen: SystemTime::now(),
+            tx_packets_per_sec: 0,
+            rx_packets_per_sec: 0,
+            expected_seq: HashMap::new(),
+            retransmissions: 0,
+            duplicate_acks: 0,
+            last_retransmissions: 0,
+            last_duplicate_acks: 0,
+            retransmissions_per_sec: 0,
+            duplicate_acks_per_sec: 0,
+            last_window_size: HashMap::new(),
+            window_size_changes: 0,
+            last_window_size_changes: 0,
+            window_size_changes_per_sec: 0,
+            tx_protocols: HashMap::new(),
+            rx_protocols: HashMap::new(),
+            domains: HashMap::new(),
+            quic_versions: HashMap::new(),
+            dns_queries: 0,
+            dns_resolver_queries: HashMap::new(),
+            dns_responses: HashMap::new(),
+            dns_timeouts: 0,
+            icmp_tx_messages: HashMap::new(),
+            icmp_rx_messages: HashMap::new(),
+            icmp_reported_mtu: 0,
+            tx_dscp: HashMap::new(),
+            rx_dscp: HashMap::new(),
+            tx_ecn_packets: HashMap::new(),
+            rx_ecn_packets: HashMap::new(),
+            tx_tcp_ecn_flags: HashMap::new(),
+            rx_tcp_ecn_flags: HashMap::new(),
+            nat_signals: nat_detect::HostSignals::new(),
+            tx_scope: scope::ScopeCounters::new(),
+            rx_scope: scope::ScopeCounters::new(),
+            tx_asns: HashMap::new(),
+            rx_asns: HashMap::new(),
+            tx_countries: HashMap::new(),
+            rx_countries: HashMap::new(),
+            tx_cast: HashMap::new(),
+            last_broadcast_packets: 0,
+            last_multicast_packets: 0,
+            broadcast_packets_per_sec: 0,
+            multicast_packets_per_sec: 0,
+            flows: flows::FlowTable::new(),
+        }
+    }
+}
+
+impl IpStats {
+    /// 平均フレームサイズ（直近1秒、パケットがなければ累積値から）
+    fn average_packet_size(&self) -> u64 {
+        (self.tx_bytes_per_sec + self.rx_bytes_per_sec)
+            .checked_div(self.tx_packets_per_sec + self.rx_packets_per_sec)
+            .or_else(|| (self.tx_byte_count + self.rx_byte_count).checked_div(self.tx_packet_count + self.rx_packet_count))
+            .unwrap_or(0)
+    }
+
+    fn broadcast_packets(&self) -> u64 {
+        self.tx_cast.get(&("l2", "broadcast")).map_or(0, |t| t.packets)
+    }
+
+    fn multicast_packets(&self) -> u64 {
+        self.tx_cast.get(&("l2", "multicast")).map_or(0, |t| t.packets)
+    }
+}
+
+/// direction ラベルの値
+fn direction(is_tx: bool) -> &'static str {
+    if is_tx { "tx" } else { "rx" }
+}
+
+/// パケット数とバイト数の組
+#[derive(Clone, Copy, Default, serde::Serialize)]
+struct Traffic {
+    packets: u64,
+    bytes: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, bytes: u64) {
+        self.packets += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// キャプチャ・統計表示・HTTPサーバーで共有する統計（ロックは hosts → totals の順に取る）
+#[derive(Clone)]
+struct SharedStats {
+    hosts: Arc<Mutex<HashMap<IpAddr, IpStats>>>,
+    totals: Arc<Mutex<TrafficTotals>>,
+    target_ips: scope::TargetSet,
+    // 1秒ごとの更新の配信（/api/v1/stream）
+    updates: stream::Updates,
+    // 累積値を保存する状態ファイル（state.path が未設定なら None）
+    state: Option<Arc<persist::StateFile>>,
+    // ホスト別・合計の時系列の履歴（/api/v1/history）
+    history: Arc<Mutex<history::History>>,
+}
+
+/// 監視対象のホストの合計の累積値（network_bytes_total など）。
+/// 生きているホストの和にするとホストの削除や監視対象の変更で値が減り、Prometheus がリセットと誤認するので、
+/// バイト数・パケット数はパケットごとに、TCPの値は calculate_bps で求めた増分と削除したホストの残りの増分を足していく
+#[derive(Default)]
+struct MonitoredTotals {
+    bytes: [[u64; 3]; 2],   // [tx, rx] × スコープ
+    packets: [[u64; 3]; 2], // [tx, rx] × スコープ
+    retransmissions: u64,
+    duplicate_acks: u64,
+    window_size_changes: u64,
+}
+
+impl MonitoredTotals {
+    fn add(&mut self, is_tx: bool, scope: scope::Scope, bytes: u64) {
+        let direction = if is_tx { 0 } else { 1 };
+        self.bytes[direction][scope.index()] += bytes;
+        self.packets[direction][scope.index()] += 1;
+    }
+
+    // 前回の calculate_bps 以降のTCPの値の増分を足す（last_* を更新する前に呼ぶ）
+    fn add_pending(&mut self, stat: &IpStats) {
+        self.retransmissions += stat.retransmissions - stat.last_retransmissions;
+        self.duplicate_acks += stat.duplicate_acks - stat.last_duplicate_acks;
+        self.window_size_changes += stat.window_size_changes - stat.last_window_size_changes;
+    }
+}
+
+/// ホストに属さない、パケットごとに1回だけ数える全体の集計
+struct TrafficTotals {
+    monitored: MonitoredTotals,
+    scope: scope::ScopeCounters,
+    multicast_groups: cast::GroupCounters,
+    // 宛先MACがブロードキャスト・マルチキャストのフレーム（送信元・EtherTypeによらない）
+    storm: cast::StormCounters,
+    // 外部IP別の送受信バイト数（固定メモリの近似値、外部IPごとのIpStatsは作らない）
+    external_talkers: heavy_hitters::SpaceSaving,
+    external_packets: u64,
+}
+
+impl TrafficTotals {
+    fn new() -> Self {
+        Self {
+            monitored: MonitoredTotals::default(),
+            scope: scope::ScopeCounters::new(),
+            multicast_groups: cast::GroupCounters::new(),
+            storm: cast::StormCounters::new(),
+            external_talkers: heavy_hitters::SpaceSaving::new(heavy_hitters::DEFAULT_CAPACITY),
+            external_packets: 0,
+        }
+    }
+}
+
+/// キャプチャスレッドが所有するプロトコル解析器
+struct Analyzers {
+    quic: quic::QuicTracker,
+    dns: dns::DnsTracker,
+    http: plain_http::HttpTracker,
+    tls: tls::TlsTracker,
+    icmp: icmp::IcmpTracker,
+}
+
+impl Analyzers {
+    fn new(http_ports: HashSet<u16>) -> Self {
+        Self {
+            quic: quic::QuicTracker::new(),
+            dns: dns::DnsTracker::new(),
+            http: plain_http::HttpTracker::new(http_ports),
+            tls: tls::TlsTracker::new(),
+            icmp: icmp::IcmpTracker::new(),
+        }
+    }
+}
+
+/// L4の判定結果（プロトコルのバケット名と各解析器の結果）
+struct L4Info {
+    protocol: &'static str,
+    quic: Option<quic::QuicPacketInfo>,
+    dns: Option<dns::DnsEvent>,
+    http: Option<plain_http::HttpEvent>,
+    tls_sni: Option<String>, // TCP 上の TLS の ClientHello で判明した SNI
+    icmp: Option<icmp::IcmpInfo>,
+    tcp_flags: Option<u8>,
+    tcp_timestamp: Option<(u16, u32)>, // (送信元ポート, TSval)
+    ports: Option<(u16, u16)>,         // TCP / UDP の (送信元ポート, 宛先ポート)
+}
+
+fn inspect_l4(
+    next_header: IpNextHeaderProtocol,
+    payload: &[u8],
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    analyzers: &mut Analyzers,
+) -> L4Info {
+    let mut info = L4Info {
+        protocol: match next_header {
+            IpNextHeaderProtocols::Tcp => "tcp",
+            IpNextHeaderProtocols::Udp => "udp",
+            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => "icmp",
+            _ => "other",
+        },
+        quic: None,
+        dns: None,
+        http: None,
+        tls_sni: None,
+        icmp: None,
+        tcp_flags: None,
+        tcp_timestamp: None,
+        ports: None,
+    };
+
+    match next_header {
+        IpNextHeaderProtocols::Udp => {
+            if let Some(udp) = UdpPacket::new(payload) {
+                info.ports = Some((udp.get_source(), udp.get_destination()));
+                let src = (src_ip, udp.get_source());
+                let dst = (dst_ip, udp.get_destination());
+                if src.1 == dns::DNS_PORT || dst.1 == dns::DNS_PORT {
+                    if let Some(message) = dns::parse_message(udp.payload()) {
+                        info.dns = analyzers.dns.observe(&message, src, dst);
+                    }
+                } else {
+                    info.quic = analyzers.quic.inspect(src, dst, udp.payload());
+                    if info.quic.is_some() {
+                        info.protocol = "quic";
+                    }
+                }
+            }
+        }
+        IpNextHeaderProtocols::Tcp => {
+            if let Some(tcp) = TcpPacket::new(payload) {
+                info.tcp_flags = Some(tcp.get_flags());
+                info.ports = Some((tcp.get_source(), tcp.get_destination()));
+                let options_end = (tcp.get_data_offset() as usize * 4).min(tcp.packet().len());
+                info.tcp_timestamp = tcp
+                    .packet()
+                    .get(20..options_end)
+                    .and_then(nat_detect::tcp_timestamp)
+                    .map(|tsval| (tcp.get_source(), tsval));
+                let src = (src_ip, tcp.get_source());
+                let dst = (dst_ip, tcp.get_destination());
+                if src.1 == dns::DNS_PORT || dst.1 == dns::DNS_PORT {
+                    if let Some(message) = dns::parse_tcp(tcp.payload()) {
+                        info.dns = analyzers.dns.observe(&message, src, dst);
+                    }
+                } else {
+                    info.http = analyzers.http.inspect(src, dst, tcp.get_sequence(), tcp.payload());
+                    info.tls_sni = analyzers.tls.inspect(src, dst, tcp.get_sequence(), tcp.payload());
+                }
+            }
+        }
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+            let message = if next_header == IpNextHeaderProtocols::Icmp {
+                icmp::parse_v4(payload)
+            } else {
+                icmp::parse_v6(payload)
+            };
+            info.icmp = message.map(|m| analyzers.icmp.inspect(m, src_ip, dst_ip));
+        }
+        _ => {}
+    }
+
+    info
+}
+
+fn record_l4_stats(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, is_tx: bool, bytes: u64, l4: &L4Info) {
+    let entry = stats.entry(ip).or_insert_with(IpStats::new);
+
+    let protocols = if is_tx { &mut entry.tx_protocols } else { &mut entry.rx_protocols };
+    protocols.entry(l4.protocol).or_default().add(bytes);
+
+    if let Some(quic) = &l4.quic {
+        *entry.quic_versions.entry(quic.version).or_insert(0) += 1;
+    }
+    // QUIC と TCP 上の TLS の SNI（ドメインの種類数はホストごとに上限を設ける）
+    if let Some(sni) = l4.quic.as_ref().and_then(|quic| quic.sni.as_ref()).or(l4.tls_sni.as_ref()) {
+        bounded::entry(&mut entry.domains, sni.clone(), || tls::OTHER_DOMAIN.to_string(), tls::MAX_DOMAINS_PER_HOST).add(bytes);
+    }
+
+    if let Some(icmp) = &l4.icmp {
+        let key = (icmp.message.kind.label(), icmp.message.code);
+        let messages = if is_tx { &mut entry.icmp_tx_messages } else { &mut entry.icmp_rx_messages };
+        *messages.entry(key).or_insert(0) += 1;
+        // PMTUの通知は受け取った側（送信元ホスト）に記録
+        if let (false, Some(mtu)) = (is_tx, icmp.message.mtu) {
+            entry.icmp_reported_mtu = mtu;
+        }
+    }
+
+    if let Some(flags) = l4.tcp_flags {
+        let ecn_flags = if is_tx { &mut entry.tx_tcp_ecn_flags } else { &mut entry.rx_tcp_ecn_flags };
+        if flags & TcpFlags::ECE != 0 {
+            *ecn_flags.entry("ece").or_insert(0) += 1;
+        }
+        if flags & TcpFlags::CWR != 0 {
+            *ecn_flags.entry("cwr").or_insert(0) += 1;
+        }
+    }
+}
+
+// 送信パケットのTTL・IP ID・TCPタイムスタンプを記録する。
+// マルチキャストやブロードキャストはOSごとに異なるTTLを使うため、ユニキャストのTCP/UDPのみ対象とする
+fn record_nat_signals(stats: &mut HashMap<IpAddr, IpStats>, src_ip: IpAddr, dst_ip: IpAddr, ttl: u8, ip_id: Option<u16>, l4: &L4Info) {
+    let is_unicast = match dst_ip {
+        IpAddr::V4(v4) => !v4.is_multicast() && !v4.is_broadcast(),
+        IpAddr::V6(v6) => !v6.is_multicast(),
+    };
+    if !is_unicast || !matches!(l4.protocol, "tcp" | "udp" | "quic") {
+        return;
+    }
+
+    let signals = &mut stats.entry(src_ip).or_insert_with(IpStats::new).nat_signals;
+    signals.observe_ttl(ttl);
+    if let Some(ip_id) = ip_id {
+        signals.observe_ipid(ip_id);
+    }
+    if let Some((src_port, tsval)) = l4.tcp_timestamp {
+        signals.observe_tcp_timestamp(src_port, tsval);
+    }
+}
+
+// 監視対象ホストから見たフロー（相手のIP・ポート）を記録する。
+// エコー応答を受け取った場合は、ping を送ったホストのフローに RTT を記録する
+fn record_flow(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, is_tx: bool, remote: IpAddr, bytes: u64, l4: &L4Info) {
+    let (src_port, dst_port) = l4.ports.unwrap_or((0, 0));
+    let (local_port, remote_port) = if is_tx { (src_port, dst_port) } else { (dst_port, src_port) };
+    let key = flows::FlowKey {
+        protocol: l4.protocol,
+        remote,
+        local_port,
+        remote_port,
+    };
+    let flows = &mut stats.entry(ip).or_insert_with(IpStats::new).flows;
+    flows.record(key, is_tx, bytes, l4.tcp_flags, Instant::now());
+    if let (false, Some(rtt)) = (is_tx, l4.icmp.as_ref().and_then(|icmp| icmp.echo_rtt)) {
+        flows.observe_rtt(key, rtt);
+    }
+}
+
+// パケットのスコープを判定する。ブロードキャスト・マルチキャストのフレームはLANの外に出ない
+fn classify_scope(target_ips: &scope::TargetSet, src_ip: IpAddr, dst_ip: IpAddr, dst_mac: MacAddr) -> Option<scope::Scope> {
+    let is_lan = |ip: IpAddr| target_ips.contains(&ip) || scope::is_link_scoped(ip);
+    scope::classify(is_lan(src_ip), is_lan(dst_ip) || dst_mac.is_multicast())
+}
+
+// スコープ別のバイト数を、全体の合計（1パケット1回）と監視対象ホストごとに記録する
+fn record_scope(
+    stats: &mut HashMap<IpAddr, IpStats>,
+    totals: &Mutex<TrafficTotals>,
+    scope: Option<scope::Scope>,
+    tx_ip: Option<IpAddr>,
+    rx_ip: Option<IpAddr>,
+    bytes: u64,
+) {
+    let Some(scope) = scope else {
+        return;
+    };
+    let mut totals = totals.lock().unwrap();
+    totals.scope.add(scope, bytes);
+    if let Some(ip) = tx_ip {
+        totals.monitored.add(true, scope, bytes);
+        stats.entry(ip).or_insert_with(IpStats::new).tx_scope.add(scope, bytes);
+    }
+    if let Some(ip) = rx_ip {
+        totals.monitored.add(false, scope, bytes);
+        stats.entry(ip).or_insert_with(IpStats::new).rx_scope.add(scope, bytes);
+    }
+}
+
+// インターネット側の通信相手をASN・国に集約して、内部ホストごとに記録する
+fn record_remote_peer(
+    stats: &mut HashMap<IpAddr, IpStats>,
+    geoip: &mut geoip::GeoIp,
+    scope: Option<scope::Scope>,
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    track_host: impl Fn(&IpAddr) -> bool,
+    bytes: u64,
+) {
+    let (host, remote, is_tx) = match scope {
+        Some(scope::Scope::LanToWan) => (src_ip, dst_ip, true),
+        Some(scope::Scope::WanToLan) => (dst_ip, src_ip, false),
+        _ => return,
+    };
+    if !track_host(&host) {
+        return;
+    }
+    let Some(peer) = geoip.lookup(remote) else {
+        return;
+    };
+
+    let entry = stats.entry(host).or_insert_with(IpStats::new);
+    let (asns, countries) = if is_tx {
+        (&mut entry.tx_asns, &mut entry.tx_countries)
+    } else {
+        (&mut entry.rx_asns, &mut entry.rx_countries)
+    };
+    bounded::entry(
+        asns,
+        (peer.asn, peer.as_org),
+        || (geoip::OTHER_ASN, Arc::from("other")),
+        geoip::MAX_ASNS_PER_HOST,
+    )
+    .add(bytes);
+    bounded::entry(countries, peer.country, || Arc::from("other"), geoip::MAX_COUNTRIES_PER_HOST).add(bytes);
+}
+
+// インターネット側の通信相手を上位通信相手の集計に加える
+fn record_external_talker(totals: &Mutex<TrafficTotals>, scope: Option<scope::Scope>, src_ip: IpAddr, dst_ip: IpAddr, bytes: u64) {
+    let remote = match scope {
+        Some(scope::Scope::LanToWan) => dst_ip,
+        Some(scope::Scope::WanToLan) => src_ip,
+        _ => return,
+    };
+    let mut totals = totals.lock().unwrap();
+    totals.external_talkers.add(remote, bytes);
+    totals.external_packets += 1;
+}
+
+// 送信元ごとの宛先種別と、マルチキャストグループ別の合計を記録する
+fn record_cast(
+    stats: &mut HashMap<IpAddr, IpStats>,
+    totals: &Mutex<TrafficTotals>,
+    src_ip: Option<IpAddr>,
+    dst_ip: IpAddr,
+    l2: cast::CastType,
+    l3: cast::CastType,
+    bytes: u64,
+) {
+    if let Some(ip) = src_ip {
+        let entry = stats.entry(ip).or_insert_with(IpStats::new);
+        for key in [("l2", l2.label()), ("l3", l3.label())] {
+            entry.tx_cast.entry(key).or_default().add(bytes);
+        }
+    }
+    if l3 == cast::CastType::Multicast {
+        totals.lock().unwrap().multicast_groups.add(dst_ip, bytes);
+    }
+}
+
+// IPv4のTOS / IPv6のトラフィッククラスからDSCPとECNを記録
+fn record_traffic_class(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, is_tx: bool, bytes: u64, traffic_class: u8) {
+    let entry = stats.entry(ip).or_insert_with(IpStats::new);
+
+    let dscp = if is_tx { &mut entry.tx_dscp } else { &mut entry.rx_dscp };
+    dscp.entry(qos::dscp_name(qos::dscp(traffic_class))).or_default().add(bytes);
+
+    let ecn = qos::ecn(traffic_class);
+    if ecn != qos::ECN_NOT_ECT {
+        let ecn_packets = if is_tx { &mut entry.tx_ecn_packets } else { &mut entry.rx_ecn_packets };
+        *ecn_packets.entry(qos::ecn_name(ecn)).or_insert(0) += 1;
+    }
+}
+
+// エコー応答のRTTは応答元（ping先）ごとのヒストグラムに記録する。
+// ping先は任意のアドレスになり得るので、ラベルにするのは監視対象ホスト（削除時にシリーズも消す）だけで、
+// それ以外の宛先は "external" にまとめる（個別のRTTはフローの詳細で見られる）
+fn record_icmp_rtt(icmp: &icmp::IcmpInfo, responder: IpAddr, target_ips: &scope::TargetSet, prometheus_metrics: &PrometheusMetrics) {
+    if let Some(rtt) = icmp.echo_rtt {
+        let target = if target_ips.contains(&responder) { responder.to_string() } else { icmp::EXTERNAL_TARGET.to_string() };
+        prometheus_metrics
+            .icmp_echo_rtt_seconds
+            .with_label_values(&[&target])
+            .observe(rtt.as_secs_f64());
+    }
+}
+
+// HTTPのメトリクスはサーバー単位なので、キャプチャ時に直接記録する。
+// サーバーは任意のアドレスになり得るので、ラベルにするのは監視対象ホスト（削除時にシリーズも消す）だけで、
+// それ以外のサーバーは "external" にまとめる
+fn record_http_event(event: &plain_http::HttpEvent, target_ips: &scope::TargetSet, prometheus_metrics: &PrometheusMetrics) {
+    let server_label = |server: &IpAddr| {
+        if target_ips.contains(server) { server.to_string() } else { plain_http::EXTERNAL_SERVER.to_string() }
+    };
+    match event {
+        plain_http::HttpEvent::Request { server, request } => {
+            prometheus_metrics
+                .http_requests_total
+                .with_label_values(&[&server_label(server), &request.host, request.method, &request.path_prefix])
+                .inc();
+        }
+        plain_http::HttpEvent::Response { server, request, status, latency } => {
+            let server = server_label(server);
+            let host = request.as_ref().map(|r| r.host.as_str()).unwrap_or("");
+            prometheus_metrics
+                .http_responses_total
+                .with_label_values(&[&server, host, plain_http::status_class(*status)])
+                .inc();
+            if let Some(latency) = latency {
+                prometheus_metrics
+                    .http_response_seconds
+                    .with_label_values(&[&server, host])
+                    .observe(latency.as_secs_f64());
+            }
+        }
+    }
+}
+
+// DNSの統計はクライアントごとに記録する。ホストごとの統計は監視対象だけに持つので、
+// インターフェースのプレフィックス外のIPv6クライアント（ULAなど）は target_networks に追加した場合だけ記録する
+fn is_dns_client_monitored(target_ips: &scope::TargetSet, client: &IpAddr) -> bool {
+    target_ips.contains(client)
+}
+
+fn record_dns_query(stats: &mut HashMap<IpAddr, IpStats>, client: IpAddr, resolver: IpAddr) {
+    let entry = stats.entry(client).or_insert_with(IpStats::new);
+    entry.dns_queries += 1;
+    *entry.dns_resolver_queries.entry(resolver).or_insert(0) += 1;
+}
+
+fn record_dns_transaction(
+    stats: &mut HashMap<IpAddr, IpStats>,
+    target_ips: &scope::TargetSet,
+    transaction: &dns::DnsTransaction,
+    prometheus_metrics: &PrometheusMetrics,
+    dns_state: &dns::DnsState,
+) {
+    if let Some(latency) = transaction.latency {
+        prometheus_metrics
+            .dns_response_seconds
+            .with_label_values(&[&dns_state.resolvers.lock().unwrap().label(transaction.resolver)])
+            .observe(latency.as_secs_f64());
+    }
+    dns::record_transaction(dns_state, transaction);
+
+    if is_dns_client_monitored(target_ips, &transaction.client) {
+        let entry = stats.entry(transaction.client).or_insert_with(IpStats::new);
+        match transaction.rcode {
+            Some(_) => *entry.dns_responses.entry(dns::rcode_label(transaction.rcode)).or_insert(0) += 1,
+            None => entry.dns_timeouts += 1,
+        }
+    }
+}
+
+fn record_dns_event(
+    stats: &mut HashMap<IpAddr, IpStats>,
+    target_ips: &scope::TargetSet,
+    event: &dns::DnsEvent,
+    prometheus_metrics: &PrometheusMetrics,
+    dns_state: &dns::DnsState,
+) {
+    match event {
+        dns::DnsEvent::Query { client, resolver } => {
+            dns_state.resolvers.lock().unwrap().count_query(*resolver);
+            if is_dns_client_monitored(target_ips, client) {
+                record_dns_query(stats, *client, *resolver);
+            }
+        }
+        dns::DnsEvent::Response(transaction) => {
+            record_dns_transaction(stats, target_ips, transaction, prometheus_metrics, dns_state);
+        }
+    }
 }
 
 fn get_interface_info(interface_name: &str) -> Option<(Ipv4Addr, u8)> {
@@ -278,129 +897,451 @@ fn get_interface_info(interface_name: &str) -> Option<(Ipv4Addr, u8)> {
     None
 }
 
-fn ipv4_list(ip: Ipv4Addr, prefix: u8) -> HashSet<IpAddr> {
-    let ip_u32 = u32::from(ip);
-    let mask = !(0xFFFFFFFFu32 >> prefix);
-    let network_addr_u32 = ip_u32 & mask;
-    let broadcast_addr_u32 = network_addr_u32 | !mask;
+// インターフェースのIPv6プレフィックス（リンクローカルを含む）
+fn get_interface_ipv6_networks(interface_name: &str) -> Vec<Ipv6Network> {
+    datalink::interfaces()
+        .into_iter()
+        .filter(|interface| interface.name == interface_name)
+        .flat_map(|interface| interface.ips)
+        .filter_map(|network| match network {
+            IpNetwork::V6(ipv6_network) => Some(ipv6_network),
+            IpNetwork::V4(_) => None,
+        })
+        .collect()
+}
+
+// インターフェースのアドレスを確認する間隔
+const INTERFACE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
-    let mut ip_address_set = HashSet::new();
+/// インターフェースのアドレスの変化をログに記録する（監視対象は SIGHUP で設定を再読み込みしたときに更新する）
+struct InterfaceWatch {
+    name: String,
+    ipv4: Option<(Ipv4Addr, u8)>,
+    ipv6: Vec<Ipv6Network>,
+    last_check: Instant,
+}
+
+impl InterfaceWatch {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ipv4: get_interface_info(name),
+            ipv6: get_interface_ipv6_networks(name),
+            last_check: Instant::now(),
+        }
+    }
 
-    // ネットワークアドレスとブロードキャストアドレスを除く
-    for ip_int in (network_addr_u32 + 1)..broadcast_addr_u32 {
-        ip_address_set.insert(IpAddr::V4(Ipv4Addr::from(ip_int)));
+    fn check(&mut self) {
+        if self.last_check.elapsed() < INTERFACE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_check = Instant::now();
+        let ipv4 = get_interface_info(&self.name);
+        if ipv4 != self.ipv4 {
+            match ipv4 {
+                Some((ip, prefix)) => warn!(
+                    interface = %self.name,
+                    address = %ip,
+                    prefix,
+                    "Interface IPv4 address changed (reload or restart to monitor the new subnet)"
+                ),
+                None => warn!(interface = %self.name, "Interface lost its IPv4 address"),
+            }
+            self.ipv4 = ipv4;
+        }
+        let ipv6 = get_interface_ipv6_networks(&self.name);
+        if ipv6 != self.ipv6 {
+            let prefixes: Vec<String> = ipv6.iter().map(ToString::to_string).collect();
+            warn!(interface = %self.name, prefixes = %prefixes.join(", "), "Interface IPv6 prefixes changed");
+            self.ipv6 = ipv6;
+        }
     }
+}
 
-    ip_address_set
+// サブネットのブロードキャストアドレス（監視対象には含まれない）
+fn ipv4_broadcast(ip: Ipv4Addr, prefix: u8) -> Ipv4Addr {
+    Ipv4Network::new(ip, prefix).map_or(Ipv4Addr::BROADCAST, |network| network.broadcast())
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <interface_name>", args[0]);
+// インターフェースのアドレスを含むサブネット（ネットワークアドレスで表す）
+fn ipv4_subnet(ip: Ipv4Addr, prefix: u8) -> Option<Ipv4Network> {
+    let network = Ipv4Network::new(ip, prefix).ok()?;
+    Ipv4Network::new(network.network(), prefix).ok()
+}
+
+/// 監視対象のネットワーク。target_networks があればそれを、なければインターフェースのサブネットとIPv6プレフィックスを使う
+fn resolve_targets(config: &config::Config, interfaces: &[String]) -> Result<(Vec<Ipv4Network>, Vec<Ipv6Network>), String> {
+    let mut ipv4 = Vec::new();
+    let mut ipv6 = Vec::new();
+    if !config.target_networks.is_empty() {
+        for network in config.parsed_target_networks()? {
+            match network {
+                IpNetwork::V4(network) => ipv4.push(network),
+                IpNetwork::V6(network) => ipv6.push(network),
+            }
+        }
+        return Ok((ipv4, ipv6));
+    }
+    for name in interfaces {
+        let (ip, prefix) = get_interface_info(name).ok_or_else(|| {
+            format!("Interface {} not found or has no IPv4 address (set target_networks to monitor it anyway)", name)
+        })?;
+        ipv4.extend(ipv4_subnet(ip, prefix));
+        ipv6.extend(get_interface_ipv6_networks(name));
+    }
+    Ok((ipv4, ipv6))
+}
+
+fn open_capture(interface_name: &str, capture: &config::CaptureConfig) -> Result<Capture<pcap::Active>, String> {
+    let devices = Device::list().map_err(|e| format!("Failed to list capture devices: {}", e))?;
+    let device = devices
+        .into_iter()
+        .find(|d| d.name == interface_name)
+        .ok_or_else(|| format!("Capture device {} not found", interface_name))?;
+    Capture::from_device(device)
+        .and_then(|cap| {
+            cap.promisc(capture.promisc)
+                .snaplen(capture.snaplen)
+                .timeout(capture.timeout_ms) // タイムアウトを短くして応答性を向上
+                .open()
+        })
+        .map_err(|e| format!("Failed to start capture on {}: {}", interface_name, e))
+}
+
+// check-config: 設定を検査し、適用される設定を TOML で出力する
+fn check_config(args: &cli::RunArgs) -> ! {
+    match args.load_config().and_then(|config| toml::to_string_pretty(&config).map_err(|e| e.to_string())) {
+        Ok(text) => {
+            print!("{}", text);
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// interfaces: キャプチャできるインターフェースとアドレスの一覧
+fn list_interfaces() -> ! {
+    let devices = Device::list().unwrap_or_else(|e| {
+        eprintln!("Failed to list capture devices: {}", e);
         process::exit(1);
+    });
+    let default = Device::lookup().ok().flatten().map(|device| device.name);
+    for device in devices {
+        let marker = if default.as_deref() == Some(device.name.as_str()) { " (default)" } else { "" };
+        match &device.desc {
+            Some(desc) => println!("{}{}  {}", device.name, marker, desc),
+            None => println!("{}{}", device.name, marker),
+        }
+        for address in &device.addresses {
+            match address.netmask {
+                Some(netmask) => println!("    {} netmask {}", address.addr, netmask),
+                None => println!("    {}", address.addr),
+            }
+        }
     }
+    process::exit(0);
+}
 
-    let interface_name = &args[1];
+// 終了時にHTTPサーバーの処理中のリクエストを待つ時間
+const SERVER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    match get_interface_info(interface_name) {
-        Some((ip, prefix)) => {
-            println!("Interface: {}", interface_name);
-            println!("IP Address: {}", ip);
-            println!("Subnet Mask: /{}", prefix);
+fn main() {
+    let args = match cli::Cli::parse().command() {
+        cli::Command::Run(args) => args,
+        cli::Command::CheckConfig(args) => check_config(&args),
+        cli::Command::Interfaces => list_interfaces(),
+    };
+    // ログの設定も設定ファイルにあるため、設定の誤りは標準エラーにそのまま出力する
+    let mut config = args.load_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    logging::init(&config.log);
+    let output_mode = OutputMode::select(&config);
 
-            let ip_set = ipv4_list(ip, prefix);
-            println!("Available IP addresses in subnet: {} addresses", ip_set.len());
-            
-            // 最初の10個のIPアドレスを表示
-            let mut count = 0;
-            for ip_addr in &ip_set {
-                if count < 10 {
-                    println!("  {}", ip_addr);
-                    count += 1;
-                } else {
-                    println!("  ... and {} more", ip_set.len() - 10);
-                    break;
-                }
+    // インターフェースの指定がなければ既定のインターフェース
+    if config.interfaces.is_empty() {
+        match Device::lookup() {
+            Ok(Some(device)) => {
+                info!(interface = %device.name, "No interface given, using the default capture device");
+                config.interfaces.push(device.name);
             }
-            
-            // Prometheusメトリクスを初期化
-            let prometheus_metrics = Arc::new(PrometheusMetrics::new());
-            
-            // Prometheus HTTPサーバーを起動
-            let metrics_clone = prometheus_metrics.clone();
-            let rt = Runtime::new().unwrap();
-            rt.spawn(async move {
-                start_prometheus_server(metrics_clone).await;
+            Ok(None) => {
+                error!("No interface given and no default capture device found");
+                process::exit(1);
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to look up the default capture device");
+                process::exit(1);
+            }
+        }
+    }
+
+    let (ipv4_targets, ipv6_targets) = resolve_targets(&config, &config.interfaces).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+    let target_ips = scope::TargetSet::new(ipv4_targets, ipv6_targets);
+    for network in target_ips.ipv4_networks() {
+        info!(network = %network, "Monitoring IPv4 subnet");
+    }
+    for network in target_ips.ipv6_networks() {
+        info!(prefix = %network, "Monitoring IPv6 prefix");
+    }
+
+    // Prometheusメトリクスを初期化
+    let prometheus_metrics = Arc::new(PrometheusMetrics::new(config.exporters.packet_size_buckets.clone()));
+
+    // DNSクエリログとホスト名の対応表（HTTPサーバーとキャプチャで共有）
+    let dns_state = dns::new_state();
+
+    // IP別・全体の統計（キャプチャ、スクレイプ、REST APIで共有）
+    let shared_stats = SharedStats {
+        hosts: Arc::new(Mutex::new(HashMap::new())),
+        totals: Arc::new(Mutex::new(TrafficTotals::new())),
+        target_ips,
+        updates: stream::channel(),
+        state: config.state.path.clone().map(|path| Arc::new(persist::StateFile::new(path))),
+        history: Arc::new(Mutex::new(load_history(&config.history))),
+    };
+    // 前回の実行の累積値を引き継ぐ（読めない場合は0から数える）
+    if let Some(state) = &shared_stats.state {
+        let mut hosts = shared_stats.hosts.lock().unwrap();
+        let mut totals = shared_stats.totals.lock().unwrap();
+        match state.restore(&mut hosts, &mut totals.monitored, config.thresholds.idle_timeout()) {
+            Ok(restored) => info!(path = %state.path().display(), hosts = restored, "Restored cumulative counters"),
+            Err(e) => warn!("{}, starting from zero", e),
+        }
+    }
+    // 累積値・1秒間の値はスクレイプ時に統計のスナップショットから出力する
+    prometheus_metrics
+        .registry
+        .register(Box::new(collector::SnapshotCollector::new(shared_stats.clone())))
+        .unwrap();
+
+    // Prometheus HTTPサーバーを起動（待ち受けに失敗したらキャプチャを始める前に終了）
+    let listeners = listen::bind_all(&config.exporters.listen).unwrap_or_else(|e| {
+        error!("{}", e);
+        process::exit(1);
+    });
+
+    // インターフェースごとにキャプチャを開く（1つでも失敗したら終了）
+    let geoip = geoip::GeoIp::open(config.geoip.asn_db.as_deref(), config.geoip.country_db.as_deref());
+    if !geoip.is_enabled() {
+        info!(
+            "ASN/country aggregation disabled (set geoip.asn_db / geoip.country_db or {} / {} to MaxMind DB files)",
+            geoip::ASN_DB_ENV,
+            geoip::COUNTRY_DB_ENV
+        );
+    }
+    let http_ports: HashSet<u16> = config.capture.http_ports.iter().copied().collect();
+    let mut sorted_http_ports: Vec<_> = http_ports.iter().collect();
+    sorted_http_ports.sort();
+    info!(ports = ?sorted_http_ports, "Plain HTTP ports");
+    let sources: Vec<CaptureSource> = config
+        .interfaces
+        .iter()
+        .map(|name| {
+            let cap = open_capture(name, &config.capture).unwrap_or_else(|e| {
+                error!("{}", e);
+                process::exit(1);
             });
-            
-            // パケットキャプチャ部分に進む
-            start_packet_capture(interface_name, ip_set, prometheus_metrics);
+            info!(interface = %name, "Capturing on interface");
+            CaptureSource {
+                name: name.clone(),
+                cap,
+                subnet_broadcast: get_interface_info(name).map_or(Ipv4Addr::BROADCAST, |(ip, prefix)| ipv4_broadcast(ip, prefix)),
+                analyzers: Analyzers::new(http_ports.clone()),
+                geoip: geoip.clone(),
+            }
+        })
+        .collect();
+
+    // 実行中に SIGHUP で差し替える設定
+    let settings: config::Settings = Arc::new(RwLock::new(config));
+
+    // SIGINT / SIGTERM で終了処理を始める（2回目で強制終了）
+    let shutdown = shutdown::Shutdown::new();
+    let rt = Runtime::new().unwrap();
+    rt.spawn(shutdown::handle_signals(shutdown.clone()));
+
+    let metrics_clone = prometheus_metrics.clone();
+    let dns_state_clone = dns_state.clone();
+    let stats_clone = shared_stats.clone();
+    let settings_clone = settings.clone();
+    let shutdown_clone = shutdown.clone();
+    let server = rt.spawn(async move {
+        start_prometheus_server(listeners, metrics_clone, dns_state_clone, stats_clone, settings_clone, shutdown_clone).await;
+    });
+    rt.spawn(reload_on_sighup(args, settings.clone(), shared_stats.target_ips.clone()));
+
+    // パケットキャプチャ部分に進む（終了処理が始まり、最後の統計を出力するまで戻らない）
+    let ctx = CaptureContext {
+        stats: shared_stats,
+        prometheus_metrics,
+        dns_state,
+        shutdown,
+    };
+    start_packet_capture(ctx, sources, output_mode, settings);
+
+    // 処理中のリクエストと配信の終了を待ってHTTPサーバーを止める
+    if rt.block_on(tokio::time::timeout(SERVER_SHUTDOWN_TIMEOUT, server)).is_err() {
+        warn!(timeout_secs = SERVER_SHUTDOWN_TIMEOUT.as_secs(), "HTTP server did not stop in time, closing remaining connections");
+    }
+    rt.shutdown_timeout(Duration::from_secs(1));
+    info!("Stopped");
+}
+
+// SIGHUP で設定を読み直す。読み込みに失敗した場合は今の設定のまま続ける
+async fn reload_on_sighup(args: cli::RunArgs, settings: config::Settings, target_ips: scope::TargetSet) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(error = %e, "Failed to install the SIGHUP handler, configuration reload is disabled");
+            return;
         }
-        None => {
-            eprintln!(
-                "Interface '{}' not found or has no IPv4 address",
-                interface_name
-            );
-            process::exit(1);
+    };
+    while hangup.recv().await.is_some() {
+        info!("Reloading configuration");
+        if let Err(e) = reload_config(&args, &settings, &target_ips) {
+            error!("Configuration reload failed, keeping the current settings: {}", e);
         }
     }
 }
 
-fn start_packet_capture(interface_name: &str, target_ips: HashSet<IpAddr>, prometheus_metrics: Arc<PrometheusMetrics>) {
-    // インターフェースを見つける
-    let device = Device::list()
-        .unwrap()
-        .into_iter()
-        .find(|d| d.name == *interface_name)
-        .unwrap_or_else(|| {
-            eprintln!("Interface '{}' not found", interface_name);
-            process::exit(1);
-        });
+/// 監視対象・しきい値・表示・ラベルを差し替える（統計はそのまま残す）。
+/// 再起動が必要な設定は変更を警告し、実行中の値を残す
+fn reload_config(args: &cli::RunArgs, settings: &config::Settings, target_ips: &scope::TargetSet) -> Result<(), String> {
+    let mut config = args.load_config()?;
+    let running = settings.read().unwrap().clone();
+    if config.interfaces.is_empty() {
+        config.interfaces = running.interfaces.clone();
+    }
+    let (ipv4, ipv6) = resolve_targets(&config, &running.interfaces)?;
+    for setting in config.restart_required_changes(&running) {
+        warn!(setting, "Changed setting takes effect after a restart");
+    }
+    config.keep_restart_settings(&running);
 
-    println!("Capturing on interface: {}", device.name);
-    println!("Monitoring {} IP addresses in the subnet", target_ips.len());
+    target_ips.replace(ipv4, ipv6);
+    info!(
+        ipv4_addresses = target_ips.ipv4_len(),
+        ipv6_prefixes = target_ips.ipv6_networks().len(),
+        idle_timeout_secs = config.thresholds.idle_timeout_secs,
+        max_series = config.thresholds.max_series,
+        top_hosts = config.display.top_hosts,
+        labels = config.labels.len(),
+        "Configuration reloaded"
+    );
+    *settings.write().unwrap() = config;
+    Ok(())
+}
 
-    // キャプチャを開始
-    let mut cap = Capture::from_device(device)
-        .unwrap()
-        .promisc(true)
-        .snaplen(65535)
-        .timeout(100) // タイムアウトを短くして応答性を向上
-        .open()
-        .unwrap();
+/// キャプチャスレッドで共有する状態
+#[derive(Clone)]
+struct CaptureContext {
+    stats: SharedStats,
+    prometheus_metrics: Arc<PrometheusMetrics>,
+    dns_state: Arc<dns::DnsState>,
+    shutdown: shutdown::Shutdown,
+}
 
-    let ip_stats = Arc::new(Mutex::new(HashMap::new()));
-    let running = Arc::new(AtomicBool::new(true));
+/// 1つのインターフェースのキャプチャとその解析状態
+struct CaptureSource {
+    name: String,
+    cap: Capture<pcap::Active>,
+    subnet_broadcast: Ipv4Addr,
+    analyzers: Analyzers,
+    geoip: geoip::GeoIp,
+}
 
-    // Ctrl+C ハンドラ
-    ctrlc::set_handler(move || {
-        let _count = SIGINT_COUNT.fetch_add(1, Ordering::SeqCst);
-        println!("\nForce quitting...");
-        process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+fn start_packet_capture(ctx: CaptureContext, sources: Vec<CaptureSource>, output_mode: OutputMode, settings: config::Settings) {
+    let SharedStats { hosts: ip_stats, totals, target_ips, updates, state, history } = ctx.stats.clone();
 
     // 統計表示用スレッド
-    let stats_running = running.clone();
+    let stats_shutdown = ctx.shutdown.clone();
     let ip_stats_clone = Arc::clone(&ip_stats);
     let target_ips_clone = target_ips.clone();
-    let prometheus_metrics_clone = prometheus_metrics.clone();
+    let prometheus_metrics_clone = ctx.prometheus_metrics.clone();
+    let dns_state_clone = ctx.dns_state.clone();
+    let totals_clone = Arc::clone(&totals);
+    let settings_clone = settings.clone();
+    {
+        let thresholds = &settings.read().unwrap().thresholds;
+        info!(
+            idle_timeout_secs = thresholds.idle_timeout_secs,
+            max_series = thresholds.max_series,
+            "Idle hosts are removed after the idle timeout or when the series limit is exceeded"
+        );
+    }
+    let mut last_summary = Instant::now();
+    let mut last_print: Option<Instant> = None;
+    let mut last_checkpoint = Instant::now();
+    let mut last_history_checkpoint = Instant::now();
+    let state_clone = state.clone();
+    let history_clone = Arc::clone(&history);
+    let mut interface_watches: Vec<InterfaceWatch> = sources.iter().map(|source| InterfaceWatch::new(&source.name)).collect();
     let stats_thread = thread::spawn(move || {
-        while stats_running.load(Ordering::SeqCst) {
+        while stats_shutdown.is_running() {
             thread::sleep(Duration::from_millis(100)); // より短い間隔でチェック
-            if !stats_running.load(Ordering::SeqCst) {
+            if !stats_shutdown.is_running() {
                 break;
             }
+            // しきい値・表示・保存間隔・履歴の設定は SIGHUP で変わる
+            let (thresholds, display, checkpoint_interval, history_config) = {
+                let settings = settings_clone.read().unwrap();
+                (
+                    settings.thresholds.clone(),
+                    settings.display.clone(),
+                    settings.state.checkpoint_interval(),
+                    settings.history.clone(),
+                )
+            };
+            calculate_bps(&mut ip_stats_clone.lock().unwrap(), &target_ips_clone, &mut totals_clone.lock().unwrap());
+            if history_config.enabled {
+                history::record(&history_clone, &ip_stats_clone, &target_ips_clone, history_config.max_hosts);
+            }
+            evict_hosts(&ip_stats_clone, &totals_clone, &target_ips_clone, state_clone.as_deref(), &prometheus_metrics_clone, &thresholds);
             {
-                let mut stats = ip_stats_clone.lock().unwrap();
-                calculate_bps(&mut stats);
-                prometheus_metrics_clone.update_metrics(&stats, &target_ips_clone);
-                print_stats(&stats, &target_ips_clone);
+                let stats = ip_stats_clone.lock().unwrap();
+                let mut totals = totals_clone.lock().unwrap();
+                totals.scope.roll(Instant::now());
+                totals.storm.roll(Instant::now());
+                let hostnames = dns_state_clone.hostnames.lock().unwrap();
+                stream::publish(&updates, &stats, &target_ips_clone, &hostnames);
+                match output_mode {
+                    OutputMode::Table if last_print.is_none_or(|t| t.elapsed() >= display.refresh_interval()) => {
+                        print_stats(&stats, &target_ips_clone, &totals, &hostnames, display.top_hosts);
+                        last_print = Some(Instant::now());
+                    }
+                    OutputMode::Daemon if last_summary.elapsed() >= thresholds.summary_interval() => {
+                        log_summary(&stats, &target_ips_clone, &totals);
+                        last_summary = Instant::now();
+                    }
+                    _ => {}
+                }
+            }
+            for watch in &mut interface_watches {
+                watch.check();
+            }
+            if let Some(state) = state_clone.as_ref().filter(|_| last_checkpoint.elapsed() >= checkpoint_interval) {
+                if let Err(e) = state.save(&ip_stats_clone, &totals_clone) {
+                    warn!("{}", e);
+                }
+                last_checkpoint = Instant::now();
+            }
+            if let Some(path) = history_config.path.as_ref().filter(|_| last_history_checkpoint.elapsed() >= history_config.checkpoint_interval()) {
+                if let Err(e) = history::save(&history_clone, path, history_config.max_hosts) {
+                    warn!("{}", e);
+                }
+                last_history_checkpoint = Instant::now();
             }
             // 1秒待つが、100msごとに中断チェック
             for _ in 0..10 {
-                if !stats_running.load(Ordering::SeqCst) {
+                if !stats_shutdown.is_running() {
                     break;
                 }
                 thread::sleep(Duration::from_millis(100));
@@ -408,28 +1349,147 @@ fn start_packet_capture(interface_name: &str, target_ips: HashSet<IpAddr>, prome
         }
     });
 
-    println!("Press Ctrl+C to stop...");
+    if output_mode == OutputMode::Table {
+        println!("Press Ctrl+C to stop...");
+    }
+
+    // 全画面表示は起動時の出力の後に始める（q / Ctrl+C で端末を元に戻して終了処理を始める）
+    let tui_thread = (output_mode == OutputMode::Tui).then(|| {
+        let tui_stats = ctx.stats.clone();
+        let tui_dns_state = ctx.dns_state.clone();
+        let tui_settings = settings.clone();
+        let tui_shutdown = ctx.shutdown.clone();
+        thread::spawn(move || {
+            if let Err(e) = tui::run(tui_stats, tui_dns_state, tui_settings, tui_shutdown.clone()) {
+                error!(error = %e, "Terminal UI failed");
+            }
+            tui_shutdown.begin("terminal UI closed");
+        })
+    });
+
+    // インターフェースごとのキャプチャスレッド
+    let capture_threads: Vec<_> = sources
+        .into_iter()
+        .map(|source| {
+            let ctx = ctx.clone();
+            thread::spawn(move || capture_loop(ctx, source))
+        })
+        .collect();
+    for capture_thread in capture_threads {
+        let _ = capture_thread.join();
+    }
+
+    // すべてのキャプチャが終了したら（失敗した場合も）統計表示スレッドと全画面表示も止めて終了を待つ
+    ctx.shutdown.begin("capture stopped");
+    let _ = stats_thread.join();
+    if let Some(tui_thread) = tui_thread {
+        let _ = tui_thread.join();
+    }
+
+    // 最後の1秒間の値を求めて、配信の購読者・画面・ログに出力する
+    let history_config = settings.read().unwrap().history.clone();
+    calculate_bps(&mut ip_stats.lock().unwrap(), &target_ips, &mut totals.lock().unwrap());
+    if history_config.enabled {
+        history::record(&history, &ip_stats, &target_ips, history_config.max_hosts);
+    }
+    {
+        let top_hosts = settings.read().unwrap().display.top_hosts;
+        let final_stats = ip_stats.lock().unwrap();
+        let mut final_totals = totals.lock().unwrap();
+        final_totals.scope.roll(Instant::now());
+        final_totals.storm.roll(Instant::now());
+        let hostnames = ctx.dns_state.hostnames.lock().unwrap();
+        stream::publish(&ctx.stats.updates, &final_stats, &target_ips, &hostnames);
+        if output_mode == OutputMode::Daemon {
+            log_summary(&final_stats, &target_ips, &final_totals);
+        } else {
+            println!("\nFinal statistics:");
+            print_stats(&final_stats, &target_ips, &final_totals, &hostnames, top_hosts);
+        }
+    }
+    let _ = io::stdout().flush();
+
+    // 終了時の累積値を保存する（次回の起動で引き継ぐ）
+    if let Some(state) = &state {
+        match state.save(&ip_stats, &totals) {
+            Ok(hosts) => info!(path = %state.path().display(), hosts, "Saved cumulative counters"),
+            Err(e) => error!("{}", e),
+        }
+    }
+    if let Some(path) = &history_config.path {
+        match history::save(&history, path, history_config.max_hosts) {
+            Ok(hosts) => info!(path = %path.display(), hosts, "Saved traffic history"),
+            Err(e) => error!("{}", e),
+        }
+    }
+}
+
+/// 履歴ファイルがあれば読み込む（読めない場合は空の履歴から記録する）
+fn load_history(config: &config::HistoryConfig) -> history::History {
+    let Some(path) = &config.path else {
+        return history::History::new();
+    };
+    match history::load(path) {
+        Ok(history) => {
+            info!(path = %path.display(), hosts = history.host_count(), "Loaded traffic history");
+            history
+        }
+        Err(e) => {
+            warn!("{}, starting a new history", e);
+            history::History::new()
+        }
+    }
+}
+
+/// 1つのインターフェースのパケットを、停止するかキャプチャが失敗するまで処理する
+fn capture_loop(ctx: CaptureContext, source: CaptureSource) {
+    let CaptureContext { stats, prometheus_metrics, dns_state, shutdown } = ctx;
+    let SharedStats { hosts: ip_stats, totals, target_ips, state, .. } = stats;
+    let CaptureSource { name: interface_name, mut cap, subnet_broadcast, mut analyzers, mut geoip } = source;
 
     let mut consecutive_timeouts = 0;
     const MAX_CONSECUTIVE_TIMEOUTS: u32 = 50; // 5秒間タイムアウトが続いたら強制チェック
 
-    while running.load(Ordering::SeqCst) {
+    while shutdown.is_running() {
+        // 応答のないまま期限切れになったDNSクエリを記録
+        let expired_queries = analyzers.dns.expire();
+        if !expired_queries.is_empty() {
+            let mut stats = ip_stats.lock().unwrap();
+            for transaction in &expired_queries {
+                record_dns_transaction(&mut stats, &target_ips, transaction, &prometheus_metrics, &dns_state);
+            }
+        }
+
         match cap.next_packet() {
             Ok(packet) => {
                 consecutive_timeouts = 0; // パケットを受信したらリセット
                 if let Some(ethernet) = EthernetPacket::new(packet.data) {
+                    // 宛先MACの種別はARPなどIP以外も含めたすべてのフレームで判定し、送信元MAC別に数える
+                    let l2_cast = cast::l2_cast(ethernet.get_destination());
+                    if l2_cast != cast::CastType::Unicast {
+                        totals.lock().unwrap().storm.add(l2_cast, ethernet.get_source(), ethernet.get_ethertype(), packet.header.len as u64);
+                    }
                     match ethernet.get_ethertype() {
                         EtherTypes::Ipv4 => {
                             if let Some(ipv4) = Ipv4Packet::new(ethernet.payload()) {
                                 let src_ip = IpAddr::V4(ipv4.get_source());
                                 let dst_ip = IpAddr::V4(ipv4.get_destination());
 
-                                // ソースまたはデスティネーションがターゲットIPセットに含まれている場合のみ処理
-                                if target_ips.contains(&src_ip) || target_ips.contains(&dst_ip) {
+                                let l3_cast = cast::l3_cast(dst_ip, subnet_broadcast);
+
+                                // ソースまたはデスティネーションがターゲットIPセットに含まれている場合と、
+                                // LAN内へのブロードキャスト・マルチキャスト（外部からのIPTVなど）を処理
+                                let is_group_addressed = l2_cast != cast::CastType::Unicast || l3_cast != cast::CastType::Unicast;
+                                if target_ips.contains(&src_ip) || target_ips.contains(&dst_ip) || is_group_addressed {
+                                    let next_header = ipv4.get_next_level_protocol();
+                                    let l4 = inspect_l4(next_header, ipv4.payload(), src_ip, dst_ip, &mut analyzers);
                                     let mut stats = ip_stats.lock().unwrap();
+                                    if let Some(state) = &state {
+                                        state.revive(&mut stats, [src_ip, dst_ip].into_iter().filter(|ip| target_ips.contains(ip)));
+                                    }
                                     
                                     // TCPパケットの場合、追加情報を解析
-                                    if ipv4.get_next_level_protocol() == pnet::packet::ip::IpNextHeaderProtocols::Tcp {
+                                    if next_header == IpNextHeaderProtocols::Tcp {
                                         if let Some(tcp) = TcpPacket::new(ipv4.payload()) {
                                             // 送信トラフィック（ソースIPがターゲットセット内）
                                             if target_ips.contains(&src_ip) {
@@ -451,18 +1511,110 @@ fn start_packet_capture(interface_name: &str, target_ips: HashSet<IpAddr>, prome
                                             update_rx_stats(&mut stats, dst_ip, packet.header.len as u64);
                                         }
                                     }
+
+                                    // LAN内・インターネット向けの区別
+                                    let scope = classify_scope(&target_ips, src_ip, dst_ip, ethernet.get_destination());
+                                    record_scope(
+                                        &mut stats,
+                                        &totals,
+                                        scope,
+                                        Some(src_ip).filter(|ip| target_ips.contains(ip)),
+                                        Some(dst_ip).filter(|ip| target_ips.contains(ip)),
+                                        packet.header.len as u64,
+                                    );
+                                    record_remote_peer(&mut stats, &mut geoip, scope, src_ip, dst_ip, |ip| target_ips.contains(ip), packet.header.len as u64);
+                                    record_external_talker(&totals, scope, src_ip, dst_ip, packet.header.len as u64);
+                                    record_cast(
+                                        &mut stats,
+                                        &totals,
+                                        Some(src_ip).filter(|ip| target_ips.contains(ip)),
+                                        dst_ip,
+                                        l2_cast,
+                                        l3_cast,
+                                        packet.header.len as u64,
+                                    );
+
+                                    // プロトコル別・ドメイン別の内訳とフレームサイズ分布
+                                    let traffic_class = (ipv4.get_dscp() << 2) | ipv4.get_ecn();
+                                    if target_ips.contains(&src_ip) {
+                                        record_l4_stats(&mut stats, src_ip, true, packet.header.len as u64, &l4);
+                                        record_flow(&mut stats, src_ip, true, dst_ip, packet.header.len as u64, &l4);
+                                        record_traffic_class(&mut stats, src_ip, true, packet.header.len as u64, traffic_class);
+                                        record_nat_signals(&mut stats, src_ip, dst_ip, ipv4.get_ttl(), Some(ipv4.get_identification()), &l4);
+                                        prometheus_metrics.observe_packet_size(&src_ip, true, packet.header.len as u64);
+                                    }
+                                    if target_ips.contains(&dst_ip) {
+                                        record_l4_stats(&mut stats, dst_ip, false, packet.header.len as u64, &l4);
+                                        record_flow(&mut stats, dst_ip, false, src_ip, packet.header.len as u64, &l4);
+                                        record_traffic_class(&mut stats, dst_ip, false, packet.header.len as u64, traffic_class);
+                                        prometheus_metrics.observe_packet_size(&dst_ip, false, packet.header.len as u64);
+                                    }
+                                    if let Some(event) = &l4.dns {
+                                        record_dns_event(&mut stats, &target_ips, event, &prometheus_metrics, &dns_state);
+                                    }
+                                    if let Some(event) = &l4.http {
+                                        record_http_event(event, &target_ips, &prometheus_metrics);
+                                    }
+                                    if let Some(info) = &l4.icmp {
+                                        record_icmp_rtt(info, src_ip, &target_ips, &prometheus_metrics);
+                                    }
                                 }
                             }
                         }
                         EtherTypes::Ipv6 => {
                             if let Some(ipv6) = Ipv6Packet::new(ethernet.payload()) {
                                 let src_ip = IpAddr::V6(ipv6.get_source());
-                                let _dst_ip = IpAddr::V6(ipv6.get_destination());
+                                let dst_ip = IpAddr::V6(ipv6.get_destination());
+                                let l3_cast = cast::l3_cast(dst_ip, subnet_broadcast);
 
-                                // IPv6の場合、ターゲットセットには含まれていないが、記録はする
-                                // 必要に応じてIPv6のフィルタリングも追加可能
-                                let mut stats = ip_stats.lock().unwrap();
-                                update_tx_stats(&mut stats, src_ip, packet.header.len as u64);
+                                // IPv6はインターフェースのプレフィックス内のアドレスを監視対象とする
+                                let src_monitored = target_ips.contains(&src_ip);
+                                let dst_monitored = target_ips.contains(&dst_ip);
+                                let is_group_addressed = l2_cast != cast::CastType::Unicast || l3_cast != cast::CastType::Unicast;
+                                if src_monitored || dst_monitored || is_group_addressed {
+                                    let l4 = inspect_l4(ipv6.get_next_header(), ipv6.payload(), src_ip, dst_ip, &mut analyzers);
+                                    let mut stats = ip_stats.lock().unwrap();
+                                    if let Some(state) = &state {
+                                        state.revive(&mut stats, [src_ip, dst_ip].into_iter().filter(|ip| target_ips.contains(ip)));
+                                    }
+                                    if src_monitored {
+                                        update_tx_stats(&mut stats, src_ip, packet.header.len as u64);
+                                        record_l4_stats(&mut stats, src_ip, true, packet.header.len as u64, &l4);
+                                        record_flow(&mut stats, src_ip, true, dst_ip, packet.header.len as u64, &l4);
+                                        record_traffic_class(&mut stats, src_ip, true, packet.header.len as u64, ipv6.get_traffic_class());
+                                        record_nat_signals(&mut stats, src_ip, dst_ip, ipv6.get_hop_limit(), None, &l4);
+                                        prometheus_metrics.observe_packet_size(&src_ip, true, packet.header.len as u64);
+                                    }
+                                    if dst_monitored {
+                                        update_rx_stats(&mut stats, dst_ip, packet.header.len as u64);
+                                        record_l4_stats(&mut stats, dst_ip, false, packet.header.len as u64, &l4);
+                                        record_flow(&mut stats, dst_ip, false, src_ip, packet.header.len as u64, &l4);
+                                        record_traffic_class(&mut stats, dst_ip, false, packet.header.len as u64, ipv6.get_traffic_class());
+                                        prometheus_metrics.observe_packet_size(&dst_ip, false, packet.header.len as u64);
+                                    }
+
+                                    let scope = classify_scope(&target_ips, src_ip, dst_ip, ethernet.get_destination());
+                                    record_scope(
+                                        &mut stats,
+                                        &totals,
+                                        scope,
+                                        Some(src_ip).filter(|_| src_monitored),
+                                        Some(dst_ip).filter(|_| dst_monitored),
+                                        packet.header.len as u64,
+                                    );
+                                    record_remote_peer(&mut stats, &mut geoip, scope, src_ip, dst_ip, |ip| target_ips.contains(ip), packet.header.len as u64);
+                                    record_external_talker(&totals, scope, src_ip, dst_ip, packet.header.len as u64);
+                                    record_cast(&mut stats, &totals, Some(src_ip).filter(|_| src_monitored), dst_ip, l2_cast, l3_cast, packet.header.len as u64);
+                                    if let Some(event) = &l4.dns {
+                                        record_dns_event(&mut stats, &target_ips, event, &prometheus_metrics, &dns_state);
+                                    }
+                                    if let Some(event) = &l4.http {
+                                        record_http_event(event, &target_ips, &prometheus_metrics);
+                                    }
+                                    if let Some(info) = &l4.icmp {
+                                        record_icmp_rtt(info, src_ip, &target_ips, &prometheus_metrics);
+                                    }
+                                }
                             }
                         }
                         _ => {}
@@ -472,125 +1624,42 @@ fn start_packet_capture(interface_name: &str, target_ips: HashSet<IpAddr>, prome
             Err(pcap::Error::TimeoutExpired) => {
                 consecutive_timeouts += 1;
                 // タイムアウト時にrunningフラグをチェック
-                if !running.load(Ordering::SeqCst) {
+                if !shutdown.is_running() {
                     break;
                 }
                 // 長時間タイムアウトが続く場合も強制的にrunningフラグをチェック
                 if consecutive_timeouts >= MAX_CONSECUTIVE_TIMEOUTS {
                     consecutive_timeouts = 0;
-                    if !running.load(Ordering::SeqCst) {
+                    if !shutdown.is_running() {
                         break;
                     }
                 }
                 continue;
             }
             Err(e) => {
-                eprintln!("Error reading packet: {}", e);
+                error!(interface = %interface_name, error = %e, "Capture failed");
                 break;
             }
         }
     }
-
-    // 統計表示スレッドの終了を待つ
-    let _ = stats_thread.join();
-
-    println!("\nFinal statistics:");
-    {
-        let mut final_stats = ip_stats.lock().unwrap();
-        calculate_bps(&mut final_stats);
-        prometheus_metrics.update_metrics(&final_stats, &target_ips);
-        print_stats(&final_stats, &target_ips);
-    }
 }
 
 fn update_tx_stats(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, bytes: u64) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
-    });
+    let entry = stats.entry(ip).or_insert_with(IpStats::new);
 
     entry.tx_packet_count += 1;
     entry.tx_byte_count += bytes;
 }
 
 fn update_rx_stats(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, bytes: u64) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
-    });
+    let entry = stats.entry(ip).or_insert_with(IpStats::new);
 
     entry.rx_packet_count += 1;
     entry.rx_byte_count += bytes;
 }
 
 fn update_tx_stats_with_tcp(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, bytes: u64, tcp: &TcpPacket) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
-    });
+    let entry = stats.entry(ip).or_insert_with(IpStats::new);
 
     entry.tx_packet_count += 1;
     entry.tx_byte_count += bytes;
@@ -624,31 +1693,7 @@ fn update_tx_stats_with_tcp(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, by
 }
 
 fn update_rx_stats_with_tcp(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, bytes: u64, tcp: &TcpPacket) {
-    let now = Instant::now();
-    let entry = stats.entry(ip).or_insert(IpStats {
-        tx_packet_count: 0,
-        rx_packet_count: 0,
-        tx_byte_count: 0,
-        rx_byte_count: 0,
-        tx_last_bytes: 0,
-        rx_last_bytes: 0,
-        last_time: now,
-        tx_current_bps: 0.0,
-        rx_current_bps: 0.0,
-        tx_bytes_per_sec: 0,
-        rx_bytes_per_sec: 0,
-        expected_seq: HashMap::new(),
-        retransmissions: 0,
-        duplicate_acks: 0,
-        last_retransmissions: 0,
-        last_duplicate_acks: 0,
-        retransmissions_per_sec: 0,
-        duplicate_acks_per_sec: 0,
-        last_window_size: HashMap::new(),
-        window_size_changes: 0,
-        last_window_size_changes: 0,
-        window_size_changes_per_sec: 0,
-    });
+    let entry = stats.entry(ip).or_insert_with(IpStats::new);
 
     entry.rx_packet_count += 1;
     entry.rx_byte_count += bytes;
@@ -672,10 +1717,10 @@ fn update_rx_stats_with_tcp(stats: &mut HashMap<IpAddr, IpStats>, ip: IpAddr, by
     entry.last_window_size.insert(dst_port, window_size);
 }
 
-fn calculate_bps(stats: &mut HashMap<IpAddr, IpStats>) {
+fn calculate_bps(stats: &mut HashMap<IpAddr, IpStats>, target_ips: &scope::TargetSet, totals: &mut TrafficTotals) {
     let now = Instant::now();
 
-    for (_, stat) in stats.iter_mut() {
+    for (ip, stat) in stats.iter_mut() {
         let time_diff = now.duration_since(stat.last_time).as_secs_f64();
         if time_diff >= 1.0 {
             let tx_bytes_diff = stat.tx_byte_count - stat.tx_last_bytes;
@@ -688,6 +1733,12 @@ fn calculate_bps(stats: &mut HashMap<IpAddr, IpStats>) {
             // 1秒間のバイト数を計算
             stat.tx_bytes_per_sec = tx_bytes_diff;
             stat.rx_bytes_per_sec = rx_bytes_diff;
+            stat.tx_packets_per_sec = stat.tx_packet_count - stat.tx_last_packets;
+            stat.rx_packets_per_sec = stat.rx_packet_count - stat.rx_last_packets;
+            if stat.tx_packets_per_sec > 0 || stat.rx_packets_per_sec > 0 {
+                stat.last_active = now;
+                stat.last_seen = SystemTime::now();
+            }
 
             // パケットロスの1秒間の値を計算
             stat.retransmissions_per_sec = stat.retransmissions - stat.last_retransmissions;
@@ -696,26 +1747,129 @@ fn calculate_bps(stats: &mut HashMap<IpAddr, IpStats>) {
             // ウィンドウサイズ変更の1秒間の値を計算
             stat.window_size_changes_per_sec = stat.window_size_changes - stat.last_window_size_changes;
 
+            // フローごとの1秒間の値と無通信のフローの削除
+            stat.flows.roll(now);
+
+            // ブロードキャスト・マルチキャストの1秒間の送信フレーム数
+            stat.broadcast_packets_per_sec = stat.broadcast_packets() - stat.last_broadcast_packets;
+            stat.multicast_packets_per_sec = stat.multicast_packets() - stat.last_multicast_packets;
+
+            if target_ips.contains(ip) {
+                totals.monitored.add_pending(stat);
+            }
             stat.tx_last_bytes = stat.tx_byte_count;
             stat.rx_last_bytes = stat.rx_byte_count;
+            stat.tx_last_packets = stat.tx_packet_count;
+            stat.rx_last_packets = stat.rx_packet_count;
             stat.last_retransmissions = stat.retransmissions;
             stat.last_duplicate_acks = stat.duplicate_acks;
             stat.last_window_size_changes = stat.window_size_changes;
+            stat.last_broadcast_packets = stat.broadcast_packets();
+            stat.last_multicast_packets = stat.multicast_packets();
             stat.last_time = now;
         }
     }
 }
 
-fn print_stats(stats: &HashMap<IpAddr, IpStats>, target_ips: &HashSet<IpAddr>) {
-    // Clear screen and move cursor to top
-    print!("\x1B[2J\x1B[1;1H");
+// 無通信のホストと、ホストのシリーズ数が上限を超えた分の古いホストを、メトリクスのシリーズとともに削除する
+// スナップショットの Collector が ip_stats をロックするので、registry.gather() はロックを外して呼ぶ
+fn evict_hosts(
+    ip_stats: &Mutex<HashMap<IpAddr, IpStats>>,
+    totals: &Mutex<TrafficTotals>,
+    target_ips: &scope::TargetSet,
+    state: Option<&persist::StateFile>,
+    prometheus_metrics: &PrometheusMetrics,
+    thresholds: &config::Thresholds,
+) {
+    let now = Instant::now();
+    // 削除するホストの、まだ合計に足していない増分を足し、累積値は状態ファイル用に残す
+    let remove = |stats: &mut HashMap<IpAddr, IpStats>, ip: &IpAddr, reason: &str| {
+        if let Some(stat) = stats.remove(ip) {
+            if target_ips.contains(ip) {
+                totals.lock().unwrap().monitored.add_pending(&stat);
+            }
+            if let Some(state) = state {
+                state.archive(*ip, &stat);
+            }
+            prometheus_metrics.remove_host(ip, reason);
+        }
+    };
+    {
+        let mut stats = ip_stats.lock().unwrap();
+        let idle: Vec<IpAddr> = stats
+            .iter()
+            .filter(|(_, stat)| now.duration_since(stat.last_active) >= thresholds.idle_timeout())
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in idle {
+            remove(&mut stats, &ip, "idle");
+        }
+    }
 
+    // 上限と比べるのはホストが持つシリーズだけ（外部IPの上位通信相手などはホストを削除しても減らない）
+    let (mut series, series_by_ip) = prometheus_metrics.series_by_ip();
+    let mut stats = ip_stats.lock().unwrap();
+    let owned = |ip: &IpAddr| series_by_ip.get(&ip.to_string()).copied().unwrap_or(0);
+    let mut host_series: usize = stats.keys().map(owned).sum();
+    if host_series > thresholds.max_series {
+        let mut oldest: Vec<(Instant, IpAddr)> = stats.iter().map(|(ip, stat)| (stat.last_active, *ip)).collect();
+        oldest.sort();
+        for (_, ip) in oldest {
+            if host_series <= thresholds.max_series {
+                break;
+            }
+            let removed = owned(&ip);
+            if removed == 0 {
+                continue;
+            }
+            remove(&mut stats, &ip, "series_limit");
+            host_series -= removed;
+            series = series.saturating_sub(removed);
+        }
+    }
+    prometheus_metrics.series.set(series as f64);
+}
+
+// デーモンモードで定期的に記録する要約（監視対象のIPの合計とネットワーク全体の値）
+fn log_summary(stats: &HashMap<IpAddr, IpStats>, target_ips: &scope::TargetSet, totals: &TrafficTotals) {
+    let monitored = || stats.iter().filter(|(ip, _)| target_ips.contains(ip)).map(|(_, stat)| stat);
+    let active_hosts = monitored().filter(|stat| stat.tx_packets_per_sec + stat.rx_packets_per_sec > 0).count();
+    let tx_bytes: u64 = monitored().map(|stat| stat.tx_byte_count).sum();
+    let rx_bytes: u64 = monitored().map(|stat| stat.rx_byte_count).sum();
+    let retransmissions: u64 = monitored().map(|stat| stat.retransmissions).sum();
+    let top_host = stats
+        .iter()
+        .max_by(|a, b| (a.1.tx_current_bps + a.1.rx_current_bps).total_cmp(&(b.1.tx_current_bps + b.1.rx_current_bps)))
+        .filter(|(_, stat)| stat.tx_current_bps + stat.rx_current_bps > 0.0);
+    info!(
+        hosts = stats.len(),
+        active_hosts,
+        tx_bytes,
+        rx_bytes,
+        retransmissions,
+        wan_up_bps = totals.scope.bps(scope::Scope::LanToWan),
+        wan_down_bps = totals.scope.bps(scope::Scope::WanToLan),
+        lan_bps = totals.scope.bps(scope::Scope::LanToLan),
+        external_ips_tracked = totals.external_talkers.tracked(),
+        top_host = %top_host.map(|(ip, _)| ip.to_string()).unwrap_or_default(),
+        top_host_bps = top_host.map_or(0.0, |(_, stat)| stat.tx_current_bps + stat.rx_current_bps),
+        "Traffic summary"
+    );
+}
+
+fn print_stats(
+    stats: &HashMap<IpAddr, IpStats>,
+    target_ips: &scope::TargetSet,
+    totals: &TrafficTotals,
+    hostnames: &dns::HostnameCache,
+    top_hosts: usize,
+) {
     println!("=== Subnet Network Traffic Monitor ===");
     println!(
-        "{:<30} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6}",
-        "IP Address", "TX/s", "RX/s", "↑ Up", "↓ Down", "PLoss/s", "DupAck/s", "WinChg/s"
+        "{:<30} {:>10} {:>10} {:>10} {:>10} {:>7} {:>8} {:>8} {:>8}  {:<20}",
+        "IP Address", "TX/s", "RX/s", "↑ Up", "↓ Down", "AvgPkt", "PLoss/s", "DupAck/s", "WinChg/s", "Host"
     );
-    println!("{:-<120}", "");
+    println!("{:-<127}", "");
 
     let mut sorted_stats: Vec<_> = stats.iter().collect();
     sorted_stats.sort_by(|a, b| {
@@ -729,35 +1883,53 @@ fn print_stats(stats: &HashMap<IpAddr, IpStats>, target_ips: &HashSet<IpAddr>) {
     if sorted_stats.is_empty() {
         println!("No traffic detected from monitored subnet IPs...");
     } else {
-        for (ip, stat) in sorted_stats.iter().take(20) {
-            let is_subnet_ip = target_ips.contains(ip);
-            let ip_prefix = if is_subnet_ip { "" } else { "*" };
+        for (ip, stat) in sorted_stats.iter().take(top_hosts) {
+            // NATの疑いがあれば併記
+            let hostname = stat.nat_signals.nat_suspected().then_some("[NAT?]");
             
             println!(
-                "{}{:<29} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} {:>6}",
-                ip_prefix,
+                "{:<30} {:>10} {:>10} {:>10} {:>10} {:>7} {:>8} {:>8} {:>8}  {}",
                 ip.to_string(),
                 format_bytes_short(stat.tx_bytes_per_sec),
                 format_bytes_short(stat.rx_bytes_per_sec),
                 format_bps_short(stat.tx_current_bps),
                 format_bps_short(stat.rx_current_bps),
+                stat.average_packet_size(),
                 stat.retransmissions_per_sec,
                 stat.duplicate_acks_per_sec,
-                stat.window_size_changes_per_sec
+                stat.window_size_changes_per_sec,
+                hostname.unwrap_or("")
             );
         }
     }
     
-    let subnet_ips_with_traffic = sorted_stats.iter()
-        .filter(|(ip, _)| target_ips.contains(ip))
-        .count();
-    let external_ips_with_traffic = sorted_stats.len() - subnet_ips_with_traffic;
+    // 外部IPは上位通信相手の推定値を、DNS応答から学習したホスト名とともに表示
+    let talkers = &totals.external_talkers;
+    println!();
+    println!("Top external talkers (estimated total bytes, ± max error):");
+    for talker in talkers.top(5) {
+        println!(
+            "  {:<39} {:>8} ±{:<8} {}",
+            talker.key.to_string(),
+            format_bytes_short(talker.count),
+            format_bytes_short(talker.error),
+            hostnames.lookup(&talker.key).unwrap_or("")
+        );
+    }
     
     println!();
-    println!("Legend: TX/s=TX Bytes per second, RX/s=RX Bytes per second, PLoss/s=Packet Loss per second");
-    println!("        DupAck/s=Duplicate ACKs per second, WinChg/s=Window Size Changes per second");
-    println!("Subnet IPs: {} | External IPs: {} (*) | Total subnet: {}", 
-             subnet_ips_with_traffic, external_ips_with_traffic, target_ips.len());
+    println!("Legend: TX/s=TX Bytes per second, RX/s=RX Bytes per second, AvgPkt=Average frame size (bytes)");
+    println!("        PLoss/s=Packet Loss per second, DupAck/s=Duplicate ACKs per second, WinChg/s=Window Size Changes per second");
+    println!("        [NAT?]=Multiple TTLs or TCP clocks seen (likely sharing its connection)");
+    println!("Subnet IPs: {} | External IPs tracked: {} | Total subnet: {}", 
+             sorted_stats.len(), talkers.tracked(), target_ips.ipv4_len());
+    println!("WAN ↑ {} ↓ {} | LAN internal {}",
+             format_bps_short(totals.scope.bps(scope::Scope::LanToWan)),
+             format_bps_short(totals.scope.bps(scope::Scope::WanToLan)),
+             format_bps_short(totals.scope.bps(scope::Scope::LanToLan)));
+    println!("Broadcast {} pkt/s | Multicast {} pkt/s",
+             totals.storm.packets_per_sec(cast::CastType::Broadcast),
+             totals.storm.packets_per_sec(cast::CastType::Multicast));
 }
 
 fn format_bps_short(bps: f64) -> String {
@@ -784,40 +1956,213 @@ fn format_bytes_short(bytes: u64) -> String {
     }
 }
 
-async fn start_prometheus_server(metrics: Arc<PrometheusMetrics>) {
-    let make_svc = make_service_fn(move |_conn| {
+// クエリ文字列を key=value のマップに分解する（パーセントデコードする）
+fn parse_query_params(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            Some((percent_decode(key), percent_decode(value)))
+        })
+        .collect()
+}
+
+// %2F（/）や %3A（:）などのエスケープを戻す（CIDRやIPv6アドレスの指定用）
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(500)
+            .body(Body::from(format!("JSON encode error: {}", e)))
+            .unwrap(),
+    }
+}
+
+// 最近のDNSクエリを新しい順に返す（client / resolver / limit で絞り込み）
+fn dns_queries_response(dns_state: &dns::DnsState, params: &HashMap<String, String>) -> Response<Body> {
+    let client: Option<IpAddr> = params.get("client").and_then(|v| v.parse().ok());
+    let resolver: Option<IpAddr> = params.get("resolver").and_then(|v| v.parse().ok());
+    let limit = params.get("limit").and_then(|v| v.parse().ok()).unwrap_or(100usize);
+
+    let entries: Vec<dns::DnsLogEntry> = dns_state
+        .log
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|e| client.is_none() || client == Some(e.client))
+        .filter(|e| resolver.is_none() || resolver == Some(e.resolver))
+        .take(limit)
+        .cloned()
+        .collect();
+    json_response(&entries)
+}
+
+#[derive(serde::Serialize)]
+struct HostnameEntry {
+    address: IpAddr,
+    name: String,
+    ttl_remaining: u64,
+}
+
+// DNS応答から学習したIP → ホスト名の対応表を返す
+fn hostnames_response(dns_state: &dns::DnsState) -> Response<Body> {
+    let mut entries: Vec<HostnameEntry> = dns_state
+        .hostnames
+        .lock()
+        .unwrap()
+        .entries()
+        .into_iter()
+        .map(|(address, name, ttl_remaining)| HostnameEntry { address, name, ttl_remaining })
+        .collect();
+    entries.sort_by_key(|e| e.address);
+    json_response(&entries)
+}
+
+async fn start_prometheus_server(
+    listeners: Vec<listen::Listener>,
+    metrics: Arc<PrometheusMetrics>,
+    dns_state: Arc<dns::DnsState>,
+    stats: SharedStats,
+    settings: config::Settings,
+    shutdown: shutdown::Shutdown,
+) {
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let name = listener.to_string();
+        let server = match listener {
+            listen::Listener::Tcp(listener) => match Server::from_tcp(listener) {
+                Ok(builder) => tokio::spawn(serve(builder, metrics.clone(), dns_state.clone(), stats.clone(), settings.clone(), shutdown.clone())),
+                Err(e) => {
+                    error!(listener = %name, error = %e, "Failed to serve metrics");
+                    continue;
+                }
+            },
+            listen::Listener::Unix(listener, _) => match tokio::net::UnixListener::from_std(listener) {
+                Ok(listener) => {
+                    let incoming = hyper::server::accept::poll_fn(move |cx| {
+                        listener.poll_accept(cx).map(|result| Some(result.map(|(stream, _)| stream)))
+                    });
+                    tokio::spawn(serve(Server::builder(incoming), metrics.clone(), dns_state.clone(), stats.clone(), settings.clone(), shutdown.clone()))
+                }
+                Err(e) => {
+                    error!(listener = %name, error = %e, "Failed to serve metrics");
+                    continue;
+                }
+            },
+        };
+        info!(listener = %name, "Prometheus metrics server listening on {}/metrics", name);
+        servers.push((name, server));
+    }
+
+    for (name, server) in servers {
+        match server.await {
+            Ok(Err(e)) => error!(listener = %name, error = %e, "Metrics server stopped"),
+            Err(e) => error!(listener = %name, error = %e, "Metrics server panicked"),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+// 待ち受け先の種類によらず同じハンドラで応答する
+async fn serve<I>(
+    builder: hyper::server::Builder<I>,
+    metrics: Arc<PrometheusMetrics>,
+    dns_state: Arc<dns::DnsState>,
+    stats: SharedStats,
+    settings: config::Settings,
+    shutdown: shutdown::Shutdown,
+) -> Result<(), hyper::Error>
+where
+    I: hyper::server::accept::Accept + Send + 'static,
+    I::Conn: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let server_shutdown = shutdown.clone();
+    let make_svc = make_service_fn(move |_conn: &I::Conn| {
         let metrics = metrics.clone();
+        let dns_state = dns_state.clone();
+        let stats = stats.clone();
+        let settings = settings.clone();
+        let shutdown = shutdown.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let metrics = metrics.clone();
+                let dns_state = dns_state.clone();
+                let stats = stats.clone();
+                let settings = settings.clone();
+                let shutdown = shutdown.clone();
                 async move {
-                    match req.uri().path() {
+                    let params = parse_query_params(req.uri().query());
+                    let path = req.uri().path().to_string();
+                    match path.as_str() {
                         "/metrics" => {
                             let encoder = TextEncoder::new();
-                            let metric_families = metrics.registry.gather();
+                            let labels = settings.read().unwrap().labels.clone();
+                            let metric_families = metrics.gather(&labels);
                             let mut buffer = Vec::new();
                             encoder.encode(&metric_families, &mut buffer).unwrap();
                             Ok::<_, hyper::Error>(Response::new(Body::from(buffer)))
                         }
-                        _ => {
-                            let response = Response::builder()
-                                .status(404)
-                                .body(Body::from("Not Found"))
-                                .unwrap();
-                            Ok(response)
+                        "/api/v1/dns/queries" => Ok(dns_queries_response(&dns_state, &params)),
+                        "/api/v1/hostnames" => Ok(hostnames_response(&dns_state)),
+                        "/api/v1/hosts" => Ok(api::hosts_response(&stats, &dns_state, &params)),
+                        "/api/v1/totals" => Ok(api::totals_response(&stats, &dns_state, &params)),
+                        "/api/v1/top" => Ok(api::top_response(&stats, &dns_state, &params)),
+                        "/api/v1/history" => Ok(history::range_response(&stats.history, &params)),
+                        "/api/v1/stream" if stream::is_websocket_upgrade(&req) => {
+                            Ok(stream::websocket_response(req, &stats.updates, &params, &shutdown))
                         }
+                        "/api/v1/stream" => Ok(stream::sse_response(&stats.updates, &params, &shutdown)),
+                        path if path.starts_with("/api/v1/hosts/") => {
+                            Ok(api::host_response(&stats, &dns_state, &path["/api/v1/hosts/".len()..]))
+                        }
+                        path => match dashboard::response(path) {
+                            Some(response) => Ok(response),
+                            None => {
+                                let response = Response::builder()
+                                    .status(404)
+                                    .body(Body::from("Not Found"))
+                                    .unwrap();
+                                Ok(response)
+                            }
+                        },
                     }
                 }
             }))
         }
     });
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 59121));
-    let server = Server::bind(&addr).serve(make_svc);
-
-    println!("Prometheus metrics server listening on http://{}/metrics", addr);
-    
-    if let Err(e) = server.await {
-        eprintln!("Server error: {}", e);
-    }
+    // 終了処理が始まったら新しい接続を受け付けず、処理中のリクエストが終わるのを待つ
+    builder
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { server_shutdown.wait().await })
+        .await
 }
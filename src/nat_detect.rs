@@ -0,0 +1,231 @@
+// TTL / ホップリミット・IP ID・TCP タイムスタンプによる隠れ NAT（テザリング等）の検出
+//
+// 1つの IP の背後に複数の端末がある場合、送信パケットには
+//  - OS ごとに異なる TTL の初期値（64 / 128 / 255）や、NAT を1段経由したことによる TTL の減少
+//  - 独立した複数の TCP タイムスタンプ時計
+// が現れる。これらをホストごとに集計し、NAT の疑いを判定する。
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+// TTL の値として有意とみなす最小パケット数と割合
+const MIN_TTL_PACKETS: u64 = 20;
+const MIN_TTL_SHARE: f64 = 0.01;
+// IP ID の差分がこの範囲内なら連番とみなす
+const MAX_INCREMENTAL_IPID_DELTA: u16 = 1024;
+// TCP タイムスタンプ時計の追跡数の上限
+const MAX_CONNECTIONS: usize = 256;
+const MAX_CLOCKS: usize = 32;
+// 時計の刻みを推定するのに必要な経過時間（秒）
+const MIN_RATE_INTERVAL: f64 = 1.0;
+// 既知の時計の刻み (Hz)
+const CLOCK_RATES: [f64; 5] = [1.0, 10.0, 100.0, 250.0, 1000.0];
+// 同じ時計とみなすオフセットの許容誤差（秒）
+const CLOCK_TOLERANCE_SECS: f64 = 2.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpIdBehavior {
+    Zero,
+    Incremental,
+    Random,
+}
+
+impl IpIdBehavior {
+    pub fn label(self) -> &'static str {
+        match self {
+            IpIdBehavior::Zero => "zero",
+            IpIdBehavior::Incremental => "incremental",
+            IpIdBehavior::Random => "random",
+        }
+    }
+}
+
+// TCP 接続ごとのタイムスタンプの初回観測値
+struct ConnectionClock {
+    first_tsval: u32,
+    first_seen: Instant,
+    // 刻みが推定できて時計に割り当て済みなら true
+    assigned: bool,
+}
+
+// 刻みと、基準時刻における TSval のオフセット（秒換算）で識別される時計
+struct ClockSource {
+    rate: f64,
+    offset_secs: f64,
+    connections: u32,
+}
+
+pub struct HostSignals {
+    reference: Instant,
+    pub ttl_packets: HashMap<u8, u64>,
+    pub ipid_packets: HashMap<IpIdBehavior, u64>,
+    last_ipid: Option<u16>,
+    connections: HashMap<u16, ConnectionClock>,
+    clocks: Vec<ClockSource>,
+}
+
+impl HostSignals {
+    pub fn new() -> Self {
+        Self {
+            reference: Instant::now(),
+            ttl_packets: HashMap::new(),
+            ipid_packets: HashMap::new(),
+            last_ipid: None,
+            connections: HashMap::new(),
+            clocks: Vec::new(),
+        }
+    }
+
+    /// 送信パケットの TTL（IPv6 ではホップリミット）を記録する
+    pub fn observe_ttl(&mut self, ttl: u8) {
+        *self.ttl_packets.entry(ttl).or_insert(0) += 1;
+    }
+
+    /// IPv4 の Identification フィールドを直前の値と比べて分類する
+    pub fn observe_ipid(&mut self, id: u16) {
+        let behavior = if id == 0 {
+            IpIdBehavior::Zero
+        } else {
+            match self.last_ipid {
+                Some(last) if (1..=MAX_INCREMENTAL_IPID_DELTA).contains(&id.wrapping_sub(last)) => {
+                    IpIdBehavior::Incremental
+                }
+                _ => IpIdBehavior::Random,
+            }
+        };
+        if id != 0 {
+            self.last_ipid = Some(id);
+        }
+        *self.ipid_packets.entry(behavior).or_insert(0) += 1;
+    }
+
+    /// TCP タイムスタンプ (TSval) を送信元ポートごとに追跡し、時計を推定する
+    pub fn observe_tcp_timestamp(&mut self, src_port: u16, tsval: u32) {
+        let now = Instant::now();
+        let Some(connection) = self.connections.get_mut(&src_port) else {
+            if self.connections.len() >= MAX_CONNECTIONS {
+                self.connections.retain(|_, c| !c.assigned);
+                if self.connections.len() >= MAX_CONNECTIONS {
+                    return;
+                }
+            }
+            self.connections.insert(
+                src_port,
+                ConnectionClock {
+                    first_tsval: tsval,
+                    first_seen: now,
+                    assigned: false,
+                },
+            );
+            return;
+        };
+        if connection.assigned {
+            return;
+        }
+
+        let elapsed = now.duration_since(connection.first_seen).as_secs_f64();
+        if elapsed < MIN_RATE_INTERVAL {
+            return;
+        }
+        let ticks = tsval.wrapping_sub(connection.first_tsval) as f64;
+        let Some(rate) = nearest_clock_rate(ticks / elapsed) else {
+            // ポートの再利用などで刻みが合わない場合は測り直す
+            connection.first_tsval = tsval;
+            connection.first_seen = now;
+            return;
+        };
+        connection.assigned = true;
+
+        // 基準時刻（このホストを初めて見た時刻）での TSval を秒換算したもの
+        let since_reference = now.duration_since(self.reference).as_secs_f64();
+        let offset_secs = tsval as f64 / rate - since_reference;
+
+        let period = u32::MAX as f64 / rate;
+        let existing = self.clocks.iter().position(|c| {
+            let diff = (c.offset_secs - offset_secs).rem_euclid(period);
+            c.rate == rate && diff.min(period - diff) <= CLOCK_TOLERANCE_SECS
+        });
+        match existing {
+            Some(i) => self.clocks[i].connections += 1,
+            None if self.clocks.len() < MAX_CLOCKS => self.clocks.push(ClockSource {
+                rate,
+                offset_secs,
+                connections: 1,
+            }),
+            None => {}
+        }
+    }
+
+    /// 有意な数のパケットで観測された TTL の値
+    pub fn significant_ttls(&self) -> Vec<u8> {
+        let total: u64 = self.ttl_packets.values().sum();
+        let mut ttls: Vec<u8> = self
+            .ttl_packets
+            .iter()
+            .filter(|(_, &packets)| {
+                packets >= MIN_TTL_PACKETS && packets as f64 >= total as f64 * MIN_TTL_SHARE
+            })
+            .map(|(&ttl, _)| ttl)
+            .collect();
+        ttls.sort_unstable();
+        ttls
+    }
+
+    /// 有意な TTL から推定した OS の初期値（64 / 128 / 255 など）の種類数
+    pub fn ttl_baselines(&self) -> usize {
+        let mut baselines: Vec<u8> = self.significant_ttls().into_iter().map(ttl_baseline).collect();
+        baselines.dedup();
+        baselines.len()
+    }
+
+    /// 複数の接続で共有されている TCP タイムスタンプ時計の数。
+    /// Linux などは接続ごとにオフセットを乱数化するため、1接続だけの時計は数えない。
+    pub fn clock_sources(&self) -> usize {
+        self.clocks.iter().filter(|c| c.connections >= 2).count()
+    }
+
+    /// 複数の TTL（初期値の違い、または NAT による減少）や複数の時計が見えていれば NAT の疑いあり
+    pub fn nat_suspected(&self) -> bool {
+        self.significant_ttls().len() >= 2 || self.clock_sources() >= 2
+    }
+}
+
+/// 観測した TTL 以上で最小の一般的な初期値
+pub fn ttl_baseline(ttl: u8) -> u8 {
+    match ttl {
+        0..=32 => 32,
+        33..=64 => 64,
+        65..=128 => 128,
+        _ => 255,
+    }
+}
+
+fn nearest_clock_rate(measured: f64) -> Option<f64> {
+    CLOCK_RATES
+        .iter()
+        .copied()
+        .find(|rate| (measured / rate - 1.0).abs() < 0.2)
+}
+
+/// TCP オプション列から Timestamps オプションの TSval を取り出す
+pub fn tcp_timestamp(options: &[u8]) -> Option<u32> {
+    let mut pos = 0;
+    while pos < options.len() {
+        match options[pos] {
+            0 => return None,
+            1 => pos += 1,
+            kind => {
+                let len = *options.get(pos + 1)? as usize;
+                if len < 2 {
+                    return None;
+                }
+                if kind == 8 && len == 10 {
+                    let tsval = options.get(pos + 2..pos + 6)?;
+                    return Some(u32::from_be_bytes(tsval.try_into().ok()?));
+                }
+                pos += len;
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,282 @@
+// 再起動をまたいで引き継ぐホストごとの累積値（状態ファイル）
+//
+// 状態ファイルには一定間隔（state.checkpoint_interval_secs）と終了時にだけ書き込む（スクレイプでは書かない）。
+// 一時ファイルに書いて fsync してから rename するため、途中で落ちても前回の内容がそのまま残る。
+// SIGTERM での停止では最後の値を書くのでカウンタは減らない。電源断などの異常終了では
+// 最後のチェックポイント以降の増分が失われ、Prometheus にはリセットとして1回見える。
+//
+// 引き継ぐのはホストごとの送受信のパケット数・バイト数、TCPの再送・重複ACK・ウィンドウサイズ変更、
+// DNSのクエリ数・タイムアウト数と、最初・最後に通信した時刻、監視対象のホストの合計。
+// 内訳（プロトコル別など）は0から数え直す。
+//
+// 無通信で統計から削除したホストの累積値は別に保持して（archive）状態ファイルにも書き、
+// 再び通信を始めたときに続きから数える。月ごとの使用量は increase(network_ip_bytes_total[...]) で求める。
+// このツールには使用量の上限（クォータ）の機能がないため、クォータの状態は保存しない。
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{IpStats, MonitoredTotals, TrafficTotals};
+
+// 状態ファイルの形式が変わったら上げる（異なる版のファイルは読まない）
+const STATE_VERSION: u32 = 1;
+// 保持する削除済みホストの上限（超えた分は最後の通信が古いものから捨てる）
+const MAX_ARCHIVED_HOSTS: usize = 4096;
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    version: u32,
+    saved_at: u64, // UNIX時刻（秒）
+    hosts: Vec<HostState>,
+    // 統計から削除したホスト
+    #[serde(default)]
+    archived: Vec<HostState>,
+    // 監視対象のホストの合計（network_bytes_total など）
+    #[serde(default)]
+    totals: Option<TotalsState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TotalsState {
+    bytes: [[u64; 3]; 2],   // [tx, rx] × スコープ（lan_lan, lan_wan, wan_lan）
+    packets: [[u64; 3]; 2], // [tx, rx] × スコープ
+    retransmissions: u64,
+    duplicate_acks: u64,
+    window_size_changes: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HostState {
+    ip: IpAddr,
+    first_seen: u64, // UNIX時刻（秒）
+    last_seen: u64,  // UNIX時刻（秒）
+    tx_packets: u64,
+    rx_packets: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    retransmissions: u64,
+    duplicate_acks: u64,
+    window_size_changes: u64,
+    dns_queries: u64,
+    dns_timeouts: u64,
+    // スコープ別（lan_lan, lan_wan, wan_lan）の内訳（network_ip_bytes_total の scope ラベル）
+    #[serde(default)]
+    tx_scope_bytes: [u64; 3],
+    #[serde(default)]
+    tx_scope_packets: [u64; 3],
+    #[serde(default)]
+    rx_scope_bytes: [u64; 3],
+    #[serde(default)]
+    rx_scope_packets: [u64; 3],
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+pub struct StateFile {
+    path: PathBuf,
+    // 定期的な書き込みと終了時の書き込みが重ならないようにする排他
+    write_lock: Mutex<()>,
+    // 統計から削除したホストの累積値（hosts → totals → archive の順にロックする）
+    archive: Mutex<HashMap<IpAddr, HostState>>,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+            archive: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 状態ファイルのホストと合計を復元する。最後の通信から idle_timeout 以上経ったホストは
+    /// 統計には戻さず、再び通信を始めるまで archive に置く。ファイルがなければ 0 を返す
+    pub fn restore(
+        &self,
+        hosts: &mut HashMap<IpAddr, IpStats>,
+        totals: &mut MonitoredTotals,
+        idle_timeout: Duration,
+    ) -> Result<usize, String> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("Failed to read state file {}: {}", self.path.display(), e)),
+        };
+        let state: State = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid state file {}: {}", self.path.display(), e))?;
+        if state.version != STATE_VERSION {
+            return Err(format!(
+                "State file {} has version {} (expected {})",
+                self.path.display(),
+                state.version,
+                STATE_VERSION
+            ));
+        }
+
+        if let Some(saved) = state.totals {
+            totals.bytes = saved.bytes;
+            totals.packets = saved.packets;
+            totals.retransmissions = saved.retransmissions;
+            totals.duplicate_acks = saved.duplicate_acks;
+            totals.window_size_changes = saved.window_size_changes;
+        }
+
+        let now = SystemTime::now();
+        let mut restored = 0;
+        let mut archive = self.archive.lock().unwrap();
+        for host in state.archived {
+            archive.insert(host.ip, host);
+        }
+        for host in state.hosts {
+            let last_seen = from_unix_secs(host.last_seen);
+            let idle = now.duration_since(last_seen).unwrap_or_default();
+            if idle >= idle_timeout {
+                archive.insert(host.ip, host);
+                continue;
+            }
+            let stat = hosts.entry(host.ip).or_insert_with(IpStats::new);
+            apply(stat, &host);
+            stat.last_active = Instant::now().checked_sub(idle).unwrap_or_else(Instant::now);
+            restored += 1;
+        }
+        trim_archive(&mut archive);
+        Ok(restored)
+    }
+
+    /// 統計から削除するホストの累積値を、再び通信を始めるまで保持する
+    pub fn archive(&self, ip: IpAddr, stat: &IpStats) {
+        let mut archive = self.archive.lock().unwrap();
+        archive.insert(ip, host_state(ip, stat));
+        trim_archive(&mut archive);
+    }
+
+    /// 統計にないホストが archive にあれば、続きから数えるように統計へ戻す（パケットを記録する前に呼ぶ）
+    pub fn revive(&self, hosts: &mut HashMap<IpAddr, IpStats>, ips: impl IntoIterator<Item = IpAddr>) {
+        for ip in ips {
+            if hosts.contains_key(&ip) {
+                continue;
+            }
+            let Some(host) = self.archive.lock().unwrap().remove(&ip) else {
+                continue;
+            };
+            apply(hosts.entry(ip).or_insert_with(IpStats::new), &host);
+        }
+    }
+
+    /// ホストと合計の累積値を書き込む（書き込んだホスト数を返す）
+    pub fn save(&self, hosts: &Mutex<HashMap<IpAddr, IpStats>>, totals: &Mutex<TrafficTotals>) -> Result<usize, String> {
+        let _guard = self.write_lock.lock().unwrap();
+        let state = {
+            let hosts = hosts.lock().unwrap();
+            let totals = &totals.lock().unwrap().monitored;
+            let archive = self.archive.lock().unwrap();
+            State {
+                version: STATE_VERSION,
+                saved_at: unix_secs(SystemTime::now()),
+                hosts: hosts.iter().map(|(ip, stat)| host_state(*ip, stat)).collect(),
+                // 統計に戻る前のホストも archive に残っているので、統計にあるものは除く
+                archived: archive.values().filter(|host| !hosts.contains_key(&host.ip)).map(HostState::clone).collect(),
+                totals: Some(TotalsState {
+                    bytes: totals.bytes,
+                    packets: totals.packets,
+                    retransmissions: totals.retransmissions,
+                    duplicate_acks: totals.duplicate_acks,
+                    window_size_changes: totals.window_size_changes,
+                }),
+            }
+        };
+        let json = serde_json::to_vec(&state).map_err(|e| format!("Failed to serialize state: {}", e))?;
+        write_atomic(&self.path, &json).map_err(|e| format!("Failed to write state file {}: {}", self.path.display(), e))?;
+        Ok(state.hosts.len() + state.archived.len())
+    }
+}
+
+// 保存した累積値を足す。last_* にも同じだけ足して、1秒間の値や合計の増分に含まれないようにする
+fn apply(stat: &mut IpStats, host: &HostState) {
+    stat.first_seen = stat.first_seen.min(from_unix_secs(host.first_seen));
+    stat.last_seen = stat.last_seen.max(from_unix_secs(host.last_seen));
+    stat.tx_packet_count += host.tx_packets;
+    stat.rx_packet_count += host.rx_packets;
+    stat.tx_byte_count += host.tx_bytes;
+    stat.rx_byte_count += host.rx_bytes;
+    stat.retransmissions += host.retransmissions;
+    stat.duplicate_acks += host.duplicate_acks;
+    stat.window_size_changes += host.window_size_changes;
+    stat.dns_queries += host.dns_queries;
+    stat.dns_timeouts += host.dns_timeouts;
+    stat.tx_last_bytes += host.tx_bytes;
+    stat.rx_last_bytes += host.rx_bytes;
+    stat.tx_last_packets += host.tx_packets;
+    stat.rx_last_packets += host.rx_packets;
+    stat.last_retransmissions += host.retransmissions;
+    stat.last_duplicate_acks += host.duplicate_acks;
+    stat.last_window_size_changes += host.window_size_changes;
+    stat.tx_scope.restore(host.tx_scope_packets, host.tx_scope_bytes);
+    stat.rx_scope.restore(host.rx_scope_packets, host.rx_scope_bytes);
+}
+
+fn trim_archive(archive: &mut HashMap<IpAddr, HostState>) {
+    if archive.len() <= MAX_ARCHIVED_HOSTS {
+        return;
+    }
+    let mut by_last_seen: Vec<(u64, IpAddr)> = archive.values().map(|host| (host.last_seen, host.ip)).collect();
+    by_last_seen.sort();
+    for (_, ip) in &by_last_seen[..archive.len() - MAX_ARCHIVED_HOSTS] {
+        archive.remove(ip);
+    }
+}
+
+fn host_state(ip: IpAddr, stat: &IpStats) -> HostState {
+    HostState {
+        ip,
+        first_seen: unix_secs(stat.first_seen),
+        last_seen: unix_secs(stat.last_seen),
+        tx_packets: stat.tx_packet_count,
+        rx_packets: stat.rx_packet_count,
+        tx_bytes: stat.tx_byte_count,
+        rx_bytes: stat.rx_byte_count,
+        retransmissions: stat.retransmissions,
+        duplicate_acks: stat.duplicate_acks,
+        window_size_changes: stat.window_size_changes,
+        dns_queries: stat.dns_queries,
+        dns_timeouts: stat.dns_timeouts,
+        tx_scope_bytes: stat.tx_scope.byte_counts(),
+        tx_scope_packets: stat.tx_scope.packet_counts(),
+        rx_scope_bytes: stat.rx_scope.byte_counts(),
+        rx_scope_packets: stat.rx_scope.packet_counts(),
+    }
+}
+
+/// 同じディレクトリの一時ファイルに書いてから置き換える（履歴ファイルでも使う）
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    // rename 自体を永続化する
+    File::open(dir)?.sync_all()
+}
@@ -0,0 +1,351 @@
+// 平文 HTTP/1.x のリクエスト・レスポンス解析
+//
+// 1つの TCP セグメントに収まっているリクエスト行・Host ヘッダ・ステータス行だけを読む。
+// セグメントをまたぐヘッダの再構築は行わない。
+// 再送されたセグメントはシーケンス番号で見分け、同じリクエスト・レスポンスを二重に数えない。
+// Host ヘッダと URL パスはクライアントが自由に決められるので、ラベルにするのは最初に見た
+// MAX_HOST_LABELS 個・MAX_PATH_LABELS 個までで、それ以降は "other" にまとめる。
+// パスはラベルにする前に先頭セグメント（/api/v1/users?id=1 → /api）だけにする。
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 80;
+/// 解析対象ポートを指定する環境変数（カンマ区切り）
+pub const PORTS_ENV: &str = "LPD_HTTP_PORTS";
+
+const METHODS: [&str; 9] = [
+    "GET", "POST", "PUT", "HEAD", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+const MAX_CONNECTIONS: usize = 8192;
+const MAX_PIPELINED: usize = 16;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HOST_LEN: usize = 128;
+const MAX_PATH_PREFIX_LEN: usize = 64;
+const MAX_HOST_LABELS: usize = 64;
+const MAX_PATH_LABELS: usize = 64;
+pub const OTHER_LABEL: &str = "other";
+/// 監視対象外のサーバーをまとめる server ラベル
+pub const EXTERNAL_SERVER: &str = "external";
+
+#[derive(Clone)]
+pub struct HttpRequest {
+    pub method: &'static str,
+    pub host: String,
+    pub path_prefix: String,
+}
+
+pub enum HttpEvent {
+    Request {
+        server: IpAddr,
+        request: HttpRequest,
+    },
+    Response {
+        server: IpAddr,
+        request: Option<HttpRequest>,
+        status: u16,
+        latency: Option<Duration>,
+    },
+}
+
+// クライアント IP/ポート, サーバー IP/ポート
+type ConnectionKey = (IpAddr, u16, IpAddr, u16);
+
+struct PendingRequest {
+    sent_at: Instant,
+    request: HttpRequest,
+}
+
+/// 接続ごとの応答待ちのリクエストと、方向ごとに次に来るはずのシーケンス番号
+#[derive(Default)]
+struct Connection {
+    pending: VecDeque<PendingRequest>,
+    client_next_seq: Option<u32>,
+    server_next_seq: Option<u32>,
+    last_active: Option<Instant>,
+}
+
+pub struct HttpTracker {
+    ports: HashSet<u16>,
+    connections: HashMap<ConnectionKey, Connection>,
+    hosts: HashSet<String>,
+    paths: HashSet<String>,
+}
+
+impl HttpTracker {
+    pub fn new(ports: HashSet<u16>) -> Self {
+        Self {
+            ports,
+            connections: HashMap::new(),
+            hosts: HashSet::new(),
+            paths: HashSet::new(),
+        }
+    }
+
+    /// TCP ペイロードを調べ、HTTP のリクエストまたはレスポンスであればイベントを返す
+    pub fn inspect(
+        &mut self,
+        src: (IpAddr, u16),
+        dst: (IpAddr, u16),
+        seq: u32,
+        payload: &[u8],
+    ) -> Option<HttpEvent> {
+        if payload.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+
+        if self.ports.contains(&dst.1) {
+            let mut request = parse_request(payload)?;
+            let key = (src.0, src.1, dst.0, dst.1);
+            let connection = self.connection(key, now)?;
+            if is_retransmission(&mut connection.client_next_seq, seq, payload.len()) {
+                return None;
+            }
+            request.host = bounded_label(&mut self.hosts, request.host, MAX_HOST_LABELS);
+            request.path_prefix = bounded_label(&mut self.paths, request.path_prefix, MAX_PATH_LABELS);
+            let event_request = request.clone();
+            let connection = self.connections.get_mut(&key)?;
+            if connection.pending.len() < MAX_PIPELINED {
+                connection.pending.push_back(PendingRequest {
+                    sent_at: now,
+                    request,
+                });
+            }
+            return Some(HttpEvent::Request {
+                server: dst.0,
+                request: event_request,
+            });
+        }
+
+        if self.ports.contains(&src.1) {
+            let status = parse_status(payload)?;
+            let connection = self.connection((dst.0, dst.1, src.0, src.1), now)?;
+            if is_retransmission(&mut connection.server_next_seq, seq, payload.len()) {
+                return None;
+            }
+            // 1xx は最終レスポンスではないので、リクエストは待ち行列に残す
+            if (100..200).contains(&status) {
+                return None;
+            }
+            let (request, latency) = match connection.pending.pop_front() {
+                Some(p) => (Some(p.request), Some(now.duration_since(p.sent_at))),
+                None => (None, None),
+            };
+            return Some(HttpEvent::Response {
+                server: src.0,
+                request,
+                status,
+                latency,
+            });
+        }
+
+        None
+    }
+
+    // 接続の状態を取り出す。上限に達したら REQUEST_TIMEOUT 以上動きのない接続を捨て、それでも空きがなければ None
+    fn connection(&mut self, key: ConnectionKey, now: Instant) -> Option<&mut Connection> {
+        if !self.connections.contains_key(&key) && self.connections.len() >= MAX_CONNECTIONS {
+            self.connections.retain(|_, connection| {
+                connection
+                    .last_active
+                    .is_some_and(|at| now.duration_since(at) < REQUEST_TIMEOUT)
+            });
+            if self.connections.len() >= MAX_CONNECTIONS {
+                return None;
+            }
+        }
+        let connection = self.connections.entry(key).or_default();
+        connection.last_active = Some(now);
+        Some(connection)
+    }
+}
+
+// 次に来るはずのシーケンス番号より前から始まるセグメントは再送とみなす（番号の一周を考慮して比較する）
+fn is_retransmission(next_seq: &mut Option<u32>, seq: u32, len: usize) -> bool {
+    if let Some(next) = *next_seq {
+        if (seq.wrapping_sub(next) as i32) < 0 {
+            return true;
+        }
+    }
+    *next_seq = Some(seq.wrapping_add(len as u32));
+    false
+}
+
+// ラベルにする値を最初に見た limit 個に限る
+fn bounded_label(seen: &mut HashSet<String>, value: String, limit: usize) -> String {
+    if seen.contains(&value) {
+        return value;
+    }
+    if seen.len() < limit {
+        seen.insert(value.clone());
+        return value;
+    }
+    OTHER_LABEL.to_string()
+}
+
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "other",
+    }
+}
+
+fn header_lines(payload: &[u8]) -> impl Iterator<Item = &[u8]> {
+    // ヘッダ部分（空行まで）だけを行に分割する
+    let end = payload
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .unwrap_or(payload.len());
+    payload[..end]
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+fn parse_request(payload: &[u8]) -> Option<HttpRequest> {
+    let mut lines = header_lines(payload);
+    let request_line = std::str::from_utf8(lines.next()?).ok()?;
+    let mut parts = request_line.split(' ');
+    let method = parts.next()?;
+    let method = *METHODS.iter().find(|m| **m == method)?;
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let mut host = String::new();
+    for line in lines {
+        let Ok(line) = std::str::from_utf8(line) else {
+            continue;
+        };
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("host") {
+                host = normalize_host(value.trim());
+                break;
+            }
+        }
+    }
+
+    Some(HttpRequest {
+        method,
+        host,
+        path_prefix: path_prefix(target),
+    })
+}
+
+fn parse_status(payload: &[u8]) -> Option<u16> {
+    let status_line = std::str::from_utf8(header_lines(payload).next()?).ok()?;
+    let mut parts = status_line.split(' ');
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+fn normalize_host(host: &str) -> String {
+    // ポート番号を除いて小文字にそろえる（IPv6リテラルの [..] はそのまま）
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    host.to_ascii_lowercase().chars().take(MAX_HOST_LEN).collect()
+}
+
+/// URL パスの先頭セグメントだけを残す（例: /api/v1/users?id=1 → /api）
+fn path_prefix(target: &str) -> String {
+    let path = target
+        .split_once("://")
+        .and_then(|(_, rest)| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or(target);
+    let path = path.split(['?', '#']).next().unwrap_or("");
+    if !path.starts_with('/') {
+        return "*".to_string();
+    }
+    let prefix = match path[1..].find('/') {
+        Some(i) => &path[..i + 1],
+        None => path,
+    };
+    prefix.chars().take(MAX_PATH_PREFIX_LEN).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: (IpAddr, u16) = (IpAddr::V4(Ipv4Addr::new(192, 168, 1, 23)), 50000);
+    const SERVER: (IpAddr, u16) = (IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), DEFAULT_PORT);
+
+    #[test]
+    fn parses_request() {
+        let request = parse_request(b"GET /api/v1/users?id=1 HTTP/1.1\r\nUser-Agent: curl\r\nHOST: Example.COM:8080\r\n\r\n").unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.host, "example.com");
+        assert_eq!(request.path_prefix, "/api");
+
+        // 絶対形式のターゲットと、ホストの IPv6 リテラル
+        let request = parse_request(b"POST http://[::1]:8080/upload#top HTTP/1.0\r\nHost: [::1]:8080\r\n\r\n").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.host, "[::1]");
+        assert_eq!(request.path_prefix, "/upload");
+
+        let request = parse_request(b"OPTIONS * HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.host, "");
+        assert_eq!(request.path_prefix, "*");
+    }
+
+    #[test]
+    fn rejects_non_http_requests() {
+        assert!(parse_request(b"FETCH / HTTP/1.1\r\n\r\n").is_none());
+        assert!(parse_request(b"GET / HTTP/2\r\n\r\n").is_none());
+        assert!(parse_request(b"GET /\r\n").is_none());
+        assert!(parse_request(b"\x16\x03\x01\x00\xa5\x01").is_none());
+    }
+
+    #[test]
+    fn parses_status() {
+        assert_eq!(parse_status(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"), Some(404));
+        assert_eq!(parse_status(b"HTTP/1.0 200 OK\r\n"), Some(200));
+        assert_eq!(parse_status(b"HTTP/2 200\r\n"), None);
+        assert_eq!(parse_status(b"HTTP/1.1 abc\r\n"), None);
+        assert_eq!(status_class(503), "5xx");
+        assert_eq!(status_class(99), "other");
+    }
+
+    #[test]
+    fn bounds_labels() {
+        let mut seen = HashSet::new();
+        for i in 0..3 {
+            assert_eq!(bounded_label(&mut seen, format!("host{}", i), 3), format!("host{}", i));
+        }
+        assert_eq!(bounded_label(&mut seen, "host3".to_string(), 3), OTHER_LABEL);
+        assert_eq!(bounded_label(&mut seen, "host1".to_string(), 3), "host1");
+    }
+
+    #[test]
+    fn matches_responses_and_skips_retransmissions() {
+        let mut tracker = HttpTracker::new(HashSet::from([DEFAULT_PORT]));
+        let request = b"GET /index.html HTTP/1.1\r\nHost: intranet\r\n\r\n";
+        assert!(matches!(tracker.inspect(CLIENT, SERVER, 1000, request), Some(HttpEvent::Request { .. })));
+        // 同じシーケンス番号の再送は数えない
+        assert!(tracker.inspect(CLIENT, SERVER, 1000, request).is_none());
+
+        // 1xx は最終レスポンスではない
+        assert!(tracker.inspect(SERVER, CLIENT, 5000, b"HTTP/1.1 100 Continue\r\n\r\n").is_none());
+        match tracker.inspect(SERVER, CLIENT, 5025, b"HTTP/1.1 200 OK\r\n\r\n") {
+            Some(HttpEvent::Response { request: Some(request), status, latency, .. }) => {
+                assert_eq!(request.host, "intranet");
+                assert_eq!(request.path_prefix, "/index.html");
+                assert_eq!(status, 200);
+                assert!(latency.is_some());
+            }
+            _ => panic!("expected a response matched to the request"),
+        }
+        assert!(tracker.inspect(SERVER, CLIENT, 5025, b"HTTP/1.1 200 OK\r\n\r\n").is_none());
+    }
+}
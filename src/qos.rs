@@ -0,0 +1,56 @@
+// DSCP / ECN マーキングの分類
+//
+// IPv4 の TOS バイトと IPv6 のトラフィッククラスはどちらも
+// 上位6ビットが DSCP、下位2ビットが ECN という同じ構成になっている。
+
+pub const ECN_NOT_ECT: u8 = 0b00;
+pub const ECN_ECT1: u8 = 0b01;
+pub const ECN_ECT0: u8 = 0b10;
+pub const ECN_CE: u8 = 0b11;
+
+pub fn dscp(traffic_class: u8) -> u8 {
+    traffic_class >> 2
+}
+
+pub fn ecn(traffic_class: u8) -> u8 {
+    traffic_class & 0b11
+}
+
+/// DSCP 値をクラス名（RFC 4594 / RFC 8622 の名称）に変換する
+pub fn dscp_name(dscp: u8) -> &'static str {
+    match dscp {
+        0 => "CS0",
+        1 => "LE",
+        8 => "CS1",
+        10 => "AF11",
+        12 => "AF12",
+        14 => "AF13",
+        16 => "CS2",
+        18 => "AF21",
+        20 => "AF22",
+        22 => "AF23",
+        24 => "CS3",
+        26 => "AF31",
+        28 => "AF32",
+        30 => "AF33",
+        32 => "CS4",
+        34 => "AF41",
+        36 => "AF42",
+        38 => "AF43",
+        40 => "CS5",
+        44 => "VOICE-ADMIT",
+        46 => "EF",
+        48 => "CS6",
+        56 => "CS7",
+        _ => "other",
+    }
+}
+
+pub fn ecn_name(ecn: u8) -> &'static str {
+    match ecn {
+        ECN_ECT1 => "ect1",
+        ECN_ECT0 => "ect0",
+        ECN_CE => "ce",
+        _ => "not_ect",
+    }
+}
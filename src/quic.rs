@@ -0,0 +1,582 @@
+// QUIC ロングヘッダの解析と Initial パケットの復号
+//
+// Initial パケットは RFC 9001 / RFC 9369 で公開されているソルトから鍵を導出できるため、
+// パッシブに復号して CRYPTO フレーム内の ClientHello から SNI を読み取れる。
+// 一度 SNI が判明したフローは、以降のショートヘッダパケットも同じドメインに帰属させる。
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::tls;
+
+const VERSION_1: u32 = 0x0000_0001;
+const VERSION_2: u32 = 0x6b33_43cf;
+const VERSION_DRAFT_29: u32 = 0xff00_001d;
+
+const SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const SALT_V2: [u8; 20] = [
+    0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb,
+    0xf9, 0xbd, 0x2e, 0xd9,
+];
+const SALT_DRAFT_29: [u8; 20] = [
+    0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61, 0x11, 0xe0,
+    0x43, 0x90, 0xa8, 0x99,
+];
+
+const MAX_FLOWS: usize = 4096;
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_CRYPTO_BYTES: usize = 16 * 1024;
+
+/// UDP ペイロードを QUIC と判定したときに返す情報
+pub struct QuicPacketInfo {
+    pub version: u32,
+    pub sni: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+}
+
+struct LongHeader<'a> {
+    version: u32,
+    packet_type: Option<PacketType>,
+    dcid: &'a [u8],
+    // パケット番号の開始位置とパケット末尾（いずれもデータグラム先頭からのオフセット）
+    pn_offset: usize,
+    end: usize,
+}
+
+struct InitialKeys {
+    key: [u8; 16],
+    iv: [u8; 12],
+    hp: [u8; 16],
+}
+
+// クライアント側から見たフロー（クライアント IP/ポート, サーバー IP/ポート）
+type FlowKey = (IpAddr, u16, IpAddr, u16);
+
+struct QuicFlow {
+    version: u32,
+    original_dcid: Vec<u8>,
+    sni: Option<String>,
+    // ClientHello の再構築が終わったら true
+    handshake_done: bool,
+    crypto: BTreeMap<u64, Vec<u8>>,
+    crypto_bytes: usize,
+    last_seen: Instant,
+}
+
+pub struct QuicTracker {
+    flows: HashMap<FlowKey, QuicFlow>,
+}
+
+impl QuicTracker {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+        }
+    }
+
+    /// UDP ペイロードを調べ、QUIC であればバージョンと（判明していれば）SNI を返す
+    pub fn inspect(
+        &mut self,
+        src: (IpAddr, u16),
+        dst: (IpAddr, u16),
+        payload: &[u8],
+    ) -> Option<QuicPacketInfo> {
+        let first = *payload.first()?;
+        let now = Instant::now();
+        let client_key = (src.0, src.1, dst.0, dst.1);
+        let server_key = (dst.0, dst.1, src.0, src.1);
+
+        if first & 0x80 == 0 {
+            // ショートヘッダ：既知のフローに属する場合のみ QUIC とみなす
+            let key = if self.flows.contains_key(&client_key) {
+                client_key
+            } else {
+                server_key
+            };
+            let flow = self.flows.get_mut(&key)?;
+            flow.last_seen = now;
+            return Some(QuicPacketInfo {
+                version: flow.version,
+                sni: flow.sni.clone(),
+            });
+        }
+
+        let header = parse_long_header(payload)?;
+        if !is_known_version(header.version) && src.1 != 443 && dst.1 != 443 {
+            return None;
+        }
+
+        let from_client = if self.flows.contains_key(&client_key) {
+            true
+        } else if self.flows.contains_key(&server_key) {
+            false
+        } else {
+            // 初めて見るフロー：クライアントの Initial で始まったものだけを追跡する
+            if header.packet_type != Some(PacketType::Initial) || header.version == 0 {
+                return Some(QuicPacketInfo {
+                    version: header.version,
+                    sni: None,
+                });
+            }
+            self.make_room(now);
+            if self.flows.len() >= MAX_FLOWS {
+                return Some(QuicPacketInfo {
+                    version: header.version,
+                    sni: None,
+                });
+            }
+            self.flows.insert(
+                client_key,
+                QuicFlow {
+                    version: header.version,
+                    original_dcid: header.dcid.to_vec(),
+                    sni: None,
+                    handshake_done: false,
+                    crypto: BTreeMap::new(),
+                    crypto_bytes: 0,
+                    last_seen: now,
+                },
+            );
+            true
+        };
+
+        let key = if from_client { client_key } else { server_key };
+        let flow = self.flows.get_mut(&key)?;
+        flow.last_seen = now;
+        if header.version != 0 {
+            flow.version = header.version;
+        }
+
+        if from_client && !flow.handshake_done {
+            // 1つのデータグラムに複数のロングヘッダパケットが連結されている場合がある
+            let mut offset = 0;
+            while offset < payload.len() {
+                let packet = &payload[offset..];
+                let Some(header) = parse_long_header(packet) else {
+                    break;
+                };
+                if header.packet_type == Some(PacketType::Initial) {
+                    let plaintext = decrypt_initial(packet, &header, &flow.original_dcid)
+                        .or_else(|| decrypt_initial(packet, &header, header.dcid));
+                    if let Some(plaintext) = plaintext {
+                        flow.collect_crypto(&plaintext);
+                    }
+                }
+                if header.packet_type == Some(PacketType::Retry) {
+                    break;
+                }
+                offset += header.end;
+            }
+        }
+
+        Some(QuicPacketInfo {
+            version: flow.version,
+            sni: flow.sni.clone(),
+        })
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        if self.flows.len() >= MAX_FLOWS {
+            self.flows
+                .retain(|_, flow| now.duration_since(flow.last_seen) < FLOW_IDLE_TIMEOUT);
+        }
+    }
+}
+
+impl QuicFlow {
+    fn collect_crypto(&mut self, plaintext: &[u8]) {
+        for (offset, data) in crypto_frames(plaintext) {
+            if self.crypto_bytes + data.len() > MAX_CRYPTO_BYTES {
+                self.finish_handshake();
+                return;
+            }
+            self.crypto_bytes += data.len();
+            self.crypto.entry(offset).or_insert_with(|| data.to_vec());
+        }
+
+        // 先頭から連続している部分だけを繋げて ClientHello を組み立てる
+        let mut assembled: Vec<u8> = Vec::new();
+        for (&offset, data) in &self.crypto {
+            let offset = offset as usize;
+            if offset > assembled.len() {
+                break;
+            }
+            let skip = assembled.len() - offset;
+            if skip < data.len() {
+                assembled.extend_from_slice(&data[skip..]);
+            }
+        }
+
+        match tls::client_hello_len(&assembled) {
+            Some(len) if assembled.len() >= len => {
+                self.sni = tls::client_hello_sni(&assembled[..len]);
+                self.finish_handshake();
+            }
+            Some(_) => {}
+            None if assembled.len() >= 4 => self.finish_handshake(),
+            None => {}
+        }
+    }
+
+    fn finish_handshake(&mut self) {
+        self.handshake_done = true;
+        self.crypto.clear();
+        self.crypto_bytes = 0;
+    }
+}
+
+/// メトリクスのラベルに使うバージョン名
+pub fn version_name(version: u32) -> String {
+    match version {
+        0 => "negotiation".to_string(),
+        VERSION_1 => "v1".to_string(),
+        VERSION_2 => "v2".to_string(),
+        v if v & 0xffff_ff00 == 0xff00_0000 => format!("draft-{}", v & 0xff),
+        v => format!("0x{:08x}", v),
+    }
+}
+
+fn is_known_version(version: u32) -> bool {
+    version == 0
+        || version == VERSION_1
+        || version == VERSION_2
+        || version & 0xffff_ff00 == 0xff00_0000
+}
+
+/// QUIC の可変長整数を読み取り、(値, 消費バイト数) を返す
+fn read_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let first = *buf.first()?;
+    let len = 1usize << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let mut value = (first & 0x3f) as u64;
+    for &b in &bytes[1..] {
+        value = (value << 8) | b as u64;
+    }
+    Some((value, len))
+}
+
+fn parse_long_header(packet: &[u8]) -> Option<LongHeader<'_>> {
+    let first = *packet.first()?;
+    if first & 0x80 == 0 {
+        return None;
+    }
+    let version = u32::from_be_bytes(packet.get(1..5)?.try_into().ok()?);
+
+    let mut pos = 5;
+    let dcid_len = *packet.get(pos)? as usize;
+    if dcid_len > 20 {
+        return None;
+    }
+    let dcid = packet.get(pos + 1..pos + 1 + dcid_len)?;
+    pos += 1 + dcid_len;
+    let scid_len = *packet.get(pos)? as usize;
+    if scid_len > 20 {
+        return None;
+    }
+    packet.get(pos + 1..pos + 1 + scid_len)?;
+    pos += 1 + scid_len;
+
+    if version == 0 {
+        // Version Negotiation
+        return Some(LongHeader {
+            version,
+            packet_type: None,
+            dcid,
+            pn_offset: pos,
+            end: packet.len(),
+        });
+    }
+
+    // 固定ビットが立っていないものは QUIC ではない
+    if first & 0x40 == 0 {
+        return None;
+    }
+
+    let type_bits = (first & 0x30) >> 4;
+    let packet_type = if version == VERSION_2 {
+        match type_bits {
+            0b01 => PacketType::Initial,
+            0b10 => PacketType::ZeroRtt,
+            0b11 => PacketType::Handshake,
+            _ => PacketType::Retry,
+        }
+    } else {
+        match type_bits {
+            0b00 => PacketType::Initial,
+            0b01 => PacketType::ZeroRtt,
+            0b10 => PacketType::Handshake,
+            _ => PacketType::Retry,
+        }
+    };
+
+    if packet_type == PacketType::Retry {
+        return Some(LongHeader {
+            version,
+            packet_type: Some(packet_type),
+            dcid,
+            pn_offset: pos,
+            end: packet.len(),
+        });
+    }
+
+    if packet_type == PacketType::Initial {
+        let (token_len, n) = read_varint(packet.get(pos..)?)?;
+        pos = pos.checked_add(n)?.checked_add(token_len as usize)?;
+    }
+    let (length, n) = read_varint(packet.get(pos..)?)?;
+    pos += n;
+    let end = pos.checked_add(length as usize)?;
+    if end > packet.len() {
+        return None;
+    }
+
+    Some(LongHeader {
+        version,
+        packet_type: Some(packet_type),
+        dcid,
+        pn_offset: pos,
+        end,
+    })
+}
+
+fn hkdf_expand_label(secret: &[u8], label: &str, out: &mut [u8]) -> Option<()> {
+    let full_label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(4 + full_label.len());
+    info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    info.push(full_label.len() as u8);
+    info.extend_from_slice(full_label.as_bytes());
+    info.push(0); // context は空
+
+    let hkdf = Hkdf::<Sha256>::from_prk(secret).ok()?;
+    hkdf.expand(&info, out).ok()
+}
+
+fn client_initial_keys(version: u32, dcid: &[u8]) -> Option<InitialKeys> {
+    let (salt, labels) = match version {
+        VERSION_1 => (&SALT_V1, ["quic key", "quic iv", "quic hp"]),
+        VERSION_2 => (&SALT_V2, ["quicv2 key", "quicv2 iv", "quicv2 hp"]),
+        VERSION_DRAFT_29 => (&SALT_DRAFT_29, ["quic key", "quic iv", "quic hp"]),
+        _ => return None,
+    };
+
+    let (initial_secret, _) = Hkdf::<Sha256>::extract(Some(salt), dcid);
+    let mut client_secret = [0u8; 32];
+    hkdf_expand_label(&initial_secret, "client in", &mut client_secret)?;
+
+    let mut keys = InitialKeys {
+        key: [0; 16],
+        iv: [0; 12],
+        hp: [0; 16],
+    };
+    hkdf_expand_label(&client_secret, labels[0], &mut keys.key)?;
+    hkdf_expand_label(&client_secret, labels[1], &mut keys.iv)?;
+    hkdf_expand_label(&client_secret, labels[2], &mut keys.hp)?;
+    Some(keys)
+}
+
+/// クライアントの Initial パケットのヘッダ保護を外してペイロードを復号する
+fn decrypt_initial(packet: &[u8], header: &LongHeader, dcid: &[u8]) -> Option<Vec<u8>> {
+    let keys = client_initial_keys(header.version, dcid)?;
+
+    let sample_offset = header.pn_offset + 4;
+    let sample = packet.get(sample_offset..sample_offset + 16)?;
+    let hp = Aes128::new(GenericArray::from_slice(&keys.hp));
+    let mut mask = GenericArray::clone_from_slice(sample);
+    hp.encrypt_block(&mut mask);
+
+    let first = packet[0] ^ (mask[0] & 0x0f);
+    let pn_len = (first & 0x03) as usize + 1;
+    let payload_start = header.pn_offset + pn_len;
+    if payload_start > header.end {
+        return None;
+    }
+
+    let mut aad = packet[..payload_start].to_vec();
+    aad[0] = first;
+    let mut packet_number = 0u64;
+    for i in 0..pn_len {
+        aad[header.pn_offset + i] ^= mask[1 + i];
+        packet_number = (packet_number << 8) | aad[header.pn_offset + i] as u64;
+    }
+
+    let mut nonce = keys.iv;
+    for (n, p) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+        *n ^= p;
+    }
+
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&keys.key));
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &packet[payload_start..header.end],
+                aad: &aad,
+            },
+        )
+        .ok()
+}
+
+/// 復号済みペイロードから CRYPTO フレームの (オフセット, データ) を取り出す
+fn crypto_frames(mut buf: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut frames = Vec::new();
+    while let Some((frame_type, n)) = read_varint(buf) {
+        buf = &buf[n..];
+        match frame_type {
+            // PADDING / PING
+            0x00 | 0x01 => {}
+            // ACK / ACK_ECN
+            0x02 | 0x03 => {
+                let Some(rest) = skip_ack(buf, frame_type == 0x03) else {
+                    break;
+                };
+                buf = rest;
+            }
+            // CRYPTO
+            0x06 => {
+                let Some((offset, n1)) = read_varint(buf) else {
+                    break;
+                };
+                let Some((len, n2)) = read_varint(&buf[n1..]) else {
+                    break;
+                };
+                let start = n1 + n2;
+                let Some(data) = buf.get(start..start + len as usize) else {
+                    break;
+                };
+                frames.push((offset, data));
+                buf = &buf[start + len as usize..];
+            }
+            // Initial に現れない（または読み飛ばせない）フレームに当たったら終了
+            _ => break,
+        }
+    }
+    frames
+}
+
+fn skip_ack(mut buf: &[u8], ecn: bool) -> Option<&[u8]> {
+    // Largest Acknowledged, ACK Delay, ACK Range Count, First ACK Range
+    let mut fields = [0u64; 4];
+    for field in fields.iter_mut() {
+        *field = skip_varint(&mut buf)?;
+    }
+    // Gap と ACK Range Length の組、ECN の場合は3つのカウンタが続く
+    let remaining = fields[2].checked_mul(2)? + if ecn { 3 } else { 0 };
+    for _ in 0..remaining {
+        skip_varint(&mut buf)?;
+    }
+    Some(buf)
+}
+
+fn skip_varint(buf: &mut &[u8]) -> Option<u64> {
+    let (value, n) = read_varint(buf)?;
+    *buf = &buf[n..];
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 9001 Appendix A の宛先コネクション ID と、A.2 の保護済みクライアント Initial パケット
+    const DCID: &str = "8394c8f03e515708";
+    const CLIENT_INITIAL: &str = concat!(
+        "c000000001088394c8f03e5157080000449e7b9aec34d1b1c98dd7689fb8ec11",
+        "d242b123dc9bd8bab936b47d92ec356c0bab7df5976d27cd449f63300099f399",
+        "1c260ec4c60d17b31f8429157bb35a1282a643a8d2262cad67500cadb8e7378c",
+        "8eb7539ec4d4905fed1bee1fc8aafba17c750e2c7ace01e6005f80fcb7df6212",
+        "30c83711b39343fa028cea7f7fb5ff89eac2308249a02252155e2347b63d58c5",
+        "457afd84d05dfffdb20392844ae812154682e9cf012f9021a6f0be17ddd0c208",
+        "4dce25ff9b06cde535d0f920a2db1bf362c23e596d11a4f5a6cf3948838a3aec",
+        "4e15daf8500a6ef69ec4e3feb6b1d98e610ac8b7ec3faf6ad760b7bad1db4ba3",
+        "485e8a94dc250ae3fdb41ed15fb6a8e5eba0fc3dd60bc8e30c5c4287e53805db",
+        "059ae0648db2f64264ed5e39be2e20d82df566da8dd5998ccabdae053060ae6c",
+        "7b4378e846d29f37ed7b4ea9ec5d82e7961b7f25a9323851f681d582363aa5f8",
+        "9937f5a67258bf63ad6f1a0b1d96dbd4faddfcefc5266ba6611722395c906556",
+        "be52afe3f565636ad1b17d508b73d8743eeb524be22b3dcbc2c7468d54119c74",
+        "68449a13d8e3b95811a198f3491de3e7fe942b330407abf82a4ed7c1b311663a",
+        "c69890f4157015853d91e923037c227a33cdd5ec281ca3f79c44546b9d90ca00",
+        "f064c99e3dd97911d39fe9c5d0b23a229a234cb36186c4819e8b9c5927726632",
+        "291d6a418211cc2962e20fe47feb3edf330f2c603a9d48c0fcb5699dbfe58964",
+        "25c5bac4aee82e57a85aaf4e2513e4f05796b07ba2ee47d80506f8d2c25e50fd",
+        "14de71e6c418559302f939b0e1abd576f279c4b2e0feb85c1f28ff18f58891ff",
+        "ef132eef2fa09346aee33c28eb130ff28f5b766953334113211996d20011a198",
+        "e3fc433f9f2541010ae17c1bf202580f6047472fb36857fe843b19f5984009dd",
+        "c324044e847a4f4a0ab34f719595de37252d6235365e9b84392b061085349d73",
+        "203a4a13e96f5432ec0fd4a1ee65accdd5e3904df54c1da510b0ff20dcc0c77f",
+        "cb2c0e0eb605cb0504db87632cf3d8b4dae6e705769d1de354270123cb11450e",
+        "fc60ac47683d7b8d0f811365565fd98c4c8eb936bcab8d069fc33bd801b03ade",
+        "a2e1fbc5aa463d08ca19896d2bf59a071b851e6c239052172f296bfb5e724047",
+        "90a2181014f3b94a4e97d117b438130368cc39dbb2d198065ae3986547926cd2",
+        "162f40a29f0c3c8745c0f50fba3852e566d44575c29d39a03f0cda721984b6f4",
+        "40591f355e12d439ff150aab7613499dbd49adabc8676eef023b15b65bfc5ca0",
+        "6948109f23f350db82123535eb8a7433bdabcb909271a6ecbcb58b936a88cd4e",
+        "8f2e6ff5800175f113253d8fa9ca8885c2f552e657dc603f252e1a8e308f76f0",
+        "be79e2fb8f5d5fbbe2e30ecadd220723c8c0aea8078cdfcb3868263ff8f09400",
+        "54da48781893a7e49ad5aff4af300cd804a6b6279ab3ff3afb64491c85194aab",
+        "760d58a606654f9f4400e8b38591356fbf6425aca26dc85244259ff2b19c41b9",
+        "f96f3ca9ec1dde434da7d2d392b905ddf3d1f9af93d1af5950bd493f5aa731b4",
+        "056df31bd267b6b90a079831aaf579be0a39013137aac6d404f518cfd4684064",
+        "7e78bfe706ca4cf5e9c5453e9f7cfd2b8b4c8d169a44e55c88d4a9a7f9474241",
+        "e221af44860018ab0856972e194cd934",
+    );
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derives_rfc9001_client_initial_keys() {
+        let keys = client_initial_keys(VERSION_1, &hex(DCID)).unwrap();
+        assert_eq!(keys.key.to_vec(), hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(keys.hp.to_vec(), hex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+
+    #[test]
+    fn decrypts_rfc9001_client_initial() {
+        let packet = hex(CLIENT_INITIAL);
+        let header = parse_long_header(&packet).unwrap();
+        assert!(header.packet_type == Some(PacketType::Initial));
+        assert_eq!(header.dcid, hex(DCID));
+        assert_eq!(header.end, packet.len());
+
+        let plaintext = decrypt_initial(&packet, &header, header.dcid).unwrap();
+        assert_eq!(plaintext.len(), 1162);
+        let frames = crypto_frames(&plaintext);
+        assert_eq!(frames.len(), 1);
+        let (offset, client_hello) = frames[0];
+        assert_eq!(offset, 0);
+        assert_eq!(tls::client_hello_len(client_hello), Some(client_hello.len()));
+        assert_eq!(tls::client_hello_sni(client_hello).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn tracks_sni_of_rfc9001_client_initial() {
+        let client = ("192.0.2.1".parse().unwrap(), 50000);
+        let server = ("198.51.100.1".parse().unwrap(), 443);
+        let mut tracker = QuicTracker::new();
+        let info = tracker.inspect(client, server, &hex(CLIENT_INITIAL)).unwrap();
+        assert_eq!(info.version, VERSION_1);
+        assert_eq!(info.sni.as_deref(), Some("example.com"));
+    }
+}
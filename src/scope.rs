@@ -0,0 +1,190 @@
+// LAN 内（east-west）とインターネット向けのトラフィックの区別
+//
+// 両端がサブネット内のパケットは送信側の TX と受信側の RX の両方に数えられるため、
+// WAN の利用量を求めるにはパケットごとに LAN↔LAN / LAN→WAN / WAN→LAN を判定して別に集計する。
+
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use pnet::ipnetwork::{Ipv4Network, Ipv6Network};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    LanToLan,
+    LanToWan,
+    WanToLan,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::LanToLan, Scope::LanToWan, Scope::WanToLan];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Scope::LanToLan => "lan_lan",
+            Scope::LanToWan => "lan_wan",
+            Scope::WanToLan => "wan_lan",
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// 送信元・宛先が LAN 側かどうかからスコープを決める（両端とも WAN なら None）
+pub fn classify(src_is_lan: bool, dst_is_lan: bool) -> Option<Scope> {
+    match (src_is_lan, dst_is_lan) {
+        (true, true) => Some(Scope::LanToLan),
+        (true, false) => Some(Scope::LanToWan),
+        (false, true) => Some(Scope::WanToLan),
+        (false, false) => None,
+    }
+}
+
+/// ルーターを越えないアドレス（リンクローカル・未指定、IPv6 のユニークローカル）。
+/// IPv6 のグローバルアドレスはインターフェースのプレフィックス（TargetSet）に含まれる場合のみ LAN 側になる。
+pub fn is_link_scoped(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local() || v4.is_unspecified(),
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            v6.is_unspecified() || (first & 0xffc0) == 0xfe80 || (first & 0xfe00) == 0xfc00
+        }
+    }
+}
+
+/// スコープ別のパケット数・バイト数と直近1秒の値
+pub struct ScopeCounters {
+    packets: [u64; 3],
+    bytes: [u64; 3],
+    last_bytes: [u64; 3],
+    bytes_per_sec: [u64; 3],
+    bps: [f64; 3],
+    last_time: Instant,
+}
+
+impl ScopeCounters {
+    pub fn new() -> Self {
+        Self {
+            packets: [0; 3],
+            bytes: [0; 3],
+            last_bytes: [0; 3],
+            bytes_per_sec: [0; 3],
+            bps: [0.0; 3],
+            last_time: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, scope: Scope, bytes: u64) {
+        self.packets[scope.index()] += 1;
+        self.bytes[scope.index()] += bytes;
+    }
+
+    /// 状態ファイルから復元した累積値を足す（直近1秒の値には含めない）
+    pub fn restore(&mut self, packets: [u64; 3], bytes: [u64; 3]) {
+        for i in 0..3 {
+            self.packets[i] += packets[i];
+            self.bytes[i] += bytes[i];
+            self.last_bytes[i] += bytes[i];
+        }
+    }
+
+    pub fn packet_counts(&self) -> [u64; 3] {
+        self.packets
+    }
+
+    pub fn byte_counts(&self) -> [u64; 3] {
+        self.bytes
+    }
+
+    /// 1秒以上経過していれば直近1秒の値を更新する
+    pub fn roll(&mut self, now: Instant) {
+        let time_diff = now.duration_since(self.last_time).as_secs_f64();
+        if time_diff < 1.0 {
+            return;
+        }
+        for i in 0..3 {
+            let diff = self.bytes[i] - self.last_bytes[i];
+            self.bytes_per_sec[i] = diff;
+            self.bps[i] = (diff as f64 * 8.0) / time_diff;
+            self.last_bytes[i] = self.bytes[i];
+        }
+        self.last_time = now;
+    }
+
+    pub fn packets(&self, scope: Scope) -> u64 {
+        self.packets[scope.index()]
+    }
+
+    pub fn bytes(&self, scope: Scope) -> u64 {
+        self.bytes[scope.index()]
+    }
+
+    pub fn bytes_per_sec(&self, scope: Scope) -> u64 {
+        self.bytes_per_sec[scope.index()]
+    }
+
+    pub fn bps(&self, scope: Scope) -> f64 {
+        self.bps[scope.index()]
+    }
+}
+
+/// 監視対象（LAN 側）のアドレス。IPv4 はサブネット（ネットワークアドレスとブロードキャストアドレスを除く）、
+/// IPv6 はプレフィックスで判定する。クローンは同じ集合を共有し、設定の再読み込みで replace() により差し替える
+#[derive(Clone)]
+pub struct TargetSet {
+    networks: Arc<RwLock<Networks>>,
+}
+
+struct Networks {
+    ipv4: Vec<Ipv4Network>,
+    ipv6: Vec<Ipv6Network>,
+}
+
+impl TargetSet {
+    pub fn new(ipv4: Vec<Ipv4Network>, ipv6: Vec<Ipv6Network>) -> Self {
+        Self {
+            networks: Arc::new(RwLock::new(Networks { ipv4, ipv6 })),
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let networks = self.networks.read().unwrap();
+        match ip {
+            IpAddr::V4(v4) => networks.ipv4.iter().any(|network| {
+                // /31・/32 はすべてのアドレスがホスト
+                network.contains(*v4)
+                    && (network.prefix() > 30 || (*v4 != network.network() && *v4 != network.broadcast()))
+            }),
+            IpAddr::V6(v6) => networks.ipv6.iter().any(|network| network.contains(*v6)),
+        }
+    }
+
+    /// IPv4 サブネット内のホストのアドレス数
+    pub fn ipv4_len(&self) -> usize {
+        let networks = self.networks.read().unwrap();
+        networks
+            .ipv4
+            .iter()
+            .map(|network| match network.prefix() {
+                31 => 2,
+                32 => 1,
+                prefix => (1usize << (32 - prefix)) - 2,
+            })
+            .sum()
+    }
+
+    pub fn ipv4_networks(&self) -> Vec<Ipv4Network> {
+        self.networks.read().unwrap().ipv4.clone()
+    }
+
+    pub fn ipv6_networks(&self) -> Vec<Ipv6Network> {
+        self.networks.read().unwrap().ipv6.clone()
+    }
+
+    /// 監視対象を差し替える（既存のホストの統計はそのまま残る）
+    pub fn replace(&self, ipv4: Vec<Ipv4Network>, ipv6: Vec<Ipv6Network>) {
+        *self.networks.write().unwrap() = Networks { ipv4, ipv6 };
+    }
+}
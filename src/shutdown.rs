@@ -0,0 +1,74 @@
+// SIGINT / SIGTERM での終了処理
+//
+// 1回目のシグナルで running を false にしてキャプチャと統計スレッドを止め、最後の統計を出力してから終了する。
+// HTTP サーバーは新しい接続の受け付けをやめ、配信中の SSE / WebSocket を閉じてから止まる。
+// 終了処理の途中で2回目のシグナルを受けた場合は待たずに終了する。
+
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+// 2回目のシグナルで強制終了するときの終了コード（128 + SIGINT）
+const FORCED_EXIT_CODE: i32 = 130;
+
+#[derive(Clone)]
+pub struct Shutdown {
+    running: Arc<AtomicBool>,
+    stopping: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            running: Arc::new(AtomicBool::new(true)),
+            stopping: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// 終了処理を始める。既に始まっていれば false
+    pub fn begin(&self, reason: &str) -> bool {
+        if !self.running.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        info!(reason, "Shutting down");
+        self.stopping.send_replace(true);
+        true
+    }
+
+    /// 終了処理が始まるまで待つ（HTTP サーバーと配信のタスクで使う）
+    pub async fn wait(&self) {
+        let mut stopping = self.stopping.subscribe();
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+}
+
+/// SIGINT / SIGTERM を待ち、1回目で終了処理を始め、2回目で強制終了する
+pub async fn handle_signals(shutdown: Shutdown) {
+    let (mut interrupt, mut terminate) = match (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            error!(error = %e, "Failed to install the SIGINT/SIGTERM handlers");
+            return;
+        }
+    };
+    loop {
+        let reason = tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        };
+        if shutdown.begin(reason) {
+            info!("Send the signal again to quit immediately");
+        } else {
+            warn!(reason, "Second signal received, quitting without waiting");
+            process::exit(FORCED_EXIT_CODE);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// 統計の1秒ごとの更新を SSE / WebSocket（/api/v1/stream）で配信する
+//
+// 統計スレッドは calculate_bps の後にスナップショットを broadcast チャネルへ送るだけで、
+// 送信を待たない。読み出しが遅いクライアントは古いスナップショットを読み飛ばす（Lagged）。
+// 購読条件は REST API と同じ絞り込み（host / subnet / active / active_within）に加えて、
+// fields（出力する項目、カンマ区切り）と mode（snapshot: 毎回全体 / delta: 変化したホストのみ）。
+// 終了処理が始まったら最後のスナップショットを送ってから配信を閉じる。
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::{Body, Request, Response};
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::api::{self, HostFilter, HostSummary};
+use crate::scope::TargetSet;
+use crate::shutdown::Shutdown;
+use crate::{dns, IpStats};
+
+// 読み飛ばしが起きるまでに溜めておくスナップショットの数
+const UPDATE_BUFFER: usize = 8;
+// 終了処理の開始後に最後のスナップショットを待つ時間
+const FINAL_UPDATE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 1回の統計更新の内容（IP順）
+pub struct Snapshot {
+    timestamp: u64,
+    hosts: Vec<HostSummary>,
+}
+
+pub type Updates = broadcast::Sender<Arc<Snapshot>>;
+
+pub fn channel() -> Updates {
+    broadcast::channel(UPDATE_BUFFER).0
+}
+
+/// 購読者がいればスナップショットを配信する（統計スレッドから呼ぶ、ブロックしない）
+pub fn publish(updates: &Updates, hosts: &HashMap<IpAddr, IpStats>, target_ips: &TargetSet, hostnames: &dns::HostnameCache) {
+    if updates.receiver_count() == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let mut summaries: Vec<HostSummary> = hosts
+        .iter()
+        .map(|(ip, stat)| api::summarize(*ip, stat, target_ips.contains(ip), hostnames, now))
+        .collect();
+    summaries.sort_by_key(|host| host.ip);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let _ = updates.send(Arc::new(Snapshot { timestamp, hosts: summaries }));
+}
+
+/// クライアントごとの購読条件と、差分配信のための前回の送信内容
+struct Subscription {
+    filter: HostFilter,
+    fields: Option<HashSet<String>>,
+    delta: bool,
+    last: HashMap<IpAddr, Value>,
+}
+
+impl Subscription {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let filter = HostFilter::from_params(params)?;
+        let fields = params
+            .get("fields")
+            .map(|value| value.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect());
+        let delta = match params.get("mode").map(String::as_str) {
+            None | Some("snapshot") => false,
+            Some("delta") => true,
+            Some(other) => return Err(format!("invalid mode '{}' (expected snapshot or delta)", other)),
+        };
+        Ok(Self {
+            filter,
+            fields,
+            delta,
+            last: HashMap::new(),
+        })
+    }
+
+    // ip は常に含め、fields が指定されていればその項目だけを残す
+    fn select(&self, host: &HostSummary) -> Value {
+        let mut value = serde_json::to_value(host).unwrap_or(Value::Null);
+        if let (Some(fields), Value::Object(map)) = (&self.fields, &mut value) {
+            map.retain(|key, _| key == "ip" || fields.contains(key));
+        }
+        value
+    }
+
+    /// 送信するメッセージ（差分配信で変化がなければ None）
+    fn render(&mut self, snapshot: &Snapshot) -> Option<String> {
+        let mut current = HashMap::new();
+        let mut hosts = Vec::new();
+        for host in snapshot.hosts.iter().filter(|host| self.filter.matches(host)) {
+            let value = self.select(host);
+            if !self.delta || self.last.get(&host.ip) != Some(&value) {
+                hosts.push(value.clone());
+            }
+            if self.delta {
+                current.insert(host.ip, value);
+            }
+        }
+        if !self.delta {
+            return Some(serde_json::json!({ "timestamp": snapshot.timestamp, "hosts": hosts }).to_string());
+        }
+
+        let removed: Vec<IpAddr> = self.last.keys().filter(|ip| !current.contains_key(ip)).copied().collect();
+        self.last = current;
+        if hosts.is_empty() && removed.is_empty() {
+            return None;
+        }
+        Some(serde_json::json!({ "timestamp": snapshot.timestamp, "hosts": hosts, "removed": removed }).to_string())
+    }
+}
+
+pub fn is_websocket_upgrade(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Server-Sent Events で配信する
+pub fn sse_response(updates: &Updates, params: &HashMap<String, String>, shutdown: &Shutdown) -> Response<Body> {
+    let mut subscription = match Subscription::from_params(params) {
+        Ok(subscription) => subscription,
+        Err(e) => return api::error_response(400, &e),
+    };
+    let mut receiver = updates.subscribe();
+    let (mut sender, body) = Body::channel();
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        loop {
+            let snapshot = match next_update(&mut receiver, &shutdown).await {
+                Some(snapshot) => snapshot,
+                None => break,
+            };
+            // 変化がない周期もコメント行を送り、切断を検知する
+            let event = match subscription.render(&snapshot) {
+                Some(message) => format!("data: {}\n\n", message),
+                None => ": keep-alive\n\n".to_string(),
+            };
+            if sender.send_data(event.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+/// WebSocket にアップグレードして配信する
+pub fn websocket_response(req: Request<Body>, updates: &Updates, params: &HashMap<String, String>, shutdown: &Shutdown) -> Response<Body> {
+    let Some(key) = req.headers().get(SEC_WEBSOCKET_KEY) else {
+        return api::error_response(400, "missing Sec-WebSocket-Key header");
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    let subscription = match Subscription::from_params(params) {
+        Ok(subscription) => subscription,
+        Err(e) => return api::error_response(400, &e),
+    };
+    let receiver = updates.subscribe();
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                run_websocket(socket, receiver, subscription, shutdown).await;
+            }
+            Err(e) => tracing::warn!(error = %e, "WebSocket upgrade failed"),
+        }
+    });
+    Response::builder()
+        .status(101)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn run_websocket(
+    socket: WebSocketStream<hyper::upgrade::Upgraded>,
+    mut receiver: broadcast::Receiver<Arc<Snapshot>>,
+    mut subscription: Subscription,
+    shutdown: Shutdown,
+) {
+    let (mut sink, mut incoming) = socket.split();
+    loop {
+        tokio::select! {
+            update = next_update(&mut receiver, &shutdown) => {
+                let Some(snapshot) = update else {
+                    let _ = sink.send(Message::Close(None)).await;
+                    break;
+                };
+                if let Some(message) = subscription.render(&snapshot) {
+                    if sink.send(Message::Text(message)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            message = incoming.next() => match message {
+                // クライアントは {"host": "192.168.1.23", "fields": "tx,rx", "mode": "delta"} のように購読条件を変更できる
+                Some(Ok(Message::Text(text))) => {
+                    let result = serde_json::from_str::<HashMap<String, String>>(&text)
+                        .map_err(|e| format!("invalid subscription: {}", e))
+                        .and_then(|params| Subscription::from_params(&params));
+                    match result {
+                        Ok(new_subscription) => subscription = new_subscription,
+                        Err(e) => {
+                            let error = serde_json::json!({ "error": e }).to_string();
+                            if sink.send(Message::Text(error)).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+/// 次のスナップショット。終了処理が始まった後は、統計スレッドが送る最後のスナップショットを待ってから None を返す
+async fn next_update(receiver: &mut broadcast::Receiver<Arc<Snapshot>>, shutdown: &Shutdown) -> Option<Arc<Snapshot>> {
+    loop {
+        let update = if shutdown.is_running() {
+            tokio::select! {
+                update = receiver.recv() => update,
+                _ = shutdown.wait() => continue,
+            }
+        } else {
+            match tokio::time::timeout(FINAL_UPDATE_TIMEOUT, receiver.recv()).await {
+                Ok(update) => update,
+                Err(_) => return None,
+            }
+        };
+        match update {
+            Ok(snapshot) => return Some(snapshot),
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// TLS ClientHello から SNI を取り出すための最小限のパーサと、TCP 上の TLS 接続の追跡
+//
+// QUIC の CRYPTO フレームは TLS レコード層を持たないため、
+// ハンドシェイクメッセージ（type + 24bit length）から読み始める。
+// TCP ではクライアントが最初に送るハンドシェイクレコードの中身を繋げて同じパーサに渡し、
+// SNI が判明した接続は QUIC と同様に以降の両方向のセグメントも同じドメインに帰属させる。
+// SNI はクライアントが自由に決められるので、ホストごとのドメインの種類数には上限を設ける。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_HOST: u8 = 0x00;
+const RECORD_HANDSHAKE: u8 = 0x16;
+const RECORD_HEADER_LEN: usize = 5;
+// DNS 名の最大長を超える server_name は受け付けない
+const MAX_NAME_LEN: usize = 253;
+
+const MAX_FLOWS: usize = 4096;
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const MAX_CLIENT_HELLO_BYTES: usize = 16 * 1024;
+
+/// ホストごとのドメインの種類数の上限と、超えた分をまとめる名前
+pub const MAX_DOMAINS_PER_HOST: usize = 64;
+pub const OTHER_DOMAIN: &str = "other";
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let slice = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+}
+
+/// ハンドシェイクメッセージ全体の長さ（ヘッダ4バイトを含む）を返す。
+/// ClientHello 以外、または長さがまだ読めない場合は None。
+pub fn client_hello_len(handshake: &[u8]) -> Option<usize> {
+    let mut r = Reader::new(handshake);
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    Some(r.u24()? + 4)
+}
+
+/// ハンドシェイクメッセージ形式の ClientHello から server_name を取り出す
+pub fn client_hello_sni(handshake: &[u8]) -> Option<String> {
+    let mut r = Reader::new(handshake);
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let body_len = r.u24()?;
+    let mut body = Reader::new(r.take(body_len)?);
+
+    body.take(2)?; // legacy_version
+    body.take(32)?; // random
+    let session_id_len = body.u8()? as usize;
+    body.take(session_id_len)?;
+    let cipher_suites_len = body.u16()? as usize;
+    body.take(cipher_suites_len)?;
+    let compression_len = body.u8()? as usize;
+    body.take(compression_len)?;
+
+    let extensions_len = body.u16()? as usize;
+    let mut extensions = Reader::new(body.take(extensions_len)?);
+    while let (Some(ext_type), Some(ext_len)) = (extensions.u16(), extensions.u16()) {
+        let data = extensions.take(ext_len as usize)?;
+        if ext_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut sni = Reader::new(data);
+        let list_len = sni.u16()? as usize;
+        let mut list = Reader::new(sni.take(list_len)?);
+        while let Some(name_type) = list.u8() {
+            let name_len = list.u16()? as usize;
+            let name = list.take(name_len)?;
+            if name_type == SERVER_NAME_HOST {
+                if name.len() > MAX_NAME_LEN || !name.iter().all(|b| b.is_ascii_graphic()) {
+                    return None;
+                }
+                return std::str::from_utf8(name).ok().map(|s| s.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+// クライアント側から見た接続（クライアント IP/ポート, サーバー IP/ポート）
+type FlowKey = (IpAddr, u16, IpAddr, u16);
+
+struct TlsFlow {
+    sni: Option<String>,
+    // ClientHello を読み終えたら true
+    handshake_done: bool,
+    // クライアントが送った先頭からのバイト列と、次に来るはずのシーケンス番号
+    stream: Vec<u8>,
+    next_seq: u32,
+    last_seen: Instant,
+}
+
+pub struct TlsTracker {
+    flows: HashMap<FlowKey, TlsFlow>,
+}
+
+impl TlsTracker {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+        }
+    }
+
+    /// TCP セグメントを調べ、TLS 接続の SNI が判明していれば返す
+    pub fn inspect(
+        &mut self,
+        src: (IpAddr, u16),
+        dst: (IpAddr, u16),
+        seq: u32,
+        payload: &[u8],
+    ) -> Option<String> {
+        let now = Instant::now();
+        let client_key = (src.0, src.1, dst.0, dst.1);
+        let server_key = (dst.0, dst.1, src.0, src.1);
+
+        if let Some(flow) = self.flows.get_mut(&server_key) {
+            flow.last_seen = now;
+            return flow.sni.clone();
+        }
+
+        if !self.flows.contains_key(&client_key) {
+            // 初めて見る接続：ClientHello のハンドシェイクレコードで始まったものだけを追跡する
+            if !starts_client_hello(payload) {
+                return None;
+            }
+            self.make_room(now);
+            if self.flows.len() >= MAX_FLOWS {
+                return None;
+            }
+            self.flows.insert(
+                client_key,
+                TlsFlow {
+                    sni: None,
+                    handshake_done: false,
+                    stream: Vec::new(),
+                    next_seq: seq,
+                    last_seen: now,
+                },
+            );
+        }
+
+        let flow = self.flows.get_mut(&client_key)?;
+        flow.last_seen = now;
+        if !flow.handshake_done {
+            flow.collect(seq, payload);
+            // SNI のない ClientHello や TLS でなかった接続は追跡をやめる
+            if flow.handshake_done && flow.sni.is_none() {
+                self.flows.remove(&client_key);
+                return None;
+            }
+        }
+        flow.sni.clone()
+    }
+
+    fn make_room(&mut self, now: Instant) {
+        if self.flows.len() >= MAX_FLOWS {
+            self.flows
+                .retain(|_, flow| now.duration_since(flow.last_seen) < FLOW_IDLE_TIMEOUT);
+        }
+    }
+}
+
+impl TlsFlow {
+    fn collect(&mut self, seq: u32, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        // 再送で重なった部分は読み飛ばし、欠落があれば諦める（番号の一周を考慮して比較する）
+        let end = seq.wrapping_add(payload.len() as u32);
+        if (end.wrapping_sub(self.next_seq) as i32) <= 0 {
+            return;
+        }
+        if (seq.wrapping_sub(self.next_seq) as i32) > 0 {
+            self.finish_handshake();
+            return;
+        }
+        let skip = self.next_seq.wrapping_sub(seq) as usize;
+        if self.stream.len() + payload.len() - skip > MAX_CLIENT_HELLO_BYTES {
+            self.finish_handshake();
+            return;
+        }
+        self.stream.extend_from_slice(&payload[skip..]);
+        self.next_seq = end;
+
+        // 揃っているハンドシェイクレコードの中身を繋げて ClientHello を組み立てる
+        let mut handshake: Vec<u8> = Vec::new();
+        let mut r = Reader::new(&self.stream);
+        while let Some(header) = r.take(RECORD_HEADER_LEN) {
+            if header[0] != RECORD_HANDSHAKE {
+                self.finish_handshake();
+                return;
+            }
+            let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+            let Some(fragment) = r.take(len) else {
+                break;
+            };
+            handshake.extend_from_slice(fragment);
+        }
+
+        match client_hello_len(&handshake) {
+            Some(len) if handshake.len() >= len => {
+                self.sni = client_hello_sni(&handshake[..len]);
+                self.finish_handshake();
+            }
+            Some(_) => {}
+            None if handshake.len() >= 4 => self.finish_handshake(),
+            None => {}
+        }
+    }
+
+    fn finish_handshake(&mut self) {
+        self.handshake_done = true;
+        self.stream = Vec::new();
+    }
+}
+
+// TLS のハンドシェイクレコード（バージョン 3.x）に ClientHello が続くか
+fn starts_client_hello(payload: &[u8]) -> bool {
+    payload.len() > RECORD_HEADER_LEN
+        && payload[0] == RECORD_HANDSHAKE
+        && payload[1] == 0x03
+        && payload[RECORD_HEADER_LEN] == HANDSHAKE_CLIENT_HELLO
+}
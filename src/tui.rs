@@ -0,0 +1,815 @@
+// 全画面の対話的な統計表示（端末に出力している場合に print_stats の代わりに使う）
+//
+// 表示の更新間隔（display.refresh_interval_ms）ごとに統計のスナップショットを取り、描画はスナップショットからだけ行う（描画中はロックを持たない）。
+// 一時停止中はスナップショットを更新しない。並び順の固定中は値だけを更新し、行の順序を保つ。
+// 端末のサイズが変わった場合は次の描画で全体を描き直す。
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use pnet::ipnetwork::IpNetwork;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::config::{Config, Settings};
+use crate::flows::{FlowKey, RttSummary};
+use crate::cast::CastType;
+use crate::scope::Scope;
+use crate::shutdown::Shutdown;
+use crate::{dns, logging, format_bps_short, format_bytes_short, IpStats, SharedStats, Traffic, TrafficTotals};
+
+/// `0` / `off` / `false` で全画面表示を無効にする（端末でも従来の表示を使う）
+pub const TUI_ENV: &str = "LPD_TUI";
+
+// キー入力を待つ間隔（この間隔で描き直す）
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const DETAIL_FLOWS: usize = 12;
+const DETAIL_DOMAINS: usize = 5;
+const EXTERNAL_TALKERS: usize = 5;
+
+pub fn enabled(config: &Config) -> bool {
+    config.display.tui && io::stdout().is_terminal()
+}
+
+/// 画面を表示し、q / Ctrl+C で終了するか、シグナルなどで終了処理が始まるまで戻らない
+/// 表示中のログは画面の下に最新の1行だけを表示し、終了後に標準エラーへまとめて出力する
+pub fn run(stats: SharedStats, dns_state: Arc<dns::DnsState>, settings: Settings, shutdown: Shutdown) -> io::Result<()> {
+    logging::hold_stderr();
+    let result = ratatui::try_init().and_then(|mut terminal| {
+        let result = App::new(stats, dns_state, settings).run(&mut terminal, &shutdown);
+        ratatui::restore();
+        result
+    });
+    logging::release_stderr();
+    result
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Units {
+    Bits,
+    Bytes,
+}
+
+// 表の列（並べ替えの対象）
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+    Ip,
+    Host,
+    Up,
+    Down,
+    Rate,
+    UpTotal,
+    DownTotal,
+    Packets,
+    AvgPkt,
+    Retrans,
+    DupAck,
+    WinChg,
+}
+
+impl Column {
+    const ALL: [Column; 12] = [
+        Column::Ip,
+        Column::Host,
+        Column::Up,
+        Column::Down,
+        Column::Rate,
+        Column::UpTotal,
+        Column::DownTotal,
+        Column::Packets,
+        Column::AvgPkt,
+        Column::Retrans,
+        Column::DupAck,
+        Column::WinChg,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            Column::Ip => "IP Address",
+            Column::Host => "Host",
+            Column::Up => "↑ Up",
+            Column::Down => "↓ Down",
+            Column::Rate => "Total/s",
+            Column::UpTotal => "↑ Sent",
+            Column::DownTotal => "↓ Recv",
+            Column::Packets => "Pkt/s",
+            Column::AvgPkt => "AvgPkt",
+            Column::Retrans => "PLoss/s",
+            Column::DupAck => "DupAck/s",
+            Column::WinChg => "WinChg/s",
+        }
+    }
+
+    fn width(self, ip_width: u16) -> Constraint {
+        match self {
+            Column::Ip => Constraint::Length(ip_width),
+            Column::Host => Constraint::Fill(1),
+            _ => Constraint::Length(9),
+        }
+    }
+
+    fn compare(self, a: &HostRow, b: &HostRow) -> Ordering {
+        match self {
+            Column::Ip => a.ip.cmp(&b.ip),
+            Column::Host => a.hostname.cmp(&b.hostname),
+            Column::Up => a.tx_bps.total_cmp(&b.tx_bps),
+            Column::Down => a.rx_bps.total_cmp(&b.rx_bps),
+            Column::Rate => (a.tx_bps + a.rx_bps).total_cmp(&(b.tx_bps + b.rx_bps)),
+            Column::UpTotal => a.tx_bytes.cmp(&b.tx_bytes),
+            Column::DownTotal => a.rx_bytes.cmp(&b.rx_bytes),
+            Column::Packets => a.packets_per_sec.cmp(&b.packets_per_sec),
+            Column::AvgPkt => a.average_packet_size.cmp(&b.average_packet_size),
+            Column::Retrans => a.retransmissions_per_sec.cmp(&b.retransmissions_per_sec),
+            Column::DupAck => a.duplicate_acks_per_sec.cmp(&b.duplicate_acks_per_sec),
+            Column::WinChg => a.window_size_changes_per_sec.cmp(&b.window_size_changes_per_sec),
+        }
+    }
+
+    // 文字列・IPは昇順、数値は降順から始める
+    fn descending_by_default(self) -> bool {
+        !matches!(self, Column::Ip | Column::Host)
+    }
+}
+
+/// 表の1行分のスナップショット
+struct HostRow {
+    ip: IpAddr,
+    hostname: String,
+    nat_suspected: bool,
+    tx_bps: f64,
+    rx_bps: f64,
+    tx_bytes_per_sec: u64,
+    rx_bytes_per_sec: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    packets_per_sec: u64,
+    average_packet_size: u64,
+    retransmissions_per_sec: u64,
+    duplicate_acks_per_sec: u64,
+    window_size_changes_per_sec: u64,
+}
+
+impl HostRow {
+    fn new(ip: IpAddr, stat: &IpStats, hostnames: &dns::HostnameCache) -> Self {
+        Self {
+            ip,
+            hostname: hostnames.lookup(&ip).unwrap_or("").to_string(),
+            nat_suspected: stat.nat_signals.nat_suspected(),
+            tx_bps: stat.tx_current_bps,
+            rx_bps: stat.rx_current_bps,
+            tx_bytes_per_sec: stat.tx_bytes_per_sec,
+            rx_bytes_per_sec: stat.rx_bytes_per_sec,
+            tx_bytes: stat.tx_byte_count,
+            rx_bytes: stat.rx_byte_count,
+            packets_per_sec: stat.tx_packets_per_sec + stat.rx_packets_per_sec,
+            average_packet_size: stat.average_packet_size(),
+            retransmissions_per_sec: stat.retransmissions_per_sec,
+            duplicate_acks_per_sec: stat.duplicate_acks_per_sec,
+            window_size_changes_per_sec: stat.window_size_changes_per_sec,
+        }
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        if filter.is_empty() {
+            return true;
+        }
+        // CIDR として読めればサブネットで、それ以外はIPとホスト名の部分一致で絞り込む
+        if let Ok(network) = filter.parse::<IpNetwork>() {
+            if filter.contains('/') {
+                return network.contains(self.ip);
+            }
+        }
+        let filter = filter.to_lowercase();
+        self.ip.to_string().contains(&filter) || self.hostname.to_lowercase().contains(&filter)
+    }
+}
+
+struct FlowRow {
+    key: FlowKey,
+    hostname: String, // DNS応答から学習した相手のホスト名
+    tx_bytes_per_sec: u64,
+    rx_bytes_per_sec: u64,
+    tx_bytes: u64,
+    rx_bytes: u64,
+    rtt: Option<Duration>,
+}
+
+/// 選択中のホストの詳細のスナップショット
+struct HostDetail {
+    ip: IpAddr,
+    flows: Vec<FlowRow>,
+    flow_count: usize,
+    flows_dropped: u64,
+    protocols: Vec<(&'static str, Traffic, Traffic)>,
+    domains: Vec<(String, Traffic)>,
+    rtt: RttSummary,
+    retransmissions: u64,
+    duplicate_acks: u64,
+    window_size_changes: u64,
+    nat_suspected: bool,
+}
+
+impl HostDetail {
+    fn new(ip: IpAddr, stat: &IpStats, hostnames: &dns::HostnameCache) -> Self {
+        let flows = stat
+            .flows
+            .top(DETAIL_FLOWS)
+            .into_iter()
+            .map(|(key, flow)| FlowRow {
+                key: *key,
+                hostname: hostnames.lookup(&key.remote).unwrap_or("").to_string(),
+                tx_bytes_per_sec: flow.tx_bytes_per_sec,
+                rx_bytes_per_sec: flow.rx_bytes_per_sec,
+                tx_bytes: flow.tx.bytes,
+                rx_bytes: flow.rx.bytes,
+                rtt: flow.rtt,
+            })
+            .collect();
+
+        let mut protocols: Vec<(&'static str, Traffic, Traffic)> = stat
+            .tx_protocols
+            .keys()
+            .chain(stat.rx_protocols.keys())
+            .copied()
+            .collect::<std::collections::HashSet<_>>()
+            .into_iter()
+            .map(|name| {
+                let tx = stat.tx_protocols.get(name).copied().unwrap_or_default();
+                let rx = stat.rx_protocols.get(name).copied().unwrap_or_default();
+                (name, tx, rx)
+            })
+            .collect();
+        protocols.sort_by_key(|(name, tx, rx)| (std::cmp::Reverse(tx.bytes + rx.bytes), *name));
+
+        let mut domains: Vec<(String, Traffic)> = stat.domains.iter().map(|(name, traffic)| (name.clone(), *traffic)).collect();
+        domains.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.bytes));
+        domains.truncate(DETAIL_DOMAINS);
+
+        Self {
+            ip,
+            flows,
+            flow_count: stat.flows.len(),
+            flows_dropped: stat.flows.dropped,
+            protocols,
+            domains,
+            rtt: stat.flows.rtt,
+            retransmissions: stat.retransmissions,
+            duplicate_acks: stat.duplicate_acks,
+            window_size_changes: stat.window_size_changes,
+            nat_suspected: stat.nat_signals.nat_suspected(),
+        }
+    }
+}
+
+/// ネットワーク全体の集計のスナップショット
+#[derive(Default)]
+struct Overview {
+    wan_up_bps: f64,
+    wan_down_bps: f64,
+    lan_bps: f64,
+    broadcast_per_sec: u64,
+    multicast_per_sec: u64,
+    external_tracked: usize,
+    external_talkers: Vec<(IpAddr, u64, u64, String)>,
+}
+
+impl Overview {
+    fn new(totals: &TrafficTotals, hostnames: &dns::HostnameCache) -> Self {
+        Self {
+            wan_up_bps: totals.scope.bps(Scope::LanToWan),
+            wan_down_bps: totals.scope.bps(Scope::WanToLan),
+            lan_bps: totals.scope.bps(Scope::LanToLan),
+            broadcast_per_sec: totals.storm.packets_per_sec(CastType::Broadcast),
+            multicast_per_sec: totals.storm.packets_per_sec(CastType::Multicast),
+            external_tracked: totals.external_talkers.tracked(),
+            external_talkers: totals
+                .external_talkers
+                .top(EXTERNAL_TALKERS)
+                .into_iter()
+                .map(|talker| (talker.key, talker.count, talker.error, hostnames.lookup(&talker.key).unwrap_or("").to_string()))
+                .collect(),
+        }
+    }
+}
+
+enum Action {
+    Continue,
+    Redraw,
+    Quit,
+}
+
+struct App {
+    stats: SharedStats,
+    dns_state: Arc<dns::DnsState>,
+    settings: Settings,
+    rows: Vec<HostRow>,
+    detail: Option<HostDetail>,
+    overview: Overview,
+    // 表示の状態
+    selected: Option<IpAddr>,
+    table_state: TableState,
+    sort: Column,
+    descending: bool,
+    filter: String,
+    editing_filter: bool,
+    show_detail: bool,
+    paused: bool,
+    frozen_order: Option<Vec<IpAddr>>,
+    units: Units,
+    last_refresh: Option<Instant>,
+}
+
+impl App {
+    fn new(stats: SharedStats, dns_state: Arc<dns::DnsState>, settings: Settings) -> Self {
+        Self {
+            stats,
+            dns_state,
+            settings,
+            rows: Vec::new(),
+            detail: None,
+            overview: Overview::default(),
+            selected: None,
+            table_state: TableState::default(),
+            sort: Column::Rate,
+            descending: true,
+            filter: String::new(),
+            editing_filter: false,
+            show_detail: false,
+            paused: false,
+            frozen_order: None,
+            units: Units::Bits,
+            last_refresh: None,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal, shutdown: &Shutdown) -> io::Result<()> {
+        while shutdown.is_running() {
+            // 更新間隔は設定の再読み込みで変わる
+            let refresh_interval = self.settings.read().unwrap().display.refresh_interval();
+            if !self.paused && self.last_refresh.is_none_or(|t| t.elapsed() >= refresh_interval) {
+                self.refresh();
+                self.last_refresh = Some(Instant::now());
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(POLL_INTERVAL)? {
+                continue;
+            }
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => match self.handle_key(key) {
+                    Action::Continue => {}
+                    Action::Redraw => terminal.clear()?,
+                    Action::Quit => return Ok(()),
+                },
+                // 新しいサイズでは差分ではなく全体を描き直す
+                Event::Resize(_, _) => terminal.clear()?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // hosts → totals → hostnames の順にロックしてスナップショットを取る
+    fn refresh(&mut self) {
+        let hosts = self.stats.hosts.lock().unwrap();
+        let totals = self.stats.totals.lock().unwrap();
+        let hostnames = self.dns_state.hostnames.lock().unwrap();
+        self.rows = hosts.iter().map(|(ip, stat)| HostRow::new(*ip, stat, &hostnames)).collect();
+        self.overview = Overview::new(&totals, &hostnames);
+        self.detail = self
+            .selected
+            .filter(|_| self.show_detail)
+            .and_then(|ip| hosts.get(&ip).map(|stat| HostDetail::new(ip, stat, &hostnames)));
+        drop(hostnames);
+        drop(totals);
+        drop(hosts);
+        self.sort_rows();
+    }
+
+    fn sort_rows(&mut self) {
+        if let Some(order) = &self.frozen_order {
+            // 固定した順序のホストを先に、新しいホストをその後に並べる
+            let position: HashMap<IpAddr, usize> = order.iter().enumerate().map(|(i, ip)| (*ip, i)).collect();
+            self.rows.sort_by_key(|row| (position.get(&row.ip).copied().unwrap_or(usize::MAX), row.ip));
+            return;
+        }
+        let (sort, descending) = (self.sort, self.descending);
+        self.rows.sort_by(|a, b| {
+            let ordering = sort.compare(a, b);
+            let ordering = if descending { ordering.reverse() } else { ordering };
+            ordering.then(a.ip.cmp(&b.ip))
+        });
+    }
+
+    fn visible_rows(&self) -> Vec<&HostRow> {
+        self.rows.iter().filter(|row| row.matches(&self.filter)).collect()
+    }
+
+    fn selected_index(&self, rows: &[&HostRow]) -> Option<usize> {
+        let ip = self.selected?;
+        rows.iter().position(|row| row.ip == ip)
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let rows = self.visible_rows();
+        if rows.is_empty() {
+            return;
+        }
+        let last = rows.len() as isize - 1;
+        let index = match self.selected_index(&rows) {
+            Some(index) => (index as isize + delta).clamp(0, last),
+            None if delta < 0 => last,
+            None => 0,
+        };
+        self.selected = Some(rows[index as usize].ip);
+        self.refresh_detail();
+    }
+
+    // 選択を変えたら一時停止中でも詳細だけは取り直す
+    fn refresh_detail(&mut self) {
+        if !self.show_detail {
+            self.detail = None;
+            return;
+        }
+        let Some(ip) = self.selected else {
+            return;
+        };
+        let hosts = self.stats.hosts.lock().unwrap();
+        let hostnames = self.dns_state.hostnames.lock().unwrap();
+        self.detail = hosts.get(&ip).map(|stat| HostDetail::new(ip, stat, &hostnames));
+    }
+
+    fn set_sort(&mut self, column: Column) {
+        if self.sort == column {
+            self.descending = !self.descending;
+        } else {
+            self.sort = column;
+            self.descending = column.descending_by_default();
+        }
+        self.frozen_order = None;
+        self.sort_rows();
+    }
+
+    fn shift_sort(&mut self, step: isize) {
+        let index = Column::ALL.iter().position(|c| *c == self.sort).unwrap_or(0) as isize;
+        let len = Column::ALL.len() as isize;
+        let column = Column::ALL[(index + step).rem_euclid(len) as usize];
+        self.sort = column;
+        self.descending = column.descending_by_default();
+        self.frozen_order = None;
+        self.sort_rows();
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        if ctrl && key.code == KeyCode::Char('c') {
+            return Action::Quit;
+        }
+        if ctrl && key.code == KeyCode::Char('l') {
+            return Action::Redraw;
+        }
+
+        if self.editing_filter {
+            match key.code {
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.editing_filter = false;
+                    self.filter.clear();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            return Action::Continue;
+        }
+
+        match key.code {
+            KeyCode::Char('q') => return Action::Quit,
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::PageDown => self.move_selection(10),
+            KeyCode::PageUp => self.move_selection(-10),
+            KeyCode::Home | KeyCode::Char('g') => self.move_selection(isize::MIN / 2),
+            KeyCode::End | KeyCode::Char('G') => self.move_selection(isize::MAX / 2),
+            KeyCode::Enter => {
+                self.show_detail = !self.show_detail;
+                if self.selected.is_none() {
+                    self.move_selection(0);
+                }
+                self.refresh_detail();
+            }
+            KeyCode::Esc => {
+                if self.show_detail {
+                    self.show_detail = false;
+                    self.detail = None;
+                } else {
+                    self.filter.clear();
+                }
+            }
+            KeyCode::Left | KeyCode::Char('<') => self.shift_sort(-1),
+            KeyCode::Right | KeyCode::Char('>') => self.shift_sort(1),
+            KeyCode::Char('r') => {
+                self.descending = !self.descending;
+                self.frozen_order = None;
+                self.sort_rows();
+            }
+            KeyCode::Char(c @ '1'..='9') => {
+                // 数字キーで列を直接選ぶ（同じ列なら昇順・降順を切り替える）
+                if let Some(column) = Column::ALL.get(c as usize - '1' as usize) {
+                    self.set_sort(*column);
+                }
+            }
+            KeyCode::Char('/') => self.editing_filter = true,
+            KeyCode::Char('p') | KeyCode::Char(' ') => self.paused = !self.paused,
+            KeyCode::Char('f') => {
+                self.frozen_order = match self.frozen_order {
+                    Some(_) => None,
+                    None => Some(self.rows.iter().map(|row| row.ip).collect()),
+                };
+                self.sort_rows();
+            }
+            KeyCode::Char('u') => {
+                self.units = match self.units {
+                    Units::Bits => Units::Bytes,
+                    Units::Bytes => Units::Bits,
+                };
+            }
+            _ => {}
+        }
+        Action::Continue
+    }
+
+    fn rate(&self, bytes_per_sec: u64, bps: f64) -> String {
+        match self.units {
+            Units::Bits => format_bps_short(bps),
+            Units::Bytes => format_bytes_short(bytes_per_sec),
+        }
+    }
+
+    fn unit_label(&self) -> &'static str {
+        match self.units {
+            Units::Bits => "bit/s",
+            Units::Bytes => "byte/s",
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let bottom_height = if self.show_detail { 18 } else { EXTERNAL_TALKERS as u16 + 3 };
+        let log = logging::last_held();
+        let [header, table, bottom, footer] = Layout::vertical([
+            Constraint::Length(2),
+            Constraint::Min(5),
+            Constraint::Length(bottom_height),
+            Constraint::Length(if log.is_some() { 2 } else { 1 }),
+        ])
+        .areas(frame.area());
+
+        self.draw_header(frame, header);
+        self.draw_table(frame, table);
+        if self.show_detail {
+            self.draw_detail(frame, bottom);
+        } else {
+            self.draw_external(frame, bottom);
+        }
+        self.draw_footer(frame, footer, log);
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let overview = &self.overview;
+        let mut status = vec![Span::styled("Subnet Network Traffic Monitor", Style::new().bold())];
+        if self.paused {
+            status.push(Span::styled("  PAUSED", Style::new().fg(Color::Black).bg(Color::Yellow)));
+        }
+        if self.frozen_order.is_some() {
+            status.push(Span::styled("  ORDER FROZEN", Style::new().fg(Color::Black).bg(Color::Cyan)));
+        }
+        let summary = format!(
+            "WAN ↑ {} ↓ {} | LAN internal {} | Broadcast {} pkt/s | Multicast {} pkt/s | Hosts {} | External IPs tracked {}",
+            format_bps_short(overview.wan_up_bps),
+            format_bps_short(overview.wan_down_bps),
+            format_bps_short(overview.lan_bps),
+            overview.broadcast_per_sec,
+            overview.multicast_per_sec,
+            self.rows.len(),
+            overview.external_tracked,
+        );
+        frame.render_widget(Paragraph::new(vec![Line::from(status), Line::from(summary)]), area);
+    }
+
+    fn draw_table(&mut self, frame: &mut Frame, area: Rect) {
+        let rows = self.visible_rows();
+        let selected = self.selected_index(&rows);
+        let ip_width = rows.iter().map(|row| row.ip.to_string().len()).max().unwrap_or(0).max(15) as u16;
+
+        let header = Row::new(Column::ALL.iter().map(|column| {
+            let mut title = column.title().to_string();
+            if *column == self.sort && self.frozen_order.is_none() {
+                title.push_str(if self.descending { " ▼" } else { " ▲" });
+                Cell::from(title).style(Style::new().add_modifier(Modifier::BOLD | Modifier::UNDERLINED))
+            } else {
+                Cell::from(title)
+            }
+        }))
+        .style(Style::new().bold());
+
+        let body: Vec<Row> = rows
+            .iter()
+            .map(|row| {
+                let mut host = row.hostname.clone();
+                if row.nat_suspected {
+                    host.push_str(" [NAT?]");
+                }
+                let health = |value: u64| {
+                    let cell = Cell::from(value.to_string());
+                    if value > 0 { cell.style(Style::new().fg(Color::Red)) } else { cell }
+                };
+                Row::new(vec![
+                    Cell::from(row.ip.to_string()),
+                    Cell::from(host),
+                    Cell::from(self.rate(row.tx_bytes_per_sec, row.tx_bps)),
+                    Cell::from(self.rate(row.rx_bytes_per_sec, row.rx_bps)),
+                    Cell::from(self.rate(row.tx_bytes_per_sec + row.rx_bytes_per_sec, row.tx_bps + row.rx_bps)),
+                    Cell::from(format_bytes_short(row.tx_bytes)),
+                    Cell::from(format_bytes_short(row.rx_bytes)),
+                    Cell::from(row.packets_per_sec.to_string()),
+                    Cell::from(row.average_packet_size.to_string()),
+                    health(row.retransmissions_per_sec),
+                    health(row.duplicate_acks_per_sec),
+                    Cell::from(row.window_size_changes_per_sec.to_string()),
+                ])
+            })
+            .collect();
+
+        let mut title = format!(" Hosts ({} shown, rates in {}) ", rows.len(), self.unit_label());
+        if !self.filter.is_empty() {
+            title.push_str(&format!("filter: {} ", self.filter));
+        }
+        let widths: Vec<Constraint> = Column::ALL.iter().map(|column| column.width(ip_width)).collect();
+        let table = Table::new(body, widths)
+            .header(header)
+            .block(Block::bordered().title(title))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        if rows.is_empty() {
+            let message = if self.rows.is_empty() {
+                "No traffic detected from monitored subnet IPs..."
+            } else {
+                "No hosts match the filter"
+            };
+            frame.render_widget(table, area);
+            let inner = Rect { y: area.y + 2, height: 1, x: area.x + 2, width: area.width.saturating_sub(4) };
+            frame.render_widget(Paragraph::new(message).italic(), inner);
+            return;
+        }
+        self.table_state.select(selected);
+        frame.render_stateful_widget(table, area, &mut self.table_state);
+    }
+
+    fn draw_external(&self, frame: &mut Frame, area: Rect) {
+        let rows: Vec<Row> = self
+            .overview
+            .external_talkers
+            .iter()
+            .map(|(ip, bytes, error, hostname)| {
+                Row::new(vec![
+                    ip.to_string(),
+                    format_bytes_short(*bytes),
+                    format!("±{}", format_bytes_short(*error)),
+                    hostname.clone(),
+                ])
+            })
+            .collect();
+        let table = Table::new(rows, [Constraint::Length(39), Constraint::Length(9), Constraint::Length(9), Constraint::Fill(1)])
+            .header(Row::new(["IP Address", "Bytes", "± Error", "Host"]).bold())
+            .block(Block::bordered().title(" Top external talkers (estimated total bytes, ± max error) "));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_detail(&self, frame: &mut Frame, area: Rect) {
+        let Some(detail) = &self.detail else {
+            let message = match self.selected {
+                Some(ip) => format!("{} is no longer tracked", ip),
+                None => "Select a host with ↑/↓".to_string(),
+            };
+            frame.render_widget(Paragraph::new(message).block(Block::bordered().title(" Host detail ")), area);
+            return;
+        };
+        let [flows, protocols, info] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(20), Constraint::Percentage(25)]).areas(area);
+
+        let flow_rows: Vec<Row> = detail
+            .flows
+            .iter()
+            .map(|flow| {
+                Row::new(vec![
+                    flow.key.protocol.to_string(),
+                    port_label(flow.key.local_port),
+                    remote_label(&flow.hostname, endpoint(flow.key.remote, flow.key.remote_port)),
+                    self.rate(flow.tx_bytes_per_sec, flow.tx_bytes_per_sec as f64 * 8.0),
+                    self.rate(flow.rx_bytes_per_sec, flow.rx_bytes_per_sec as f64 * 8.0),
+                    format!("{}/{}", format_bytes_short(flow.tx_bytes), format_bytes_short(flow.rx_bytes)),
+                    flow.rtt.map(format_rtt).unwrap_or_default(),
+                ])
+            })
+            .collect();
+        let table = Table::new(
+            flow_rows,
+            [
+                Constraint::Length(5),
+                Constraint::Length(6),
+                Constraint::Fill(1),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(13),
+                Constraint::Length(8),
+            ],
+        )
+        .header(Row::new(["Proto", "Local", "Remote", "↑ Up", "↓ Down", "Sent/Recv", "RTT"]).bold())
+        .block(Block::bordered().title(format!(" {} flows ({} active, {} dropped) ", detail.ip, detail.flow_count, detail.flows_dropped)));
+        frame.render_widget(table, flows);
+
+        let protocol_rows: Vec<Row> = detail
+            .protocols
+            .iter()
+            .map(|(name, tx, rx)| Row::new(vec![name.to_string(), format_bytes_short(tx.bytes), format_bytes_short(rx.bytes)]))
+            .collect();
+        let table = Table::new(protocol_rows, [Constraint::Length(6), Constraint::Length(8), Constraint::Length(8)])
+            .header(Row::new(["Proto", "Sent", "Recv"]).bold())
+            .block(Block::bordered().title(" Protocols "));
+        frame.render_widget(table, protocols);
+
+        let rtt = &detail.rtt;
+        let mut lines = vec![Line::from("RTT (TCP handshake / ICMP echo)".bold())];
+        match rtt.average() {
+            Some(average) => {
+                lines.push(Line::from(format!("  last {}  avg {}", format_rtt(rtt.last), format_rtt(average))));
+                lines.push(Line::from(format!("  min {}  max {}  ({} samples)", format_rtt(rtt.min), format_rtt(rtt.max), rtt.samples)));
+            }
+            None => lines.push(Line::from("  no samples yet")),
+        }
+        lines.push(Line::from("TCP".bold()));
+        lines.push(Line::from(format!("  retransmissions {}", detail.retransmissions)));
+        lines.push(Line::from(format!("  duplicate ACKs {}", detail.duplicate_acks)));
+        lines.push(Line::from(format!("  window changes {}", detail.window_size_changes)));
+        if detail.nat_suspected {
+            lines.push(Line::from("NAT? multiple TTLs or TCP clocks seen".yellow()));
+        }
+        if !detail.domains.is_empty() {
+            lines.push(Line::from("Domains (SNI)".bold()));
+            for (domain, traffic) in &detail.domains {
+                lines.push(Line::from(format!("  {} {}", format_bytes_short(traffic.bytes), domain)));
+            }
+        }
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Health ")), info);
+    }
+
+    fn draw_footer(&self, frame: &mut Frame, area: Rect, log: Option<String>) {
+        let line = if self.editing_filter {
+            Line::from(vec![
+                Span::styled("Filter (IP, hostname or CIDR): ", Style::new().bold()),
+                Span::raw(format!("{}_", self.filter)),
+                Span::raw("  Enter apply  Esc clear"),
+            ])
+        } else {
+            Line::from(
+                "q quit  ↑↓ select  Enter detail  ←→/1-9 sort  r reverse  / filter  p pause  f freeze order  u units  Ctrl+L redraw",
+            )
+        };
+        let mut lines = vec![line.style(Style::new().fg(Color::DarkGray))];
+        // 表示中に出たログの最新の1行（終了後に標準エラーへまとめて出力する）
+        if let Some(log) = log {
+            lines.push(Line::styled(log, Style::new().fg(Color::Yellow)));
+        }
+        frame.render_widget(Paragraph::new(lines), area);
+    }
+}
+
+fn port_label(port: u16) -> String {
+    if port == 0 { String::new() } else { format!(":{}", port) }
+}
+
+fn endpoint(ip: IpAddr, port: u16) -> String {
+    if port == 0 { ip.to_string() } else { SocketAddr::new(ip, port).to_string() }
+}
+
+// ホスト名が分かっていれば「名前 (IP:ポート)」にする
+fn remote_label(hostname: &str, endpoint: String) -> String {
+    if hostname.is_empty() { endpoint } else { format!("{} ({})", hostname, endpoint) }
+}
+
+fn format_rtt(rtt: Duration) -> String {
+    let millis = rtt.as_secs_f64() * 1000.0;
+    if millis >= 100.0 {
+        format!("{:.0}ms", millis)
+    } else {
+        format!("{:.1}ms", millis)
+    }
+}